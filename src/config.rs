@@ -18,8 +18,10 @@
 // in the documentation (see README.md)
 
 use serde_derive::{Serialize, Deserialize};
+use scheduler::TaskConfig;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub id: String,
     pub subid: String,
@@ -35,11 +37,64 @@ pub struct Config {
     pub gps_serial_port: String,
     pub gps_speed: u32,
 
+    pub lora_radio: String,
     pub lora_cs: u8,
     pub lora_int_pin: u8,
+    pub lora_busy_pin: u8,
+    pub lora_reset_pin: u8,
+    pub lora_tcxo_voltage: f32,
+    pub lora_dio2_rf_switch: bool,
     pub lora_freq: f32,
     pub lora_low_pwr: u8,
     pub lora_high_pwr: u8,
+    pub lora_preset: String,
+    pub lora_bandwidth: u32,
+    pub lora_spreading_factor: u8,
+    pub lora_coding_rate: u8,
+    pub lora_implicit_header: bool,
+    pub lora_crc: bool,
+    pub lora_spi_speed: u32,
+    pub lora_sleep: bool,
+    pub lora_radiohead_header: bool,
+    pub lora_address: u8,
+    pub lora_destination: u8,
+    pub lora_promiscuous: bool,
+    pub lora_lbt: bool,
+    pub lora_lbt_attempts: u8,
+    pub lora_lbt_min_backoff: u32,
+    pub lora_lbt_max_backoff: u32,
+    pub lora_hop_channels: Vec<f32>,
+    pub lora_hop_period: u8,
+
+    pub ssdv_freq: f32,
+    pub ssdv_preset: String,
+    pub ssdv_power: u8,
+    pub ssdv_hopping: bool,
+
+    pub uplink_freq: f32,
+    pub uplink_preset: String,
+    pub uplink_power: u8,
+    pub uplink_hopping: bool,
+
+    pub rtty_enabled: bool,
+    pub rtty_freq: f32,
+    pub rtty_baud: u32,
+    pub rtty_shift: u32,
+    pub rtty_bits: u8,
+    pub rtty_stop_bits: u8,
+    pub rtty_preamble: u32,
+
+    pub horus_enabled: bool,
+    pub horus_freq: f32,
+    pub horus_payload_id: u16,
+    pub horus_baud: u32,
+    pub horus_tone_spacing: u32,
+    pub horus_preamble: u32,
+
+    pub cw_enabled: bool,
+    pub cw_freq: f32,
+    pub cw_wpm: u32,
+    pub cw_msg: String,
 
     pub adc_cs: u8,
     pub adc_vbatt: u8,
@@ -58,6 +113,26 @@ pub struct Config {
 
     pub ssdv_size: String,
     pub ssdv_name: String,
+    pub ssdv_quality: u8,
+    pub ssdv_fec: bool,
+    pub ssdv_packet_len: u16,
+
+    pub watchdog_device: String,
+    pub watchdog_timeout: u32,
+    pub watchdog_systemd: bool,
+
+    pub uplink_window: u32,
+
+    pub tx_telemetry_max_gap: u32,
+    pub tx_share_ssdv: u32,
+    pub tx_share_bulk: u32,
+
+    pub duty_cycle_enabled: bool,
+    pub duty_cycle_limit: f32,
+    pub duty_cycle_window: u32,
+
+    // keep it last, TOML tables go after plain values
+    pub schedule: Vec<TaskConfig>,
 }
 
 
@@ -78,11 +153,64 @@ impl ::std::default::Default for Config {
             gps_serial_port: "".to_string(),
             gps_speed: 0,
 
+            lora_radio: "rf95".to_string(),
             lora_cs: 0,
             lora_int_pin: 0,
+            lora_busy_pin: 0,
+            lora_reset_pin: 0,
+            lora_tcxo_voltage: 0.0,
+            lora_dio2_rf_switch: false,
             lora_freq: 0.0,
             lora_low_pwr: 0,
             lora_high_pwr: 0,
+            lora_preset: "Bw125Cr45Sf128".to_string(),
+            lora_bandwidth: 0,
+            lora_spreading_factor: 0,
+            lora_coding_rate: 0,
+            lora_implicit_header: false,
+            lora_crc: true,
+            lora_spi_speed: 1000000,
+            lora_sleep: true,
+            lora_radiohead_header: false,
+            lora_address: 255,
+            lora_destination: 255,
+            lora_promiscuous: false,
+            lora_lbt: false,
+            lora_lbt_attempts: 5,
+            lora_lbt_min_backoff: 50,
+            lora_lbt_max_backoff: 500,
+            lora_hop_channels: Vec::new(),
+            lora_hop_period: 0,
+
+            ssdv_freq: 0.0,
+            ssdv_preset: "".to_string(),
+            ssdv_power: 0,
+            ssdv_hopping: false,
+
+            uplink_freq: 0.0,
+            uplink_preset: "".to_string(),
+            uplink_power: 0,
+            uplink_hopping: false,
+
+            rtty_enabled: false,
+            rtty_freq: 0.0,
+            rtty_baud: 50,
+            rtty_shift: 425,
+            rtty_bits: 7,
+            rtty_stop_bits: 2,
+            rtty_preamble: 500,
+
+            horus_enabled: false,
+            horus_freq: 0.0,
+            horus_payload_id: 256,
+            horus_baud: 100,
+            horus_tone_spacing: 270,
+            horus_preamble: 8,
+
+            cw_enabled: false,
+            cw_freq: 0.0,
+            cw_wpm: 20,
+            cw_msg: "".to_string(),
 
             adc_cs: 0,
             adc_vbatt: 0,
@@ -101,7 +229,25 @@ impl ::std::default::Default for Config {
 
             ssdv_size: "320x240".to_string(),
             ssdv_name: "ssdv.jpg".to_string(),
+            ssdv_quality: 4,
+            ssdv_fec: true,
+            ssdv_packet_len: 256,
+
+            watchdog_device: "".to_string(),
+            watchdog_timeout: 0,
+            watchdog_systemd: false,
+
+            uplink_window: 5,
+
+            tx_telemetry_max_gap: 0,
+            tx_share_ssdv: 3,
+            tx_share_bulk: 1,
+
+            duty_cycle_enabled: false,
+            duty_cycle_limit: 0.0,
+            duty_cycle_window: 3600,
 
+            schedule: Vec::new(),
         }
     }
 }
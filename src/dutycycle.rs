@@ -0,0 +1,385 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Rolling duty-cycle accounting of the radio transmissions,
+// with the limits of the EU 868MHz sub-bands. Each sub-band has
+// its own budget, so there is one accountant for each one used.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// EU 863-870MHz sub-bands (ETSI EN 300 220), (start MHz, end MHz, limit %)
+const EU868_BANDS: [(f32, f32, f32); 5] = [
+    (863.0, 868.0, 1.0),
+    (868.0, 868.6, 1.0),
+    (868.7, 869.2, 0.1),
+    (869.4, 869.65, 10.0),
+    (869.7, 870.0, 1.0),
+];
+
+// airtime (s) that can be used in a window (s), with
+// headroom (0-1) of the limit (%)
+fn budget(window: f32, limit: f32, headroom: f32) -> f32 {
+    window * limit / 100.0 * headroom
+}
+
+pub struct DutyCycle {
+    // percentage of the window we can transmit
+    pub limit: f32,
+    pub window: Duration,
+    history: VecDeque<(Instant, Duration)>,
+}
+
+impl DutyCycle {
+    pub fn new(limit: f32, window: u32) -> Self {
+        Self {
+            limit,
+            window: Duration::from_secs(window as u64),
+            history: VecDeque::new(),
+        }
+    }
+
+    // airtime used in the current window
+    pub fn used(&mut self, now: Instant) -> Duration {
+        while let Some(&(t, _)) = self.history.front() {
+            if now.duration_since(t) > self.window {
+                self.history.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.history.iter().map(|h| h.1).fold(Duration::from_secs(0), |a, b| a + b)
+    }
+
+    // used duty-cycle in percentage of the window
+    pub fn usage(&mut self, now: Instant) -> f32 {
+        let used = self.used(now);
+        100.0 * Self::secs(used) / Self::secs(self.window)
+    }
+
+    // true if we can transmit airtime now without using more than
+    // headroom (0-1) of the budget
+    pub fn allows(&mut self, now: Instant, airtime: Duration, headroom: f32) -> bool {
+        let budget = budget(Self::secs(self.window), self.limit, headroom);
+        Self::secs(self.used(now) + airtime) <= budget
+    }
+
+    pub fn record(&mut self, now: Instant, airtime: Duration) {
+        self.history.push_back((now, airtime));
+    }
+
+    fn secs(d: Duration) -> f32 {
+        d.as_secs() as f32 + d.subsec_nanos() as f32 / 1e9
+    }
+}
+
+// a transmission of the history, with the wall clock time
+// so it can be saved and restored after a reboot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DutyRecord {
+    // index in EU868_BANDS, none out of them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub band: Option<usize>,
+    // ms since the UNIX epoch
+    pub time: u64,
+    // ms
+    pub airtime: u64,
+}
+
+// used duty-cycle of a sub-band
+pub struct BandUsage {
+    pub band: String,
+    pub usage: f32,
+    pub limit: f32,
+}
+
+impl fmt::Display for BandUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:.2}% of {}%", self.band, self.usage, self.limit)
+    }
+}
+
+pub struct DutyCycles {
+    // limit for all the sub-bands, 0 uses the EU868 ones
+    limit: f32,
+    window: u32,
+    // accountants by index in EU868_BANDS, None out of them
+    bands: Vec<(Option<usize>, DutyCycle)>,
+}
+
+impl DutyCycles {
+    pub fn new(limit: f32, window: u32) -> Self {
+        Self {
+            limit,
+            window,
+            bands: Vec::new(),
+        }
+    }
+
+    // limit (%) that applies to freq (MHz), None if it isn't enforced
+    pub fn limit(&self, freq: f32) -> Option<f32> {
+        self.band_limit(Self::band(freq))
+    }
+
+    // name of the sub-band containing freq (MHz)
+    pub fn band_name(freq: f32) -> String {
+        Self::name(Self::band(freq))
+    }
+
+    // used duty-cycle of the sub-band containing freq (MHz)
+    pub fn usage(&mut self, now: Instant, freq: f32) -> Option<f32> {
+        self.accountant(freq).map(|dc| dc.usage(now))
+    }
+
+    // the first sub-band of the channels (MHz) that can't take
+    // airtime now without using more than headroom (0-1) of its
+    // budget, None if the transmission is allowed
+    pub fn full_band(
+        &mut self,
+        now: Instant,
+        channels: &[f32],
+        airtime: Duration,
+        headroom: f32,
+    ) -> Option<BandUsage> {
+        for f in channels {
+            if let Some(dc) = self.accountant(*f) {
+                if !dc.allows(now, airtime, headroom) {
+                    return Some(BandUsage {
+                        band: Self::band_name(*f),
+                        usage: dc.usage(now),
+                        limit: dc.limit,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    // the first sub-band of the channels (MHz) where airtime can't
+    // fit in headroom (0-1) of the budget even with an empty window,
+    // None if the transmission will be allowed at some point
+    pub fn too_long(&self, channels: &[f32], airtime: Duration, headroom: f32) -> Option<String> {
+        channels
+            .iter()
+            .find(|f| match self.limit(**f) {
+                Some(l) => DutyCycle::secs(airtime) > budget(self.window as f32, l, headroom),
+                None => false,
+            })
+            .map(|f| Self::band_name(*f))
+    }
+
+    // charge a transmission on the channels (MHz) to their sub-bands,
+    // once for each sub-band
+    pub fn record(&mut self, now: Instant, channels: &[f32], airtime: Duration) {
+        let mut charged = Vec::new();
+        for f in channels {
+            let band = Self::band(*f);
+            if charged.contains(&band) {
+                continue;
+            }
+            charged.push(band);
+            if let Some(dc) = self.accountant(*f) {
+                dc.record(now, airtime);
+            }
+        }
+    }
+
+    // usage of every sub-band used so far
+    pub fn report(&mut self, now: Instant) -> Vec<BandUsage> {
+        self.bands
+            .iter_mut()
+            .map(|&mut (band, ref mut dc)| BandUsage {
+                band: Self::name(band),
+                usage: dc.usage(now),
+                limit: dc.limit,
+            })
+            .collect()
+    }
+
+    // the transmissions still in the window of every sub-band
+    pub fn history(&mut self, now: Instant) -> Vec<DutyRecord> {
+        let wall = SystemTime::now();
+        let mut records = Vec::new();
+        for &mut (band, ref mut dc) in self.bands.iter_mut() {
+            dc.used(now);
+            for &(t, airtime) in &dc.history {
+                let time = wall - now.duration_since(t);
+                records.push(DutyRecord {
+                    band,
+                    time: Self::millis(time.duration_since(UNIX_EPOCH).unwrap_or_default()),
+                    airtime: Self::millis(airtime),
+                });
+            }
+        }
+        records
+    }
+
+    // restore a saved history. Records older than the window are dropped,
+    // and ones from the future (the clock went back) are taken as just
+    // sent, so the budget is never overestimated.
+    pub fn restore(&mut self, now: Instant, records: &[DutyRecord]) {
+        let wall = SystemTime::now();
+        let window = Duration::from_secs(self.window as u64);
+        let mut records = records.to_vec();
+        records.sort_by_key(|r| r.time);
+        for r in &records {
+            let time = UNIX_EPOCH + Duration::from_millis(r.time);
+            let age = wall.duration_since(time).unwrap_or_default();
+            if age > window {
+                continue;
+            }
+            let t = now.checked_sub(age).unwrap_or(now);
+            if let Some(dc) = self.band_accountant(r.band) {
+                dc.record(t, Duration::from_millis(r.airtime));
+            }
+        }
+    }
+
+    fn band(freq: f32) -> Option<usize> {
+        EU868_BANDS.iter().position(|b| freq >= b.0 && freq < b.1)
+    }
+
+    fn name(band: Option<usize>) -> String {
+        match band {
+            Some(i) => format!("{}-{}MHz", EU868_BANDS[i].0, EU868_BANDS[i].1),
+            None => "out of band".to_string(),
+        }
+    }
+
+    fn band_limit(&self, band: Option<usize>) -> Option<f32> {
+        if self.limit > 0.0 {
+            Some(self.limit)
+        } else {
+            band.map(|i| EU868_BANDS[i].2)
+        }
+    }
+
+    fn millis(d: Duration) -> u64 {
+        d.as_secs() * 1000 + d.subsec_millis() as u64
+    }
+
+    // accountant of the sub-band containing freq, created on first use
+    fn accountant(&mut self, freq: f32) -> Option<&mut DutyCycle> {
+        self.band_accountant(Self::band(freq))
+    }
+
+    fn band_accountant(&mut self, band: Option<usize>) -> Option<&mut DutyCycle> {
+        let limit = self.band_limit(band)?;
+        let i = match self.bands.iter().position(|b| b.0 == band) {
+            Some(i) => i,
+            None => {
+                self.bands.push((band, DutyCycle::new(limit, self.window)));
+                self.bands.len() - 1
+            }
+        };
+        Some(&mut self.bands[i].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn rolling_window() {
+        // 1s every 10s
+        let mut dc = DutyCycle::new(10.0, 10);
+        let t0 = Instant::now();
+        dc.record(t0, ms(600));
+        dc.record(t0 + ms(5000), ms(300));
+
+        assert!(dc.allows(t0 + ms(5000), ms(100), 1.0));
+        assert!(!dc.allows(t0 + ms(5000), ms(200), 1.0));
+        assert!(!dc.allows(t0 + ms(5000), ms(100), 0.8));
+        assert!((dc.usage(t0 + ms(5000)) - 9.0).abs() < 0.01);
+
+        // the first one leaves the window after 10s, not before
+        assert_eq!(dc.used(t0 + ms(10000)), ms(900));
+        assert_eq!(dc.used(t0 + ms(10001)), ms(300));
+        assert!(dc.allows(t0 + ms(10001), ms(700), 1.0));
+        assert_eq!(dc.used(t0 + ms(15001)), ms(0));
+    }
+
+    #[test]
+    fn eu868_sub_bands() {
+        let dc = DutyCycles::new(0.0, 3600);
+        assert_eq!(dc.limit(868.1), Some(1.0));
+        assert_eq!(dc.limit(868.8), Some(0.1));
+        assert_eq!(dc.limit(869.525), Some(10.0));
+        assert_eq!(dc.limit(434.0), None);
+        // a configured limit applies everywhere
+        assert_eq!(DutyCycles::new(2.0, 3600).limit(434.0), Some(2.0));
+    }
+
+    #[test]
+    fn sub_band_charged_once() {
+        let mut dc = DutyCycles::new(0.0, 100);
+        let t0 = Instant::now();
+        // two channels of the same sub-band and one of another
+        dc.record(t0, &[868.1, 868.3, 869.525], ms(500));
+        assert!((dc.usage(t0, 868.1).unwrap() - 0.5).abs() < 0.01);
+        assert!((dc.usage(t0, 869.525).unwrap() - 0.5).abs() < 0.01);
+
+        // 1s budget in 868.0-868.6
+        let full = dc.full_band(t0, &[869.525, 868.3], ms(600), 1.0).unwrap();
+        assert_eq!(full.band, "868-868.6MHz");
+        assert!(dc.full_band(t0, &[869.525, 868.3], ms(500), 1.0).is_none());
+    }
+
+    #[test]
+    fn too_long() {
+        let dc = DutyCycles::new(0.0, 100);
+        assert!(dc.too_long(&[868.1], ms(1000), 1.0).is_none());
+        assert_eq!(dc.too_long(&[868.1], ms(1001), 1.0), Some("868-868.6MHz".to_string()));
+        assert!(dc.too_long(&[868.1], ms(900), 0.8).is_some());
+        // not enforced
+        assert!(dc.too_long(&[434.0], ms(100000), 1.0).is_none());
+    }
+
+    #[test]
+    fn history_restored() {
+        let mut dc = DutyCycles::new(0.0, 100);
+        let now = Instant::now();
+        dc.record(now, &[868.1], ms(400));
+        dc.record(now, &[869.525], ms(2000));
+        let mut records = dc.history(now);
+        assert_eq!(records.len(), 2);
+
+        let wall = DutyCycles::millis(SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+        // expired, and from the future
+        records.push(DutyRecord {
+            band: Some(1),
+            time: wall - 200000,
+            airtime: 500,
+        });
+        records.push(DutyRecord {
+            band: Some(1),
+            time: wall + 60000,
+            airtime: 100,
+        });
+
+        let mut restored = DutyCycles::new(0.0, 100);
+        restored.restore(now, &records);
+        assert!((restored.usage(now, 868.1).unwrap() - 0.5).abs() < 0.01);
+        assert!((restored.usage(now, 869.525).unwrap() - 2.0).abs() < 0.01);
+    }
+}
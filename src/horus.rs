@@ -0,0 +1,362 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Horus Binary v2 telemetry packets, encoded like horus_l2.c from
+// horusdemodlib: unique word, payload, Golay (23,12) parity bits,
+// interleaver and scrambler. Sent with RF95::send_4fsk.
+
+// packet and encoded frame sizes
+pub const PACKET_LEN: usize = 32;
+const UNIQUE_WORD: [u8; 2] = [0x24, 0x24];
+
+// 0x1b = symbols 0, 1, 2, 3, so the demodulator sees the 4 tones
+const PREAMBLE_BYTE: u8 = 0x1b;
+
+// Golay (23,12) generator polynomial
+const GOLAY_GENPOL: u32 = 0x0000_0c75;
+const X22: u32 = 0x0040_0000;
+const X11: u32 = 0x0000_0800;
+const MASK12: u32 = 0xffff_f800;
+
+// initial state of the additive scrambler
+const SCRAMBLER_INIT: u16 = 0x4a80;
+
+pub struct HorusPacket {
+    // from the Horus payload ID list, 256 is 4FSKTEST-V2
+    pub payload_id: u16,
+    pub counter: u16,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub latitude: f32,
+    pub longitude: f32,
+    // m
+    pub altitude: u16,
+    // km/h
+    pub speed: u8,
+    pub sats: u8,
+    // ºC
+    pub temperature: i8,
+    // 0-255 for 0-5V
+    pub batt_voltage: u8,
+    pub custom: [u8; 9],
+}
+
+impl HorusPacket {
+    // little endian, CRC16 of the first 30 bytes at the end
+    pub fn to_bytes(&self) -> [u8; PACKET_LEN] {
+        let mut p = [0_u8; PACKET_LEN];
+        p[0..2].copy_from_slice(&self.payload_id.to_le_bytes());
+        p[2..4].copy_from_slice(&self.counter.to_le_bytes());
+        p[4] = self.hours;
+        p[5] = self.minutes;
+        p[6] = self.seconds;
+        p[7..11].copy_from_slice(&self.latitude.to_le_bytes());
+        p[11..15].copy_from_slice(&self.longitude.to_le_bytes());
+        p[15..17].copy_from_slice(&self.altitude.to_le_bytes());
+        p[17] = self.speed;
+        p[18] = self.sats;
+        p[19] = self.temperature as u8;
+        p[20] = self.batt_voltage;
+        p[21..30].copy_from_slice(&self.custom);
+        let crc = crc16(&p[..30]);
+        p[30..32].copy_from_slice(&crc.to_le_bytes());
+        p
+    }
+}
+
+// CRC16-CCITT (poly 0x1021, init 0xffff)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// frame size for a payload
+pub fn encoded_len(payload_len: usize) -> usize {
+    let payload_bits = payload_len * 8;
+    let codewords = payload_bits.div_ceil(12);
+    let bits = UNIQUE_WORD.len() * 8 + payload_bits + codewords * 11;
+    bits.div_ceil(8)
+}
+
+// unique word, payload and parity bits, interleaved and scrambled
+// (except the unique word)
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(encoded_len(payload.len()));
+    out.extend_from_slice(&UNIQUE_WORD);
+    out.extend_from_slice(payload);
+
+    // Golay encode each 12 bits of payload, MSB first,
+    // and append the 11 parity bits of each codeword
+    let mut parity = BitWriter::new();
+    let mut codeword: u32 = 0;
+    let mut n = 0;
+    for i in 0..payload.len() * 8 {
+        let bit = (payload[i / 8] >> (7 - i % 8)) & 0x01;
+        codeword = (codeword << 1) | bit as u32;
+        n += 1;
+        if n == 12 {
+            parity.push_bits(golay_syndrome(codeword << 11), 11);
+            codeword = 0;
+            n = 0;
+        }
+    }
+    // last partial codeword, horus_l2.c leaves it right aligned
+    // and shifts it one more bit than the full ones
+    if n > 0 {
+        parity.push_bits(golay_syndrome(codeword << 12), 11);
+    }
+    out.extend(parity.finish());
+
+    interleave(&mut out[UNIQUE_WORD.len()..]);
+    scramble(&mut out[UNIQUE_WORD.len()..]);
+
+    out
+}
+
+// preamble bytes to send before the frame
+pub fn preamble(len: usize) -> Vec<u8> {
+    vec![PREAMBLE_BYTE; len]
+}
+
+// parity bits of a Golay (23,12) codeword
+fn golay_syndrome(mut pattern: u32) -> u32 {
+    let mut aux = X22;
+    if pattern >= X11 {
+        while pattern & MASK12 != 0 {
+            while aux & pattern == 0 {
+                aux >>= 1;
+            }
+            pattern ^= (aux / X11) * GOLAY_GENPOL;
+        }
+    }
+    pattern
+}
+
+// bit i goes to bit (b * i) mod nbits, with b the largest prime
+// below nbits. Bits are numbered LSB first in each byte.
+fn interleave(data: &mut [u8]) {
+    let nbits = data.len() * 8;
+    let b = largest_prime_below(nbits);
+    let mut out = vec![0_u8; data.len()];
+    for i in 0..nbits {
+        let j = (b * i) % nbits;
+        let bit = (data[i / 8] >> (i % 8)) & 0x01;
+        out[j / 8] |= bit << (j % 8);
+    }
+    data.copy_from_slice(&out);
+}
+
+// additive scrambler, avoids long runs of the same symbol
+fn scramble(data: &mut [u8]) {
+    let mut scrambler = SCRAMBLER_INIT;
+    for i in 0..data.len() * 8 {
+        let s = ((scrambler & 0x02) >> 1) ^ (scrambler & 0x01);
+        data[i / 8] ^= (s as u8) << (i % 8);
+        scrambler >>= 1;
+        scrambler |= s << 14;
+    }
+}
+
+fn largest_prime_below(n: usize) -> usize {
+    (2..n).rev().find(|&p| (2..p).take_while(|d| d * d <= p).all(|d| p % d != 0)).unwrap_or(1)
+}
+
+// packs bits MSB first
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    n: usize,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            current: 0,
+            n: 0,
+        }
+    }
+
+    fn push_bits(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            self.current = (self.current << 1) | ((value >> i) & 0x01) as u8;
+            self.n += 1;
+            if self.n == 8 {
+                self.bytes.push(self.current);
+                self.current = 0;
+                self.n = 0;
+            }
+        }
+    }
+
+    // pad the last byte with zeros
+    fn finish(mut self) -> Vec<u8> {
+        if self.n > 0 {
+            self.bytes.push(self.current << (8 - self.n));
+        }
+        self.bytes
+    }
+}
+
+// No frame encoded by horus_l2.c to compare with here, so each
+// stage is checked against the properties it must have
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weight(x: u32) -> u32 {
+        x.count_ones()
+    }
+
+    fn deinterleave(data: &mut [u8]) {
+        let nbits = data.len() * 8;
+        let b = largest_prime_below(nbits);
+        let mut out = vec![0_u8; data.len()];
+        for i in 0..nbits {
+            let j = (b * i) % nbits;
+            let bit = (data[j / 8] >> (j % 8)) & 0x01;
+            out[i / 8] |= bit << (i % 8);
+        }
+        data.copy_from_slice(&out);
+    }
+
+    fn packet() -> HorusPacket {
+        HorusPacket {
+            payload_id: 256,
+            counter: 1234,
+            hours: 12,
+            minutes: 34,
+            seconds: 56,
+            latitude: 43.5,
+            longitude: -5.75,
+            altitude: 23456,
+            speed: 42,
+            sats: 9,
+            temperature: -20,
+            batt_voltage: 200,
+            custom: [1, 2, 3, 4, 5, 6, 7, 8, 9],
+        }
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        // CRC-16/CCITT-FALSE
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn packet_layout() {
+        let p = packet().to_bytes();
+        assert_eq!(&p[0..2], &[0x00, 0x01]);
+        assert_eq!(&p[2..4], &[0xd2, 0x04]);
+        assert_eq!(&p[4..7], &[12, 34, 56]);
+        assert_eq!(&p[7..11], &43.5_f32.to_le_bytes());
+        assert_eq!(&p[15..17], &[0xa0, 0x5b]);
+        assert_eq!(p[19], 0xec);
+        assert_eq!(crc16(&p[..30]), u16::from_le_bytes([p[30], p[31]]));
+    }
+
+    #[test]
+    fn golay_codewords() {
+        // parity makes each codeword a multiple of the generator, and
+        // the (23,12) Golay code has a minimum distance of 7
+        let mut min = 23;
+        for data in 1..(1_u32 << 12) {
+            let codeword = (data << 11) | golay_syndrome(data << 11);
+            assert_eq!(golay_syndrome(codeword), 0, "{:03x}", data);
+            min = min.min(weight(codeword));
+        }
+        assert_eq!(min, 7);
+    }
+
+    #[test]
+    fn frame_sizes() {
+        // v1 and v2 frames
+        assert_eq!(encoded_len(22), 45);
+        assert_eq!(encoded_len(PACKET_LEN), 65);
+        assert_eq!(encode(&packet().to_bytes()).len(), 65);
+    }
+
+    #[test]
+    fn interleaver_is_a_permutation() {
+        assert_eq!(largest_prime_below(504), 503);
+        let nbits = 63 * 8;
+        let mut seen = vec![false; nbits];
+        for i in 0..nbits {
+            let mut data = [0_u8; 63];
+            data[i / 8] = 1 << (i % 8);
+            interleave(&mut data);
+            let set: Vec<usize> = (0..nbits).filter(|j| data[j / 8] & (1 << (j % 8)) != 0).collect();
+            assert_eq!(set.len(), 1);
+            assert!(!seen[set[0]]);
+            seen[set[0]] = true;
+        }
+    }
+
+    #[test]
+    fn scrambler_round_trip() {
+        let data: Vec<u8> = (0..63).map(|i| (i * 37) as u8).collect();
+        let mut s = data.clone();
+        scramble(&mut s);
+        assert_ne!(s, data);
+        scramble(&mut s);
+        assert_eq!(s, data);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let payload = packet().to_bytes();
+        let frame = encode(&payload);
+        assert_eq!(&frame[..2], &UNIQUE_WORD);
+
+        let mut body = frame[2..].to_vec();
+        scramble(&mut body);
+        deinterleave(&mut body);
+        assert_eq!(&body[..PACKET_LEN], &payload[..]);
+
+        // each 12 payload bits and their 11 parity bits are a codeword
+        let bit = |data: &[u8], i: usize| ((data[i / 8] >> (7 - i % 8)) & 0x01) as u32;
+        let parity = &body[PACKET_LEN..];
+        let codewords = (PACKET_LEN * 8).div_ceil(12);
+        for c in 0..codewords {
+            let mut codeword = 0;
+            for i in c * 12..(c + 1) * 12 {
+                let b = if i < PACKET_LEN * 8 { bit(&payload, i) } else { 0 };
+                codeword = (codeword << 1) | b;
+            }
+            if c == codewords - 1 {
+                // the last partial codeword is right aligned
+                codeword >>= 12 - (PACKET_LEN * 8 - c * 12);
+                codeword <<= 1;
+            }
+            for i in c * 11..(c + 1) * 11 {
+                codeword = (codeword << 1) | bit(parity, i);
+            }
+            assert_eq!(golay_syndrome(codeword), 0, "codeword {}", c);
+        }
+    }
+}
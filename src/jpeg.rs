@@ -0,0 +1,490 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Baseline JPEG pieces used by the SSDV codec: the standard
+// quantisation and Huffman tables (ITU T.81 Annex K), colour
+// conversion, DCT and the entropy coding of 8x8 blocks.
+
+#![allow(dead_code)]
+
+// natural order index of each zigzag position
+pub const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
+    20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
+    59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Annex K.1 quantisation tables, natural order
+const STD_LUMINANCE_QT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69,
+    56, 14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104,
+    113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+const STD_CHROMINANCE_QT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99,
+    99, 47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+// Annex K.3 Huffman tables: number of codes of each length 1-16
+// and the symbols in code order
+pub const DC_LUMINANCE_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+pub const DC_LUMINANCE_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+pub const DC_CHROMINANCE_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+pub const DC_CHROMINANCE_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+pub const AC_LUMINANCE_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+pub const AC_LUMINANCE_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+    0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52,
+    0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25,
+    0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45,
+    0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64,
+    0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83,
+    0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+    0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+    0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3,
+    0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8,
+    0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+pub const AC_CHROMINANCE_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+pub const AC_CHROMINANCE_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
+    0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33,
+    0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18,
+    0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44,
+    0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63,
+    0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a,
+    0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97,
+    0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4,
+    0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca,
+    0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7,
+    0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+// DCT basis, cos((2x + 1) * u * PI / 16) indexed [x][u]
+const COSINE: [[f32; 8]; 8] = [
+    [1.0, 0.98078525, 0.9238795, 0.8314696, 0.70710677, 0.55557024, 0.38268343, 0.19509032],
+    [1.0, 0.8314696, 0.38268343, -0.19509032, -0.70710677, -0.98078525, -0.9238795, -0.55557024],
+    [1.0, 0.55557024, -0.38268343, -0.98078525, -0.70710677, 0.19509032, 0.9238795, 0.8314696],
+    [1.0, 0.19509032, -0.9238795, -0.55557024, 0.70710677, 0.8314696, -0.38268343, -0.98078525],
+    [1.0, -0.19509032, -0.9238795, 0.55557024, 0.70710677, -0.8314696, -0.38268343, 0.98078525],
+    [1.0, -0.55557024, -0.38268343, 0.98078525, -0.70710677, -0.19509032, 0.9238795, -0.8314696],
+    [1.0, -0.8314696, 0.38268343, 0.19509032, -0.70710677, 0.98078525, -0.9238795, 0.55557024],
+    [1.0, -0.98078525, 0.9238795, -0.8314696, 0.70710677, -0.55557024, 0.38268343, -0.19509032],
+];
+
+// AC symbols with a special meaning
+const EOB: u8 = 0x00;
+const ZRL: u8 = 0xf0;
+
+// quantisation table (natural order) scaled for a JPEG quality
+// factor 1-100, like libjpeg
+pub fn quant_table(chrominance: bool, quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+    let base = if chrominance {
+        &STD_CHROMINANCE_QT
+    } else {
+        &STD_LUMINANCE_QT
+    };
+
+    let mut table = [0_u16; 64];
+    for (t, b) in table.iter_mut().zip(base.iter()) {
+        *t = ((*b as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+    table
+}
+
+// JFIF colour conversion
+pub fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.168_736 * r - 0.331_264 * g + 0.5 * b + 128.0,
+        0.5 * r - 0.418_688 * g - 0.081_312 * b + 128.0,
+    )
+}
+
+// forward DCT of a level shifted block, quantised and
+// returned in zigzag order
+pub fn fdct_quantise(block: &[f32; 64], qt: &[u16; 64]) -> [i16; 64] {
+    let mut tmp = [0_f32; 64];
+    let mut out = [0_i16; 64];
+
+    // rows then columns
+    for y in 0..8 {
+        for u in 0..8 {
+            let mut s = 0.0;
+            for x in 0..8 {
+                s += (block[y * 8 + x] - 128.0) * COSINE[x][u];
+            }
+            tmp[y * 8 + u] = s * alpha(u) / 2.0;
+        }
+    }
+    for u in 0..8 {
+        for v in 0..8 {
+            let mut s = 0.0;
+            for (y, c) in COSINE.iter().enumerate() {
+                s += tmp[y * 8 + u] * c[v];
+            }
+            let coef = s * alpha(v) / 2.0;
+            let n = v * 8 + u;
+            // baseline coefficients are up to 10 bits
+            out[n] = (coef / qt[n] as f32).round().clamp(-1023.0, 1023.0) as i16;
+        }
+    }
+
+    let mut zz = [0_i16; 64];
+    for (k, z) in zz.iter_mut().enumerate() {
+        *z = out[ZIGZAG[k]];
+    }
+    zz
+}
+
+fn alpha(u: usize) -> f32 {
+    if u == 0 {
+        1.0 / 2.0_f32.sqrt()
+    } else {
+        1.0
+    }
+}
+
+// number of bits of the magnitude of a coefficient
+fn category(value: i16) -> u8 {
+    let mut v = (value as i32).abs();
+    let mut n = 0;
+    while v > 0 {
+        v >>= 1;
+        n += 1;
+    }
+    n
+}
+
+// the extra bits of a coefficient, negative values are
+// sent as their ones' complement
+fn magnitude_bits(value: i16, size: u8) -> u32 {
+    if value < 0 {
+        ((value as i32 - 1) as u32) & ((1 << size) - 1)
+    } else {
+        value as u32
+    }
+}
+
+// Huffman table for encoding, code and length of each symbol
+pub struct HuffmanEncoder {
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanEncoder {
+    pub fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut codes = [(0_u16, 0_u8); 256];
+        let mut code: u16 = 0;
+        let mut k = 0;
+        for (i, n) in bits.iter().enumerate() {
+            for _ in 0..*n {
+                codes[values[k] as usize] = (code, i as u8 + 1);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        Self { codes }
+    }
+
+    fn write(&self, w: &mut BitWriter, symbol: u8) {
+        let (code, len) = self.codes[symbol as usize];
+        w.write(code as u32, len);
+    }
+}
+
+// DC and AC tables of a component
+pub struct ComponentEncoder {
+    pub dc: HuffmanEncoder,
+    pub ac: HuffmanEncoder,
+}
+
+impl ComponentEncoder {
+    pub fn luminance() -> Self {
+        Self {
+            dc: HuffmanEncoder::new(&DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES),
+            ac: HuffmanEncoder::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES),
+        }
+    }
+
+    pub fn chrominance() -> Self {
+        Self {
+            dc: HuffmanEncoder::new(&DC_CHROMINANCE_BITS, &DC_CHROMINANCE_VALUES),
+            ac: HuffmanEncoder::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES),
+        }
+    }
+
+    // entropy code a block (zigzag order), the DC coefficient
+    // relative to the predictor, which is updated
+    pub fn write_block(&self, w: &mut BitWriter, block: &[i16; 64], predictor: &mut i16) {
+        let diff = block[0] - *predictor;
+        *predictor = block[0];
+        let size = category(diff);
+        self.dc.write(w, size);
+        w.write(magnitude_bits(diff, size), size);
+
+        let mut run = 0;
+        for &c in block[1..].iter() {
+            if c == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                self.ac.write(w, ZRL);
+                run -= 16;
+            }
+            let size = category(c);
+            self.ac.write(w, (run << 4) | size);
+            w.write(magnitude_bits(c, size), size);
+            run = 0;
+        }
+        if run > 0 {
+            self.ac.write(w, EOB);
+        }
+    }
+}
+
+// packs bits MSB first, without JPEG byte stuffing
+pub struct BitWriter {
+    pub bytes: Vec<u8>,
+    current: u32,
+    n: u8,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            current: 0,
+            n: 0,
+        }
+    }
+
+    pub fn write(&mut self, value: u32, count: u8) {
+        for i in (0..count).rev() {
+            self.current = (self.current << 1) | ((value >> i) & 0x01);
+            self.n += 1;
+            if self.n == 8 {
+                self.bytes.push(self.current as u8);
+                self.current = 0;
+                self.n = 0;
+            }
+        }
+    }
+
+    // pad to a byte boundary with ones
+    pub fn align(&mut self) {
+        if self.n > 0 {
+            let pad = 8 - self.n;
+            self.write(0xff, pad);
+        }
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.n == 0
+    }
+}
+
+// Huffman table for decoding, codes of each length are
+// consecutive numbers
+pub struct HuffmanDecoder {
+    mincode: [i32; 17],
+    maxcode: [i32; 17],
+    valptr: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanDecoder {
+    pub fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut mincode = [0_i32; 17];
+        let mut maxcode = [-1_i32; 17];
+        let mut valptr = [0_usize; 17];
+        let mut code: i32 = 0;
+        let mut k = 0;
+        for (i, n) in bits.iter().enumerate() {
+            let len = i + 1;
+            if *n > 0 {
+                valptr[len] = k;
+                mincode[len] = code;
+                code += *n as i32;
+                k += *n as usize;
+                maxcode[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        Self {
+            mincode,
+            maxcode,
+            valptr,
+            values: values.to_vec(),
+        }
+    }
+
+    fn read(&self, r: &mut BitReader) -> Option<u8> {
+        let mut code: i32 = 0;
+        for len in 1..17 {
+            code = (code << 1) | r.read(1)? as i32;
+            if code <= self.maxcode[len] {
+                return Some(self.values[self.valptr[len] + (code - self.mincode[len]) as usize]);
+            }
+        }
+        None
+    }
+}
+
+// DC and AC tables of a component
+pub struct ComponentDecoder {
+    pub dc: HuffmanDecoder,
+    pub ac: HuffmanDecoder,
+}
+
+impl ComponentDecoder {
+    pub fn luminance() -> Self {
+        Self {
+            dc: HuffmanDecoder::new(&DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES),
+            ac: HuffmanDecoder::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES),
+        }
+    }
+
+    pub fn chrominance() -> Self {
+        Self {
+            dc: HuffmanDecoder::new(&DC_CHROMINANCE_BITS, &DC_CHROMINANCE_VALUES),
+            ac: HuffmanDecoder::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES),
+        }
+    }
+
+    // read a block (zigzag order), None if the data ends
+    // or is not valid
+    pub fn read_block(&self, r: &mut BitReader, predictor: &mut i16) -> Option<[i16; 64]> {
+        let mut block = [0_i16; 64];
+
+        let size = self.dc.read(r)?;
+        if size > 11 {
+            return None;
+        }
+        let diff = extend(r.read(size)?, size);
+        *predictor = predictor.wrapping_add(diff);
+        block[0] = *predictor;
+
+        let mut k = 1;
+        while k < 64 {
+            let symbol = self.ac.read(r)?;
+            let run = (symbol >> 4) as usize;
+            let size = symbol & 0x0f;
+            if size == 0 {
+                if symbol == ZRL {
+                    k += 16;
+                    continue;
+                }
+                // EOB
+                break;
+            }
+            k += run;
+            if k > 63 || size > 10 {
+                return None;
+            }
+            block[k] = extend(r.read(size)?, size);
+            k += 1;
+        }
+        Some(block)
+    }
+}
+
+// value of the extra bits of a coefficient
+fn extend(bits: u32, size: u8) -> i16 {
+    if size == 0 {
+        0
+    } else if bits < 1 << (size - 1) {
+        (bits as i32 - (1 << size) + 1) as i16
+    } else {
+        bits as i16
+    }
+}
+
+// dequantise a block in zigzag order and return its
+// samples, level shifted and clamped
+pub fn dequantise_idct(block: &[i16; 64], qt: &[u16; 64]) -> [u8; 64] {
+    let mut coef = [0_f32; 64];
+    for k in 0..64 {
+        let n = ZIGZAG[k];
+        coef[n] = block[k] as f32 * qt[n] as f32;
+    }
+
+    let mut tmp = [0_f32; 64];
+    let mut out = [0_u8; 64];
+    for v in 0..8 {
+        for x in 0..8 {
+            let mut s = 0.0;
+            for u in 0..8 {
+                s += alpha(u) * coef[v * 8 + u] * COSINE[x][u];
+            }
+            tmp[v * 8 + x] = s / 2.0;
+        }
+    }
+    for x in 0..8 {
+        for y in 0..8 {
+            let mut s = 0.0;
+            for v in 0..8 {
+                s += alpha(v) * tmp[v * 8 + x] * COSINE[y][v];
+            }
+            out[y * 8 + x] = (s / 2.0 + 128.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    out
+}
+
+// JFIF colour conversion
+pub fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (y as f32, cb as f32 - 128.0, cr as f32 - 128.0);
+    [
+        (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8,
+        (y - 0.344_136 * cb - 0.714_136 * cr).round().clamp(0.0, 255.0) as u8,
+        (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8,
+    ]
+}
+
+// reads bits MSB first, without JPEG byte stuffing
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn read(&mut self, count: u8) -> Option<u32> {
+        let mut value: u32 = 0;
+        for _ in 0..count {
+            let byte = *self.data.get(self.pos / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 0x01) as u32;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
@@ -44,6 +44,15 @@ impl LED {
         Ok(())
     }
 
+    pub fn deinit(&mut self) -> Result<(), sysfs_gpio::Error> {
+        // switch it off and release the pin
+        self.pin.set_value(0)?;
+
+        self.pin.unexport()?;
+
+        Ok(())
+    }
+
     // fast blink
     pub fn blink(&mut self) -> Result<(), sysfs_gpio::Error> {
         self.pin.set_value(1)?;
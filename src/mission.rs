@@ -26,12 +26,16 @@ extern crate rusttype;
 extern crate serial;
 extern crate spidev;
 extern crate sysfs_gpio;
+extern crate signal_hook;
 
 use std::thread;
 use std::process::Command;
 use std::time::{Duration, Instant};
 use sysfs_gpio::{Direction, Pin};
 use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use signal_hook::consts::{SIGINT, SIGTERM};
 
 // own uses
 mod gps;
@@ -40,9 +44,15 @@ use gps::*;
 mod picture;
 use picture::*;
 
+mod radio;
+use radio::*;
+
 mod rf95;
 use rf95::*;
 
+mod sx1262;
+use sx1262::*;
+
 mod ds18b20;
 use ds18b20::*;
 
@@ -64,9 +74,34 @@ use ms5607::*;
 mod telemetry;
 use telemetry::*;
 
+mod jpeg;
+mod rs8;
 mod ssdv;
 use ssdv::*;
 
+mod watchdog;
+use watchdog::*;
+
+mod state;
+use state::*;
+
+mod scheduler;
+use scheduler::*;
+
+mod txqueue;
+use txqueue::*;
+
+mod dutycycle;
+use dutycycle::*;
+
+mod horus;
+
+mod morse;
+
+// share of the duty-cycle budget SSDV and bulk packets can use,
+// the rest is kept for telemetry and acks
+const DUTY_CYCLE_LOW_PRIORITY: f32 = 0.8;
+
 
 // MISSION STRUCT
 //////////////////
@@ -81,15 +116,61 @@ struct Mission {
     baro: Ms5607,
     temp_internal: DS18B20,
     temp_external: DS18B20,
-    lora: RF95,
+    lora: Box<dyn Radio>,
     pwr_pin: Pin,
     pwr_sel: u8,
     telem: Telemetry,
     pic: Picture,
+    ssdv: Option<SSDV>,
+    ssdv_next: u64,
+    ssdv_sent: u64,
+    txq: TxQueue,
+    duty: Option<DutyCycles>,
+    duty_throttled: bool,
+    rtty: Option<RttyConfig>,
+    horus: Option<FourFskConfig>,
+    horus_payload_id: u16,
+    horus_preamble: usize,
+    cw: Option<CwConfig>,
+    // frequency hopping table of the profiles that hop
+    hop_channels: Vec<f32>,
+    telemetry_profile: RadioProfile,
+    ssdv_profile: RadioProfile,
+    uplink_profile: RadioProfile,
+    active_profile: &'static str,
+    radio_sleep: bool,
+    cw_text: String,
+    vbatt: f32,
+    scheduler: Scheduler,
+    watchdog: Watchdog,
+    state: MissionState,
+    state_file: String,
+    state_error: Option<io::Error>,
+    resumed: bool,
+    terminate: Arc<AtomicBool>,
 }
 
 impl Mission {
     pub fn new(conf: &Config) -> Self {
+        // reload the state if we are rebooting in the middle of the flight
+        let state_file = conf.path_main_dir.clone() + "mission_state.toml";
+        let (state, state_error, resumed) = match MissionState::load(&state_file) {
+            Ok(Some(s)) => (s, None, true),
+            Ok(None) => (MissionState::default(), None, false),
+            Err(e) => (MissionState::default(), Some(e), false),
+        };
+        let mut telem = Telemetry::new(conf.id.clone(), conf.msg.clone(), conf.separator.clone());
+        telem.count = state.telemetry_count;
+        let schedule = if conf.schedule.is_empty() {
+            let mut tasks = Scheduler::default_tasks(conf.packet_delay, conf.packet_repeat);
+            if conf.cw_enabled {
+                tasks.extend(Scheduler::beacon_tasks());
+            }
+            tasks
+        } else {
+            conf.schedule.clone()
+        };
+
         Self {
             log: Log::new(),
             datalog: Log:: new(),
@@ -100,15 +181,73 @@ impl Mission {
             baro: Ms5607::new(conf.baro_i2c_bus, conf.baro_addr),
             temp_internal: DS18B20::new(&conf.temp_internal_addr),
             temp_external: DS18B20::new(&conf.temp_external_addr),
-            lora: RF95::new(conf.lora_cs, conf.lora_int_pin, false),
+            lora: Self::radio(conf),
             pwr_pin: Pin::new(conf.pwr_pin as u64),
             pwr_sel: 0,
-            telem: Telemetry::new(conf.id.clone(), conf.msg.clone(), conf.separator.clone()),
+            telem,
             pic: Picture::new(
-                0,
+                state.picture_number,
                 "ssdv",
                 &(conf.path_main_dir.clone() + &conf.path_images_dir.clone()),
             ),
+            ssdv: None,
+            ssdv_next: 0,
+            ssdv_sent: 0,
+            txq: TxQueue::new(
+                if conf.tx_telemetry_max_gap > 0 {
+                    conf.tx_telemetry_max_gap
+                } else {
+                    2 * conf.packet_delay
+                },
+                conf.tx_share_ssdv,
+                conf.tx_share_bulk,
+            ),
+            duty: None,
+            duty_throttled: false,
+            rtty: None,
+            horus: None,
+            horus_payload_id: conf.horus_payload_id,
+            horus_preamble: conf.horus_preamble as usize,
+            cw: None,
+            hop_channels: Vec::new(),
+            // set up in init()
+            telemetry_profile: RadioProfile {
+                name: "telemetry",
+                frequency: conf.lora_freq,
+                modem: ModemPreset::Bw125Cr45Sf128.config(),
+                power: conf.lora_low_pwr,
+                hopping: false,
+            },
+            ssdv_profile: RadioProfile {
+                name: "ssdv",
+                frequency: conf.lora_freq,
+                modem: ModemPreset::Bw125Cr45Sf128.config(),
+                power: conf.lora_low_pwr,
+                hopping: false,
+            },
+            uplink_profile: RadioProfile {
+                name: "uplink",
+                frequency: conf.lora_freq,
+                modem: ModemPreset::Bw125Cr45Sf128.config(),
+                power: conf.lora_low_pwr,
+                hopping: false,
+            },
+            active_profile: "telemetry",
+            radio_sleep: conf.lora_sleep,
+            cw_text: format!("DE {} {}", conf.id, conf.cw_msg).trim().to_string(),
+            vbatt: 0.0,
+            scheduler: Scheduler::new(schedule),
+            watchdog: Watchdog::new(
+                &conf.watchdog_device,
+                conf.watchdog_timeout,
+                conf.watchdog_systemd,
+                &(conf.path_main_dir.clone() + "watchdog_resets"),
+            ),
+            state,
+            state_file,
+            state_error,
+            resumed,
+            terminate: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -117,6 +256,43 @@ impl Mission {
         self.log.init(&(conf.path_main_dir.clone() + &conf.path_log_prefix));
         self.log.log(LogType::Info, "NSX starting.")?;
 
+        // Stop cleanly when systemd (SIGTERM) or the user (SIGINT) asks us to
+        signal_hook::flag::register(SIGTERM, Arc::clone(&self.terminate))?;
+        signal_hook::flag::register(SIGINT, Arc::clone(&self.terminate))?;
+
+        // Mission state
+        if let Some(e) = self.state_error.take() {
+            self.log.log(
+                LogType::Error,
+                &format!("Can't read mission state, starting from scratch: {}", e),
+            )?;
+        }
+        if self.resumed {
+            self.log.log(
+                LogType::Info,
+                &format!(
+                    "Resuming mission: phase {}, picture {}, telemetry packet {}, max alt {}m, launched {}",
+                    self.state.phase,
+                    self.state.picture_number,
+                    self.state.telemetry_count,
+                    self.state.max_altitude,
+                    if self.state.launch_time.is_empty() { "no" } else { &self.state.launch_time },
+                ),
+            )?;
+        }
+
+        // Watchdogs
+        match self.watchdog.init() {
+            Ok(()) => self.log.log(
+                LogType::Info,
+                &format!("Watchdog resets: {}", self.watchdog.resets),
+            )?,
+            Err(e) => self.log.log(
+                LogType::Error,
+                &format!("Can't configure watchdog: {}", e),
+            )?,
+        }
+
         // datalog
         self.datalog.init(&(conf.path_main_dir.clone() + "datalog_"));
 
@@ -189,6 +365,7 @@ impl Mission {
         }
 
         // LoRa radio
+        self.lora.set_spi_speed(conf.lora_spi_speed);
         match self.lora.init() {
             Ok(()) => println!("LoRa init ok"),
             Err(e) => {
@@ -196,8 +373,193 @@ impl Mission {
                 std::process::exit(1);
             }
         }
+        self.lora.set_sleep_after_tx(conf.lora_sleep);
 
-        self.lora.set_frequency(conf.lora_freq);
+        if let Err(e) = self.lora.set_frequency(conf.lora_freq) {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
+        }
+
+        // Modem preset, with the explicit values from the config
+        let modem = match ModemPreset::from_name(&conf.lora_preset) {
+            Some(p) => p.config(),
+            None => {
+                println!("ERROR: Unknown LoRa preset {}", conf.lora_preset);
+                std::process::exit(1);
+            }
+        };
+        let modem = modem.with_overrides(
+            conf.lora_bandwidth,
+            conf.lora_spreading_factor,
+            conf.lora_coding_rate,
+        );
+        let modem = match modem {
+            Ok(m) => ModemConfig {
+                implicit_header: conf.lora_implicit_header,
+                crc: conf.lora_crc,
+                ..m
+            },
+            Err(e) => {
+                println!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = self.lora.set_modem_config(&modem) {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
+        }
+        if conf.lora_radiohead_header {
+            self.lora.set_radiohead_headers(true);
+            self.lora.set_this_address(conf.lora_address);
+            self.lora.set_header_to(conf.lora_destination);
+            self.lora.set_promiscuous(conf.lora_promiscuous);
+            self.log.log(
+                LogType::Info,
+                &format!(
+                    "RadioHead headers, address {}, destination {}{}",
+                    conf.lora_address,
+                    conf.lora_destination,
+                    if conf.lora_promiscuous { ", promiscuous" } else { "" }
+                ),
+            )?;
+        }
+        // SSDV packets are sent without the sync byte
+        let ssdv_len = conf.ssdv_packet_len as usize;
+        if let Err(e) = check_options(conf.ssdv_quality, conf.ssdv_fec, ssdv_len) {
+            println!("ERROR: Invalid SSDV configuration: {}", e);
+            std::process::exit(1);
+        }
+        if ssdv_len - 1 + self.lora.header_len() > MAX_PACKET_LEN {
+            println!("ERROR: SSDV packets don't fit in a radio packet with the RadioHead header");
+            std::process::exit(1);
+        }
+        self.log.log(
+            LogType::Info,
+            &format!(
+                "SSDV quality {}, {} byte packets{}",
+                conf.ssdv_quality,
+                ssdv_len,
+                if conf.ssdv_fec { "" } else { ", no FEC" }
+            ),
+        )?;
+        // RTTY copy of the telemetry
+        if conf.rtty_enabled {
+            let rtty = RttyConfig {
+                frequency: if conf.rtty_freq > 0.0 { conf.rtty_freq } else { conf.lora_freq },
+                baud: conf.rtty_baud,
+                shift: conf.rtty_shift,
+                bits: conf.rtty_bits,
+                stop_bits: conf.rtty_stop_bits,
+                preamble: conf.rtty_preamble,
+            };
+            if let Err(e) = rtty.validate() {
+                println!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+            self.log.log(
+                LogType::Info,
+                &format!(
+                    "RTTY: {}MHz, {} baud, {}Hz shift, {}N{}",
+                    rtty.frequency, rtty.baud, rtty.shift, rtty.bits, rtty.stop_bits
+                ),
+            )?;
+            self.rtty = Some(rtty);
+        }
+        // Horus Binary 4FSK copy of the telemetry
+        if conf.horus_enabled {
+            let fsk = FourFskConfig {
+                frequency: if conf.horus_freq > 0.0 { conf.horus_freq } else { conf.lora_freq },
+                baud: conf.horus_baud,
+                tone_spacing: conf.horus_tone_spacing,
+            };
+            if let Err(e) = fsk.validate() {
+                println!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+            self.log.log(
+                LogType::Info,
+                &format!(
+                    "Horus Binary: payload ID {}, {}MHz, {} baud, {}Hz tone spacing",
+                    conf.horus_payload_id, fsk.frequency, fsk.baud, fsk.tone_spacing
+                ),
+            )?;
+            self.horus = Some(fsk);
+        }
+        // CW identification beacon
+        if conf.cw_enabled {
+            let cw = CwConfig {
+                frequency: if conf.cw_freq > 0.0 { conf.cw_freq } else { conf.lora_freq },
+                wpm: conf.cw_wpm,
+            };
+            if let Err(e) = cw.validate() {
+                println!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+            self.log.log(
+                LogType::Info,
+                &format!("CW beacon: {}MHz, {} WPM, \"{}\"", cw.frequency, cw.wpm, self.cw_text),
+            )?;
+            self.cw = Some(cw);
+        }
+        if conf.lora_lbt {
+            let lbt = ListenBeforeTalk {
+                attempts: conf.lora_lbt_attempts,
+                min_backoff: conf.lora_lbt_min_backoff,
+                max_backoff: conf.lora_lbt_max_backoff,
+            };
+            if let Err(e) = lbt.validate() {
+                println!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+            // the watchdog isn't pet while the channel is busy
+            let timeout = Duration::from_secs(self.watchdog.timeout as u64);
+            if self.watchdog.timeout > 0 && lbt.max_wait() >= timeout {
+                println!(
+                    "ERROR: LBT backoffs of up to {}ms don't fit in the {}s watchdog timeout",
+                    lbt.max_wait().as_millis(),
+                    self.watchdog.timeout
+                );
+                std::process::exit(1);
+            }
+            self.lora.set_listen_before_talk(Some(lbt));
+            self.log.log(
+                LogType::Info,
+                &format!(
+                    "Listen before talk: {} attempts, {}-{}ms backoff",
+                    conf.lora_lbt_attempts, conf.lora_lbt_min_backoff, conf.lora_lbt_max_backoff
+                ),
+            )?;
+        }
+        let hop_table = FrequencyHopping {
+            channels: conf.lora_hop_channels.clone(),
+            period: conf.lora_hop_period,
+        };
+        if conf.lora_hop_period > 0 {
+            self.hop_channels = hop_table.channels.clone();
+            if let Err(e) = self.lora.set_hop_table(hop_table.clone()) {
+                println!("ERROR: Invalid frequency hopping configuration: {}", e);
+                std::process::exit(1);
+            }
+            self.log.log(
+                LogType::Info,
+                &format!(
+                    "Frequency hopping: {:?}MHz every {} symbols",
+                    hop_table.channels, hop_table.period
+                ),
+            )?;
+        }
+        self.log.log(
+            LogType::Info,
+            &format!(
+                "LoRa modem: BW {}Hz, SF{}, CR 4/{}, {} header, CRC {}{}",
+                modem.bandwidth.hz(),
+                modem.spreading_factor.value(),
+                modem.coding_rate.value(),
+                if modem.implicit_header { "implicit" } else { "explicit" },
+                if modem.crc { "on" } else { "off" },
+                if modem.low_data_rate_optimize() { ", LDRO" } else { "" },
+            ),
+        )?;
 
         // Power selection
         match self.pwr_pin.export() {
@@ -224,21 +586,131 @@ impl Mission {
             }
         };
 
-        match self.pwr_sel {
-            0 => self.lora.set_tx_power(conf.lora_low_pwr),
-            1 => self.lora.set_tx_power(conf.lora_high_pwr),
-            _ => {}
+        let power = match self.pwr_sel {
+            1 => conf.lora_high_pwr,
+            _ => conf.lora_low_pwr,
+        };
+        if let Err(e) = self.lora.set_tx_power(power) {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
         }
 
         self.log.log(LogType::Info, &format!("Power selection: {}", self.pwr_sel))?;
-    
+
+        // radio profiles, SSDV and uplink use the telemetry settings
+        // for the values that are not configured
+        let modem = self.lora.modem_config();
+        self.telemetry_profile = RadioProfile {
+            name: "telemetry",
+            frequency: conf.lora_freq,
+            modem,
+            power,
+            hopping: false,
+        };
+        self.ssdv_profile = Self::radio_profile(
+            "ssdv",
+            &self.telemetry_profile,
+            conf.ssdv_freq,
+            &conf.ssdv_preset,
+            conf.ssdv_power,
+            conf.ssdv_hopping && conf.lora_hop_period > 0,
+        );
+        self.uplink_profile = Self::radio_profile(
+            "uplink",
+            &self.telemetry_profile,
+            conf.uplink_freq,
+            &conf.uplink_preset,
+            conf.uplink_power,
+            conf.uplink_hopping && conf.lora_hop_period > 0,
+        );
+        for p in &[self.telemetry_profile, self.ssdv_profile, self.uplink_profile] {
+            if p.hopping {
+                if let Err(e) = hop_table.check_period(&p.modem) {
+                    println!("ERROR: Can't hop in the {} profile: {}", p.name, e);
+                    std::process::exit(1);
+                }
+            }
+            self.log.log(LogType::Info, &format!("Radio profile {}", Self::profile_string(p)))?;
+        }
+
+        // Duty-cycle, configured limit or the ones of the EU868 sub-bands
+        if conf.duty_cycle_enabled {
+            if conf.duty_cycle_window == 0 {
+                println!("ERROR: duty_cycle_window must be at least 1s");
+                std::process::exit(1);
+            }
+            let mut duty = DutyCycles::new(conf.duty_cycle_limit, conf.duty_cycle_window);
+            duty.restore(Instant::now(), &self.state.duty_history);
+            let mut channels = Vec::new();
+            for p in &[self.telemetry_profile, self.ssdv_profile, self.uplink_profile] {
+                channels.extend(self.channels(p));
+            }
+            channels.extend(self.rtty.map(|r| r.frequency));
+            channels.extend(self.horus.map(|h| h.frequency));
+            channels.extend(self.cw.map(|c| c.frequency));
+            let mut bands: Vec<String> = Vec::new();
+            for f in channels {
+                let band = DutyCycles::band_name(f);
+                if bands.contains(&band) {
+                    continue;
+                }
+                match duty.limit(f) {
+                    Some(l) => self.log.log(
+                        LogType::Info,
+                        &format!("Duty-cycle limit {}: {}% over {}s", band, l, conf.duty_cycle_window),
+                    )?,
+                    None => self.log.log(
+                        LogType::Warn,
+                        &format!("No duty-cycle limit known for {}MHz, not enforced", f),
+                    )?,
+                }
+                bands.push(band);
+            }
+            // a max-length packet must fit in the budget, or it would wait forever
+            for &(p, headroom) in &[
+                (self.telemetry_profile, 1.0),
+                (self.ssdv_profile, DUTY_CYCLE_LOW_PRIORITY),
+                (self.uplink_profile, 1.0),
+            ] {
+                let airtime = p.modem.time_on_air(DEFAULT_PREAMBLE_LEN, MAX_PACKET_LEN);
+                if let Some(band) = duty.too_long(&self.channels(&p), airtime, headroom) {
+                    println!(
+                        "ERROR: duty-cycle budget of {} over {}s is too small for a {}ms packet of profile {}",
+                        band,
+                        conf.duty_cycle_window,
+                        airtime.as_millis(),
+                        p.name
+                    );
+                    std::process::exit(1);
+                }
+            }
+            self.duty = Some(duty);
+        }
+
+        self.radio_diagnostics()?;
+        self.radio_standby()?;
+
+        for t in self.scheduler.tasks() {
+            self.log.log(
+                LogType::Info,
+                &format!(
+                    "Task {}: every {}s, priority {}, phases {:?}, min batt {}V",
+                    t.task, t.interval, t.priority, t.phases, t.min_batt
+                ),
+            )?;
+        }
+
+        if let Err(e) = self.watchdog.ready() {
+            self.log.log(LogType::Warn, &format!("Can't notify systemd: {}", e))?;
+        }
+
         Ok(())
     }
 
     pub fn update_telemetry(&mut self, conf: &Config) -> Result<(), io::Error> {
         // Update sensor data
         // GPS
-        match self.gps.update() {
+        let fix = match self.gps.update() {
             Ok(()) => {
                 self.log.log(
                     LogType::Data,
@@ -254,6 +726,7 @@ impl Mission {
                         self.gps.time
                     ),
                 )?;
+                true
             }
             Err(e) => {
                 match e.error_type {
@@ -272,8 +745,9 @@ impl Mission {
                     _ => {}
                 };
                 self.led.err().unwrap();
+                false
             }
-        }
+        };
 
         // Baro
         self.baro.update().unwrap();
@@ -335,6 +809,7 @@ impl Mission {
 
         let vbatt: f32 =
             conf.adc_v_mult * conf.adc_v_divider * (adc_batt as f32 * 3.3 / 1023.0);
+        self.vbatt = vbatt;
         self.log.log(LogType::Data, &format!("VBATT: {}", vbatt))?;
 
         // Create telemetry packet
@@ -353,26 +828,51 @@ impl Mission {
             t_out,
             self.pwr_sel,
         );
+
+        // flight phase
+        if let Some(phase) = self.state.update(self.gps.altitude, self.telem.ascent_rate(), fix) {
+            self.log.log(LogType::Info, &format!("Flight phase: {}", phase))?;
+        }
+        self.save_state()?;
+
         Ok(())
     }
 
-    pub fn send_telemetry(&mut self) -> Result<(), io::Error>{
-        // Send telemetry
+    pub fn save_state(&mut self) -> Result<(), io::Error> {
+        self.state.picture_number = self.pic.number;
+        self.state.telemetry_count = self.telem.count;
+        if let Some(ref mut dc) = self.duty {
+            self.state.duty_history = dc.history(Instant::now());
+        }
+
+        if let Err(e) = self.state.save(&self.state_file) {
+            self.log.log(LogType::Error, &format!("Can't save mission state: {}", e))?;
+        }
+        Ok(())
+    }
+
+    pub fn queue_telemetry(&mut self) -> Result<(), io::Error>{
+        // Queue telemetry, replaces any older packet still waiting
         self.log.log(LogType::Info, "Sending telemetry packet...")?;
-        self.lora.send(self.telem.aprs_string().as_bytes());
-        self.lora.wait_packet_sent();
-        self.log.log(LogType::Info, "Telemetry packet sent.")?;
-        self.led.blink().unwrap();
+        if let Some(ref mut dc) = self.duty {
+            self.telem.duty_cycle = dc.usage(Instant::now(), self.telemetry_profile.frequency);
+        }
+        let packet = self.telem.aprs_string().into_bytes();
+        self.txq.push(TxClass::Telemetry, packet);
         Ok(())
     }
 
-    pub fn send_ssdv(&mut self, conf: &Config) -> Result<(), io::Error>{
+    pub fn take_picture(&mut self, conf: &Config) -> Result<(), io::Error>{
         // Take picture
         match self.pic.capture() {
-            Ok(()) => self.log.log(
-                LogType::Info,
-                &format!("Picture shot: {}", self.pic.filename),
-            )?,
+            Ok(()) => {
+                self.log.log(
+                    LogType::Info,
+                    &format!("Picture shot: {}", self.pic.filename),
+                )?;
+                // don't reuse image IDs after a reboot
+                self.save_state()?;
+            }
             Err(e) => self
                 .log
                 .log(LogType::Error, &format!("Error taking picture {:?}", e))?,
@@ -422,45 +922,660 @@ impl Mission {
             conf.id.clone(),
             self.pic.number,
         );
+        ssdv.quality = conf.ssdv_quality;
+        ssdv.fec = conf.ssdv_fec;
+        ssdv.packet_len = conf.ssdv_packet_len as usize;
+
+        // the new image replaces the one being sent
+        self.abort_ssdv()?;
 
         match ssdv.encode() {
-            Ok(()) => self.log.log(
-                LogType::Info,
-                &format!(
-                    "SSDV Image {}, Packets encoded: {}",
-                    ssdv.binaryname, ssdv.packets
-                ),
-            )?,
+            Ok(()) => {
+                self.log.log(
+                    LogType::Info,
+                    &format!(
+                        "SSDV Image {}, Packets encoded: {}",
+                        ssdv.binaryname, ssdv.packets
+                    ),
+                )?;
+                // packets will be queued by the SSDV task
+                self.log.log(LogType::Info, "Sending SSDV image...")?;
+                self.ssdv = Some(ssdv);
+                self.ssdv_next = 0;
+                self.ssdv_sent = 0;
+            }
             Err(e) => self
                 .log
                 .log(LogType::Error, &format!("Error encoding SSDV: {:?}", e))?,
         };
 
-        // Send SSDV
-        self.log.log(LogType::Info, "Sending SSDV image...")?;
-        // get time
-        let mut last_time = Instant::now();
-        for i in 0..ssdv.packets {
-            self.lora.send(&ssdv.get_packet(i).unwrap());
-            self.lora.wait_packet_sent();
+        Ok(())
+    }
+
 
-            // check if we need to send telemetry between image packets
-            let now = Instant::now();
-            if now.duration_since(last_time).as_secs() > conf.packet_delay as u64 {
-                self.update_telemetry(&conf)?;
-                self.send_telemetry()?;
-                last_time = Instant::now();
+    // queue the next packet of the current SSDV image
+    pub fn queue_ssdv_packet(&mut self) -> Result<(), io::Error> {
+        let packet = match self.ssdv {
+            Some(ref mut ssdv) => ssdv.get_packet(self.ssdv_next),
+            None => return Ok(()),
+        };
+
+        match packet {
+            Ok(p) => {
+                self.txq.push(TxClass::Ssdv, p.radio_bytes().to_vec());
+                self.ssdv_next += 1;
+            }
+            Err(e) => {
+                self.log.log(
+                    LogType::Error,
+                    &format!("Error reading SSDV packet {}: {:?}", self.ssdv_next, e),
+                )?;
+                self.abort_ssdv()?;
             }
+        }
 
+        Ok(())
+    }
 
-            thread::sleep(Duration::from_millis(10));
+    // stop sending the current SSDV image, its queued packets are useless
+    fn abort_ssdv(&mut self) -> Result<(), io::Error> {
+        let total = match self.ssdv.take() {
+            Some(ssdv) => ssdv.packets,
+            None => return Ok(()),
+        };
+        let dropped = self.txq.purge(TxClass::Ssdv);
+        self.log.log(
+            LogType::Warn,
+            &format!(
+                "SSDV Image aborted at packet {} of {}, {} queued packets dropped.",
+                self.ssdv_sent, total, dropped
+            ),
+        )
+    }
+
+    // transmit the next frame in the radio queue,
+    // returns false if there was nothing to send
+    pub fn service_radio(&mut self, conf: &Config) -> Result<bool, io::Error> {
+        // keep telemetry regular even if the scheduler is busy
+        if self.txq.telemetry_due(Instant::now()) {
+            self.log.log(LogType::Warn, "Telemetry overdue, sending it now.")?;
+            self.update_telemetry(conf)?;
+            self.queue_telemetry()?;
         }
 
+        let frame = match self.txq.pop() {
+            Some(f) => f,
+            None => return Ok(false),
+        };
+
+        // retune for the kind of traffic
+        let profile = match frame.class {
+            TxClass::Ssdv => self.ssdv_profile,
+            TxClass::Ack => self.uplink_profile,
+            _ => self.telemetry_profile,
+        };
+        self.use_profile(profile)?;
+
+        // check the duty-cycle budget, SSDV and bulk can't use all of it
+        let now = Instant::now();
+        let airtime = self.lora.time_on_air(frame.data.len() + self.lora.header_len());
+        let channels = self.channels(&profile);
+        if let Some(ref mut dc) = self.duty {
+            let headroom = match frame.class {
+                TxClass::Ack | TxClass::Telemetry => 1.0,
+                _ => DUTY_CYCLE_LOW_PRIORITY,
+            };
+            if let Some(band) = dc.too_long(&channels, airtime, headroom) {
+                self.log.log(
+                    LogType::Warn,
+                    &format!("{:?} packet dropped, too long for the duty-cycle of {}.", frame.class, band),
+                )?;
+                return Ok(false);
+            }
+            if let Some(full) = dc.full_band(now, &channels, airtime, headroom) {
+                if !self.duty_throttled {
+                    self.log.log(
+                        LogType::Warn,
+                        &format!(
+                            "Duty-cycle limit reached ({}), delaying transmissions.",
+                            full
+                        ),
+                    )?;
+                    self.duty_throttled = true;
+                }
+                // bulk data is dropped, the rest waits for budget
+                if frame.class == TxClass::Bulk {
+                    self.log.log(LogType::Warn, "Bulk packet dropped (duty-cycle).")?;
+                } else {
+                    self.txq.push_front(frame);
+                }
+                // waiting for budget is expected, don't let the watchdog reboot us
+                self.progress()?;
+                return Ok(false);
+            }
+        }
+        if self.duty_throttled {
+            self.log.log(LogType::Info, "Duty-cycle budget available again.")?;
+            self.duty_throttled = false;
+        }
+
+        let sent = match self.lora.send(&frame.data).and_then(|_| self.lora.wait_packet_sent()) {
+            Ok(sent) => sent,
+            Err(e) => {
+                let stats = self.lora.stats();
+                self.log.log(
+                    LogType::Warn,
+                    &format!(
+                        "Radio error: {} ({} timeouts, {} resets, channel busy {} times).",
+                        e, stats.tx_timeouts, stats.resets, stats.channel_busy
+                    ),
+                )?;
+                // try again later, bulk data is not worth it
+                if frame.class != TxClass::Bulk {
+                    self.txq.push_front(frame);
+                }
+                return Ok(true);
+            }
+        };
+        // too long for the radio, it will never be sent
+        if !sent {
+            self.log.log(
+                LogType::Error,
+                &format!(
+                    "Radio refused the {} packet ({} bytes), dropped.",
+                    frame.class,
+                    frame.data.len()
+                ),
+            )?;
+            return Ok(true);
+        }
+        // only what went on air counts against the budget
+        if let Some(ref mut dc) = self.duty {
+            dc.record(now, &channels, airtime);
+        }
+        self.txq.sent(frame.class, airtime);
+        self.progress()?;
+
+        match frame.class {
+            TxClass::Telemetry => {
+                let waited = frame.queued.elapsed();
+                self.log.log(
+                    LogType::Info,
+                    &format!(
+                        "Telemetry packet sent ({}ms in queue).",
+                        waited.as_secs() * 1000 + waited.subsec_millis() as u64
+                    ),
+                )?;
+                if let Some(ref mut dc) = self.duty {
+                    let used: Vec<String> = dc.report(now).iter().map(|b| b.to_string()).collect();
+                    self.log.log(
+                        LogType::Info,
+                        &format!("Duty-cycle used: {}", used.join(", ")),
+                    )?;
+                }
+                self.led.blink().unwrap();
+                self.send_rtty(&frame.data)?;
+                self.send_horus()?;
+            }
+            TxClass::Ssdv => {
+                self.ssdv_sent += 1;
+                let total = match self.ssdv {
+                    Some(ref ssdv) => ssdv.packets,
+                    None => 0,
+                };
+                if total > 0 && self.ssdv_sent >= total {
+                    self.log.log(
+                        LogType::Info,
+                        &format!("SSDV Image {} packets sent.", total),
+                    )?;
+                    self.ssdv = None;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            _ => {}
+        }
+
+        Ok(true)
+    }
+
+    // driver of the configured LoRa radio
+    fn radio(conf: &Config) -> Box<dyn Radio> {
+        match conf.lora_radio.as_str() {
+            "rf95" => Box::new(RF95::new(conf.lora_cs, conf.lora_int_pin, false)),
+            "sx1262" => Box::new(SX1262::new(
+                conf.lora_cs,
+                conf.lora_busy_pin,
+                conf.lora_reset_pin,
+                conf.lora_tcxo_voltage,
+                conf.lora_dio2_rf_switch,
+            )),
+            r => {
+                println!("ERROR: Unknown radio {}", r);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // profile from the telemetry one, changing the configured values
+    fn radio_profile(
+        name: &'static str,
+        base: &RadioProfile,
+        freq: f32,
+        preset: &str,
+        power: u8,
+        hopping: bool,
+    ) -> RadioProfile {
+        let modem = if preset.is_empty() {
+            base.modem
+        } else {
+            match ModemPreset::from_name(preset) {
+                Some(p) => p.config(),
+                None => {
+                    println!("ERROR: Unknown LoRa preset {}", preset);
+                    std::process::exit(1);
+                }
+            }
+        };
+
+        RadioProfile {
+            name,
+            frequency: if freq > 0.0 { freq } else { base.frequency },
+            modem,
+            power: if power > 0 { power } else { base.power },
+            hopping,
+        }
+    }
+
+    // frequencies a profile transmits on, for the duty-cycle
+    fn channels(&self, p: &RadioProfile) -> Vec<f32> {
+        if p.hopping && !self.hop_channels.is_empty() {
+            self.hop_channels.clone()
+        } else {
+            vec![p.frequency]
+        }
+    }
+
+    fn profile_string(p: &RadioProfile) -> String {
+        format!(
+            "{}: {}MHz, BW {}Hz, SF{}, CR 4/{}, {}dBm{}",
+            p.name,
+            p.frequency,
+            p.modem.bandwidth.hz(),
+            p.modem.spreading_factor.value(),
+            p.modem.coding_rate.value(),
+            p.power,
+            if p.hopping { ", hopping" } else { "" }
+        )
+    }
+
+    // retune the radio for a kind of traffic, logging profile changes
+    fn use_profile(&mut self, profile: RadioProfile) -> Result<(), io::Error> {
+        if let Err(e) = self.lora.set_profile(&profile) {
+            self.log.log(
+                LogType::Error,
+                &format!("Can't set radio profile {}: {}", profile.name, e),
+            )?;
+            return Ok(());
+        }
+        if self.active_profile != profile.name {
+            self.active_profile = profile.name;
+            self.log.log(
+                LogType::Info,
+                &format!("Radio profile {}", Self::profile_string(&profile)),
+            )?;
+        }
+        Ok(())
+    }
+
+    // send the telemetry sentence as RTTY too, if enabled
+    fn send_rtty(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let rtty = match self.rtty {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let airtime = rtty.time_on_air(data.len());
+        if let Some(ref mut dc) = self.duty {
+            if dc.full_band(now, &[rtty.frequency], airtime, 1.0).is_some() {
+                self.log.log(LogType::Warn, "RTTY telemetry skipped (duty-cycle).")?;
+                return Ok(());
+            }
+        }
+
+        // it can take longer than the watchdog timeout, keep it
+        // happy while sending (errors show on the next pet)
+        let watchdog = &mut self.watchdog;
+        let sent = self.lora.send_rtty(&rtty, data, &mut || {
+            let _ = watchdog.pet();
+        });
+        match sent {
+            Ok(()) => {
+                if let Some(ref mut dc) = self.duty {
+                    dc.record(now, &[rtty.frequency], airtime);
+                }
+                self.log.log(LogType::Info, "RTTY telemetry sent.")?;
+                self.progress()?;
+            }
+            Err(e) => self.log.log(LogType::Error, &format!("Can't send RTTY: {}", e))?,
+        }
+
+        Ok(())
+    }
+
+    // send the telemetry as a Horus Binary v2 packet too, if enabled
+    fn send_horus(&mut self) -> Result<(), io::Error> {
+        let fsk = match self.horus {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        let packet = self.telem.horus_packet(self.horus_payload_id).to_bytes();
+        let mut data = horus::preamble(self.horus_preamble);
+        data.extend(horus::encode(&packet));
+
+        let now = Instant::now();
+        let airtime = fsk.time_on_air(data.len());
+        if let Some(ref mut dc) = self.duty {
+            if dc.full_band(now, &[fsk.frequency], airtime, 1.0).is_some() {
+                self.log.log(LogType::Warn, "Horus telemetry skipped (duty-cycle).")?;
+                return Ok(());
+            }
+        }
+
+        // it can take longer than the watchdog timeout, keep it
+        // happy while sending (errors show on the next pet)
+        let watchdog = &mut self.watchdog;
+        let sent = self.lora.send_4fsk(&fsk, &data, &mut || {
+            let _ = watchdog.pet();
+        });
+        match sent {
+            Ok(()) => {
+                if let Some(ref mut dc) = self.duty {
+                    dc.record(now, &[fsk.frequency], airtime);
+                }
+                self.log.log(LogType::Info, "Horus telemetry sent.")?;
+                self.progress()?;
+            }
+            Err(e) => self.log.log(LogType::Error, &format!("Can't send Horus packet: {}", e))?,
+        }
+
+        Ok(())
+    }
+
+    // send our ID (and message) in Morse code
+    pub fn send_beacon(&mut self) -> Result<(), io::Error> {
+        let cw = match self.cw {
+            Some(c) => c,
+            None => {
+                self.log.log(LogType::Warn, "CW beacon scheduled but not enabled.")?;
+                return Ok(());
+            }
+        };
+
+        let elements = morse::encode(&self.cw_text);
+        let now = Instant::now();
+        let airtime = cw.time_on_air(&elements);
+        if let Some(ref mut dc) = self.duty {
+            if dc.full_band(now, &[cw.frequency], airtime, 1.0).is_some() {
+                self.log.log(LogType::Warn, "CW beacon skipped (duty-cycle).")?;
+                return Ok(());
+            }
+        }
+
+        // it can take longer than the watchdog timeout, keep it
+        // happy while sending (errors show on the next pet)
+        let watchdog = &mut self.watchdog;
+        let sent = self.lora.send_cw(&cw, &elements, &mut || {
+            let _ = watchdog.pet();
+        });
+        match sent {
+            Ok(()) => {
+                if let Some(ref mut dc) = self.duty {
+                    dc.record(now, &[cw.frequency], airtime);
+                }
+                self.log.log(LogType::Info, "CW beacon sent.")?;
+                self.progress()?;
+            }
+            Err(e) => self.log.log(LogType::Error, &format!("Can't send CW beacon: {}", e))?,
+        }
+
+        Ok(())
+    }
+
+    // log the radio link statistics and queue them as a bulk packet
+    pub fn link_status(&mut self) -> Result<(), io::Error> {
+        let stats = self.lora.stats();
+        let last = match stats.last_packet {
+            Some(p) => format!(", last RSSI {}dBm SNR {:.2}dB", p.rssi, p.snr),
+            None => String::new(),
+        };
         self.log.log(
             LogType::Info,
-            &format!("SSDV Image {} packets sent.", ssdv.packets),
+            &format!(
+                "Link: TX {} packets ({} bytes, {}s on air), RX {} packets ({} bytes), {} CRC errors, {} TX timeouts, {} resets, {}ms FIFO transfers{}",
+                stats.tx_packets,
+                stats.tx_bytes,
+                stats.tx_airtime.as_secs(),
+                stats.rx_packets,
+                stats.rx_bytes,
+                stats.rx_crc_errors,
+                stats.tx_timeouts,
+                stats.resets,
+                stats.fifo_time.as_secs() * 1000 + stats.fifo_time.subsec_millis() as u64,
+                last
+            ),
         )?;
+        self.log.log(
+            LogType::Info,
+            &format!(
+                "Radio modes: sleep {}s, idle {}s, TX {}s, RX {}s",
+                stats.sleep_time.as_secs(),
+                stats.idle_time.as_secs(),
+                stats.tx_time.as_secs(),
+                stats.rx_time.as_secs()
+            ),
+        )?;
+
+        self.radio_diagnostics()?;
+        self.radio_standby()?;
+
+        let packet = self.telem.status_string(&stats, self.watchdog.resets).into_bytes();
+        if !self.txq.push(TxClass::Bulk, packet) {
+            self.log.log(LogType::Warn, "Status packet dropped, queue full.")?;
+        }
+        Ok(())
+    }
 
+    // radio waiting for the next packet, sleeping if configured
+    fn radio_standby(&mut self) -> Result<(), io::Error> {
+        let r = if self.radio_sleep {
+            self.lora.set_mode_sleep()
+        } else {
+            self.lora.set_mode_idle()
+        };
+        if let Err(e) = r {
+            self.log.log(LogType::Warn, &format!("Radio error: {}", e))?;
+        }
+        Ok(())
+    }
+
+    // check the radio version, temperature and configuration, a register
+    // that changed (latch-up, brownout...) is reported and the radio reconfigured
+    fn radio_diagnostics(&mut self) -> Result<(), io::Error> {
+        let diag = match self.lora.diagnose() {
+            Ok(d) => d,
+            Err(e) => {
+                self.log.log(LogType::Error, &format!("Radio diagnostics failed: {}", e))?;
+                return Ok(());
+            }
+        };
+        let temperature = match diag.temperature {
+            Some(t) => format!(", temperature {}ºC (uncalibrated)", t),
+            None => String::new(),
+        };
+        self.log.log(LogType::Info, &format!("Radio {}{}", diag.version, temperature))?;
+
+        if diag.device_errors != 0 {
+            self.log.log(
+                LogType::Warn,
+                &format!("Radio device errors 0x{:04x}", diag.device_errors),
+            )?;
+        }
+        if !diag.drift.is_empty() || diag.device_errors != 0 {
+            for d in &diag.drift {
+                self.log.log(
+                    LogType::Warn,
+                    &format!(
+                        "Radio register 0x{:02x} is 0x{:02x}, should be 0x{:02x}",
+                        d.register, d.actual, d.expected
+                    ),
+                )?;
+            }
+            match self.lora.recover() {
+                Ok(()) => self.log.log(LogType::Warn, "Radio reconfigured.")?,
+                Err(e) => self.log.log(LogType::Error, &format!("Can't reconfigure radio: {}", e))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    // listen for uplink packets during the configured window
+    pub fn listen_uplink(&mut self, conf: &Config) -> Result<(), io::Error> {
+        let profile = self.uplink_profile;
+        self.use_profile(profile)?;
+
+        let end = Instant::now() + Duration::from_secs(conf.uplink_window as u64);
+        while self.running() && Instant::now() < end {
+            match self.lora.available() {
+                Ok(true) => {
+                    if let Some(data) = self.lora.recv() {
+                        let mut link = match self.lora.last_packet_info() {
+                            Some(p) => format!(
+                                " (RSSI {}dBm, SNR {:.2}dB, freq. error {:.0}Hz)",
+                                p.rssi, p.snr, p.freq_error
+                            ),
+                            None => String::new(),
+                        };
+                        if let Some(h) = self.lora.last_header() {
+                            link.push_str(&format!(" from {} to {} id {}", h.from, h.to, h.id));
+                        }
+                        self.log.log(
+                            LogType::Info,
+                            &format!(
+                                "Uplink packet, {} bytes{}: {}",
+                                data.len(),
+                                link,
+                                String::from_utf8_lossy(&data)
+                            ),
+                        )?;
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    self.log.log(LogType::Warn, &format!("Can't listen for uplink: {}", e))?;
+                    break;
+                }
+            }
+            // hops must be followed quickly
+            let poll = if self.lora.hopping() { 1 } else { 50 };
+            thread::sleep(Duration::from_millis(poll));
+        }
+        self.radio_standby()?;
+
+        Ok(())
+    }
+
+    // ask the scheduler for the next task and run it,
+    // returns false if there was nothing to do
+    pub fn run_next(&mut self, conf: &Config) -> Result<bool, io::Error> {
+        let ssdv_to_queue = match self.ssdv {
+            Some(ref ssdv) => self.ssdv_next < ssdv.packets && !self.txq.is_full(TxClass::Ssdv),
+            None => false,
+        };
+        let cond = Conditions {
+            phase: self.state.phase,
+            vbatt: self.vbatt,
+            ssdv_pending: self.ssdv.is_some(),
+            ssdv_to_queue,
+        };
+        let task = self.scheduler.next(Instant::now(), &cond);
+        for e in self.scheduler.take_events() {
+            self.log.log(LogType::Info, &e)?;
+        }
+
+        match task {
+            Some(TaskKind::Telemetry) => {
+                self.update_telemetry(conf)?;
+                self.queue_telemetry()?;
+            }
+            Some(TaskKind::Sensors) => self.update_telemetry(conf)?,
+            Some(TaskKind::Datalog) => self.datalog.log(LogType::Clean, &self.telem.csv_string())?,
+            Some(TaskKind::Photo) => self.take_picture(conf)?,
+            Some(TaskKind::Ssdv) => self.queue_ssdv_packet()?,
+            Some(TaskKind::Uplink) => self.listen_uplink(conf)?,
+            Some(TaskKind::Status) => self.link_status()?,
+            Some(TaskKind::Beacon) => self.send_beacon()?,
+            None => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    // a packet on air is real progress, pet the watchdog
+    fn progress(&mut self) -> Result<(), io::Error> {
+        if let Err(e) = self.watchdog.pet() {
+            self.log.log(LogType::Warn, &format!("Can't pet watchdog: {}", e))?;
+        }
+        Ok(())
+    }
+
+    pub fn running(&self) -> bool {
+        !self.terminate.load(Ordering::Relaxed)
+    }
+
+    pub fn shutdown(&mut self) -> Result<(), io::Error> {
+        self.log.log(LogType::Info, "Termination requested, shutting down.")?;
+        if let Some(ref ssdv) = self.ssdv {
+            self.log.log(
+                LogType::Warn,
+                &format!("SSDV Image aborted at packet {} of {}.", self.ssdv_sent, ssdv.packets),
+            )?;
+        }
+        let dropped = self.txq.clear();
+        if dropped > 0 {
+            self.log.log(LogType::Warn, &format!("{} queued packets dropped.", dropped))?;
+        }
+
+        // let the packet on air finish and put the radio to sleep
+        if let Err(e) = self.lora.wait_packet_sent() {
+            self.log.log(LogType::Warn, &format!("Radio error: {}", e))?;
+        }
+        match self.lora.set_mode_sleep() {
+            Ok(()) => self.log.log(LogType::Info, "Radio in sleep mode.")?,
+            Err(e) => self.log.log(LogType::Warn, &format!("Radio error: {}", e))?,
+        }
+
+        // release GPIOs
+        if let Err(e) = self.led.deinit() {
+            self.log.log(LogType::Warn, &format!("Can't release LED GPIO: {}", e))?;
+        }
+        if let Err(e) = self.batt_en_pin.unexport() {
+            self.log.log(LogType::Warn, &format!("Can't release batt GPIO: {}", e))?;
+        }
+        if let Err(e) = self.pwr_pin.unexport() {
+            self.log.log(LogType::Warn, &format!("Can't release pwr GPIO: {}", e))?;
+        }
+
+        self.save_state()?;
+
+        // intentional stop, disarm the watchdogs
+        if let Err(e) = self.watchdog.stop() {
+            self.log.log(LogType::Warn, &format!("Can't stop watchdog: {}", e))?;
+        }
+
+        self.log.log(LogType::Info, "NSX stopped.")?;
         Ok(())
     }
 }
@@ -529,26 +1644,15 @@ fn main() {
     }
 
     ///////// MAIN LOOP /////////
-    loop {
-        // Telemetry
-        for _i in 0..config.packet_repeat {
-            // Check for commands
-
-            // Send telemetry
-            mission.update_telemetry(&config).unwrap();
-            mission.send_telemetry().unwrap();
-            
-            // write datalog
-            mission.datalog.log(LogType::Clean, &mission.telem.csv_string()).unwrap();
-
-            // Wait
-            thread::sleep(Duration::from_millis(config.packet_delay as u64 * 1000));
+    while mission.running() {
+        // run due tasks and send queued packets,
+        // or wait a bit if there's nothing to do
+        let ran = mission.run_next(&config).unwrap();
+        let sent = mission.service_radio(&config).unwrap();
+        if !ran && !sent {
+            thread::sleep(Duration::from_millis(100));
         }
-
-        // send SSDV
-        mission.send_ssdv(&config).unwrap();
-
-        // Wait
-        thread::sleep(Duration::from_millis(config.packet_delay as u64 * 1000));
     }
+
+    mission.shutdown().unwrap();
 }
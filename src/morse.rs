@@ -0,0 +1,102 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Morse code encoder for the CW beacon. Text is converted to key
+// down/up elements measured in dot units: dot 1, dash 3, gap between
+// elements 1, between characters 3 and between words 7.
+
+const DOT: u32 = 1;
+const DASH: u32 = 3;
+const ELEMENT_GAP: u32 = 1;
+const CHAR_GAP: u32 = 3;
+const WORD_GAP: u32 = 7;
+
+// ITU Morse code
+fn code(c: char) -> Option<&'static str> {
+    let code = match c.to_ascii_uppercase() {
+        'A' => ".-",
+        'B' => "-...",
+        'C' => "-.-.",
+        'D' => "-..",
+        'E' => ".",
+        'F' => "..-.",
+        'G' => "--.",
+        'H' => "....",
+        'I' => "..",
+        'J' => ".---",
+        'K' => "-.-",
+        'L' => ".-..",
+        'M' => "--",
+        'N' => "-.",
+        'O' => "---",
+        'P' => ".--.",
+        'Q' => "--.-",
+        'R' => ".-.",
+        'S' => "...",
+        'T' => "-",
+        'U' => "..-",
+        'V' => "...-",
+        'W' => ".--",
+        'X' => "-..-",
+        'Y' => "-.--",
+        'Z' => "--..",
+        '0' => "-----",
+        '1' => ".----",
+        '2' => "..---",
+        '3' => "...--",
+        '4' => "....-",
+        '5' => ".....",
+        '6' => "-....",
+        '7' => "--...",
+        '8' => "---..",
+        '9' => "----.",
+        '.' => ".-.-.-",
+        ',' => "--..--",
+        '?' => "..--..",
+        '/' => "-..-.",
+        '-' => "-....-",
+        '=' => "-...-",
+        _ => return None,
+    };
+    Some(code)
+}
+
+// (key down, units) elements of the text, characters without
+// Morse code are skipped
+pub fn encode(text: &str) -> Vec<(bool, u32)> {
+    let mut elements: Vec<(bool, u32)> = Vec::new();
+
+    for word in text.split_whitespace() {
+        if !elements.is_empty() {
+            elements.push((false, WORD_GAP));
+        }
+        let mut first = true;
+        for c in word.chars().filter_map(code) {
+            if !first {
+                elements.push((false, CHAR_GAP));
+            }
+            first = false;
+            for (i, e) in c.chars().enumerate() {
+                if i > 0 {
+                    elements.push((false, ELEMENT_GAP));
+                }
+                elements.push((true, if e == '.' { DOT } else { DASH }));
+            }
+        }
+    }
+
+    elements
+}
@@ -0,0 +1,1038 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Common interface of the LoRa radio drivers (RF95/SX1276 and SX1262)
+// and the types they share, so the mission doesn't depend on the chip.
+// What doesn't depend on the chip (modes accounting, RadioHead headers,
+// listen before talk, FSK symbols) is here too, the drivers only
+// implement their register or command layer.
+
+#![allow(dead_code)]
+
+use spidev::{Spidev, SpidevOptions, SpidevTransfer, SPI_MODE_0};
+use std::io;
+use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sysfs_gpio::{Direction, Pin};
+
+// RadioHead header: to, from, id, flags
+pub const RH_HEADER_LEN: usize = 4;
+// largest packet, including the RadioHead header
+pub const MAX_PACKET_LEN: usize = 255;
+// LoRa preamble symbols the drivers start with
+pub const DEFAULT_PREAMBLE_LEN: u16 = 8;
+pub const RH_BROADCAST_ADDRESS: u8 = 0xff;
+
+// symbol time above which low data rate optimisation is mandatory (s)
+const LOW_DATA_RATE_SYMBOL_TIME: f32 = 0.016;
+
+// channel changes are polled, hops closer than this can't be followed
+const MIN_HOP_TIME: f32 = 0.01;
+// the SX1276 hop channel counter is 6 bits
+const MAX_HOP_CHANNELS: usize = 64;
+
+// longest listen before talk backoff (ms), the TX waits must stay
+// well under the watchdog timeout
+const MAX_LBT_BACKOFF: u32 = 10000;
+// longest wait (ms) of all the listen before talk attempts
+const MAX_LBT_WAIT: u64 = 10000;
+
+// frequency step of the SX1276, the coarsest of the supported chips
+const MIN_TONE_SPACING: u32 = 61;
+
+// the tick of the blocking FSK and CW transmissions, well
+// under any watchdog timeout
+const TICK_PERIOD_MS: u64 = 1000;
+
+// Modes
+pub const RADIO_MODE_INITIALISING: u8 = 0;
+pub const RADIO_MODE_SLEEP: u8 = 1;
+pub const RADIO_MODE_IDLE: u8 = 2;
+pub const RADIO_MODE_TX: u8 = 3;
+pub const RADIO_MODE_RX: u8 = 4;
+pub const RADIO_MODE_CAD: u8 = 5;
+
+// SPI bus of a radio chip, a Spidev on the hardware
+pub trait SpiBus {
+    // mode 0, 8 bits per word
+    fn setup(&mut self, speed_hz: u32) -> io::Result<()>;
+    fn write_bytes(&mut self, data: &[u8]) -> io::Result<()>;
+    // full duplex, rx as long as tx
+    fn transfer_bytes(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()>;
+}
+
+impl SpiBus for Spidev {
+    fn setup(&mut self, speed_hz: u32) -> io::Result<()> {
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(speed_hz)
+            .mode(SPI_MODE_0)
+            .build();
+        self.configure(&options)
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn transfer_bytes(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let mut transfer = SpidevTransfer::read_write(tx, rx);
+        self.transfer(&mut transfer)
+    }
+}
+
+// input line of a radio chip (SX1262 BUSY), a sysfs GPIO on the hardware
+pub trait InputPin {
+    // export it as an input
+    fn setup_input(&self) -> Result<(), &'static str>;
+    fn is_high(&self) -> bool;
+}
+
+impl InputPin for Pin {
+    fn setup_input(&self) -> Result<(), &'static str> {
+        self.export().map_err(|_| "Can't export input pin")?;
+        self.set_direction(Direction::In).map_err(|_| "Can't set input pin direction")
+    }
+
+    fn is_high(&self) -> bool {
+        self.get_value().unwrap_or(0) != 0
+    }
+}
+
+pub trait Radio {
+    // chip independent state of the driver
+    fn state(&self) -> &RadioState;
+    fn state_mut(&mut self) -> &mut RadioState;
+
+    // set up the SPI bus and the chip in LoRa mode
+    fn init(&mut self) -> Result<(), &'static str>;
+    // SPI clock in Hz, applied in init()
+    fn set_spi_speed(&mut self, hz: u32);
+
+    // MHz
+    fn set_frequency(&mut self, freq: f32) -> Result<(), &'static str>;
+    fn frequency(&self) -> f32;
+    // dBm
+    fn set_tx_power(&mut self, power: u8) -> Result<(), &'static str>;
+    fn set_modem_config(&mut self, config: &ModemConfig) -> Result<(), &'static str>;
+    fn modem_config(&self) -> ModemConfig;
+    // change frequency, modem and power at once, waiting
+    // for the packet being sent
+    fn set_profile(&mut self, profile: &RadioProfile) -> Result<(), &'static str>;
+    // time a packet of len bytes (with headers) occupies the channel
+    fn time_on_air(&self, len: usize) -> Duration;
+
+    fn set_mode_idle(&mut self) -> Result<(), &'static str>;
+    fn set_mode_sleep(&mut self) -> Result<(), &'static str>;
+
+    // sleep instead of standby after each transmission
+    fn set_sleep_after_tx(&mut self, enabled: bool) {
+        self.state_mut().sleep_after_tx = enabled;
+    }
+
+    // start sending a packet, false if it's too long
+    fn send(&mut self, data: &[u8]) -> Result<bool, &'static str>;
+    // wait for the packet being sent, false if there wasn't one
+    fn wait_packet_sent(&mut self) -> Result<bool, &'static str>;
+    // listen, true if a packet has been received
+    fn available(&mut self) -> Result<bool, &'static str>;
+
+    // the received packet, once
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let state = self.state_mut();
+        if !state.rx_buf_valid {
+            return None;
+        }
+
+        let data = state.buf[..state.buflen as usize].to_vec();
+        state.clear_rx_buf();
+        Some(data)
+    }
+
+    // metadata of the last received packet
+    fn last_packet_info(&self) -> Option<PacketInfo> {
+        self.state().last_packet
+    }
+
+    // RadioHead compatible headers on send and receive
+    fn set_radiohead_headers(&mut self, enabled: bool) {
+        self.state_mut().rh_headers = enabled;
+    }
+
+    // our node address, used as the "from" of sent packets and
+    // to filter the received ones
+    fn set_this_address(&mut self, address: u8) {
+        let state = self.state_mut();
+        state.this_address = address;
+        state.tx_header.from = address;
+    }
+
+    // destination of the sent packets, RH_BROADCAST_ADDRESS for everyone
+    fn set_header_to(&mut self, address: u8) {
+        self.state_mut().tx_header.to = address;
+    }
+
+    // accept packets addressed to any node
+    fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.state_mut().promiscuous = promiscuous;
+    }
+
+    // header of the last received packet
+    fn last_header(&self) -> Option<RadioHeadHeader> {
+        self.state().rx_header
+    }
+
+    // bytes added to each packet
+    fn header_len(&self) -> usize {
+        self.state().header_len()
+    }
+
+    // run a channel activity detection, true if a LoRa preamble was seen
+    fn channel_activity(&mut self) -> Result<bool, &'static str>;
+
+    // enable or disable listen before talk in send()
+    fn set_listen_before_talk(&mut self, lbt: Option<ListenBeforeTalk>) {
+        self.state_mut().lbt = lbt;
+    }
+
+    // wait until the channel is free, Err if it's still busy after all the attempts
+    fn listen_before_talk(&mut self, lbt: &ListenBeforeTalk) -> Result<(), &'static str> {
+        let attempts = lbt.attempts.max(1);
+        for attempt in 1..=attempts {
+            if !self.channel_activity()? {
+                return Ok(());
+            }
+            let state = self.state_mut();
+            state.stats.channel_busy += 1;
+            // no point in waiting after the last one
+            if attempt < attempts {
+                let backoff = lbt.backoff(state.random());
+                thread::sleep(Duration::from_millis(backoff as u64));
+            }
+        }
+
+        Err("Channel busy")
+    }
+
+    fn set_hop_table(&mut self, table: FrequencyHopping) -> Result<(), &'static str>;
+    fn hopping(&self) -> bool;
+
+    // blocking transmission of a tone (frequency + offset Hz) for each
+    // symbol, with an optional lead tone (symbol, time) before them.
+    // The radio goes back to LoRa with its configuration afterwards.
+    // tick is called about every second while sending.
+    fn send_tones(
+        &mut self,
+        frequency: f32,
+        offsets: &[f64],
+        lead: Option<(usize, Duration)>,
+        symbols: &[usize],
+        symbol_time: Duration,
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str>;
+
+    // send text as RTTY, blocks until it's sent
+    fn send_rtty(
+        &mut self,
+        rtty: &RttyConfig,
+        text: &[u8],
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str> {
+        rtty.validate()?;
+
+        // space, mark
+        let shift = rtty.shift as f64 / 2.0;
+        let symbols: Vec<usize> = rtty.encode(text).into_iter().map(|b| b as usize).collect();
+        let preamble = Duration::from_millis(rtty.preamble as u64);
+        self.send_tones(
+            rtty.frequency,
+            &[-shift, shift],
+            Some((1, preamble)),
+            &symbols,
+            rtty.bit_time(),
+            tick,
+        )
+    }
+
+    // send data as 4FSK, blocks until it's sent
+    fn send_4fsk(
+        &mut self,
+        fsk: &FourFskConfig,
+        data: &[u8],
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str> {
+        fsk.validate()?;
+
+        let spacing = fsk.tone_spacing as f64;
+        self.send_tones(
+            fsk.frequency,
+            &[0.0, spacing, 2.0 * spacing, 3.0 * spacing],
+            None,
+            &fsk.encode(data),
+            fsk.symbol_time(),
+            tick,
+        )
+    }
+
+    // send Morse code elements (key down, units) as an unmodulated
+    // carrier. Blocks until it's sent and goes back to LoRa mode,
+    // calling tick about every second.
+    fn send_cw(
+        &mut self,
+        cw: &CwConfig,
+        elements: &[(bool, u32)],
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str>;
+
+    // snapshot of the link counters
+    fn stats(&self) -> LinkStats {
+        self.state().stats()
+    }
+
+    fn diagnose(&mut self) -> Result<Diagnostics, &'static str>;
+    // re-initialise the chip after a failure, keeping its configuration
+    fn recover(&mut self) -> Result<(), &'static str>;
+}
+
+// modes, link counters, receive buffer and RadioHead headers, the part
+// of a driver that doesn't depend on the chip
+pub struct RadioState {
+    pub mode: u8,
+    mode_start: Instant,
+    pub sleep_after_tx: bool,
+    pub buf: [u8; 256],
+    pub buflen: u8,
+    pub last_packet: Option<PacketInfo>,
+    pub stats: LinkStats,
+    pub lbt: Option<ListenBeforeTalk>,
+    backoff_seed: u32,
+    pub rh_headers: bool,
+    pub this_address: u8,
+    pub promiscuous: bool,
+    pub tx_header: RadioHeadHeader,
+    pub rx_header: Option<RadioHeadHeader>,
+    pub rx_buf_valid: bool,
+}
+
+impl RadioState {
+    pub fn new() -> Self {
+        Self {
+            mode: RADIO_MODE_INITIALISING,
+            mode_start: Instant::now(),
+            sleep_after_tx: false,
+            buf: [0; 256],
+            buflen: 0,
+            last_packet: None,
+            stats: LinkStats::default(),
+            lbt: None,
+            backoff_seed: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0)
+                | 1,
+            rh_headers: false,
+            this_address: RH_BROADCAST_ADDRESS,
+            promiscuous: false,
+            tx_header: RadioHeadHeader {
+                to: RH_BROADCAST_ADDRESS,
+                from: RH_BROADCAST_ADDRESS,
+                id: 0,
+                flags: 0,
+            },
+            rx_header: None,
+            rx_buf_valid: false,
+        }
+    }
+
+    // change the mode, accounting the time spent in the previous one
+    pub fn enter_mode(&mut self, mode: u8) {
+        let now = Instant::now();
+        let prev = self.mode;
+        add_mode_time(&mut self.stats, prev, now - self.mode_start);
+        self.mode_start = now;
+        self.mode = mode;
+    }
+
+    // counters including the current mode until now
+    pub fn stats(&self) -> LinkStats {
+        let mut stats = self.stats.clone();
+        add_mode_time(&mut stats, self.mode, self.mode_start.elapsed());
+        stats
+    }
+
+    // xorshift, good enough for backoff times
+    pub fn random(&mut self) -> u32 {
+        let mut x = self.backoff_seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.backoff_seed = x;
+        x
+    }
+
+    // bytes added to each packet
+    pub fn header_len(&self) -> usize {
+        if self.rh_headers {
+            RH_HEADER_LEN
+        } else {
+            0
+        }
+    }
+
+    // header, if used, and data of the next packet
+    pub fn packet(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut packet: Vec<u8> = Vec::with_capacity(data.len() + RH_HEADER_LEN);
+        if self.rh_headers {
+            let h = self.tx_header;
+            packet.extend_from_slice(&[h.to, h.from, h.id, h.flags]);
+            self.tx_header.id = self.tx_header.id.wrapping_add(1);
+        }
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    // check the RadioHead header, if used, and remove it from the buffer
+    pub fn validate_rx_buf(&mut self) {
+        if !self.rh_headers {
+            self.rx_header = None;
+            self.rx_buf_valid = true;
+            return;
+        }
+
+        // too short to be a real message
+        if (self.buflen as usize) < RH_HEADER_LEN {
+            return;
+        }
+
+        let header = RadioHeadHeader {
+            to: self.buf[0],
+            from: self.buf[1],
+            id: self.buf[2],
+            flags: self.buf[3],
+        };
+        if self.promiscuous
+            || header.to == self.this_address
+            || header.to == RH_BROADCAST_ADDRESS
+        {
+            self.buf.copy_within(RH_HEADER_LEN..self.buflen as usize, 0);
+            self.buflen -= RH_HEADER_LEN as u8;
+            self.rx_header = Some(header);
+            self.rx_buf_valid = true;
+        }
+    }
+
+    pub fn clear_rx_buf(&mut self) {
+        self.rx_buf_valid = false;
+        self.buflen = 0;
+    }
+}
+
+fn add_mode_time(stats: &mut LinkStats, mode: u8, t: Duration) {
+    match mode {
+        RADIO_MODE_SLEEP => stats.sleep_time += t,
+        RADIO_MODE_TX => stats.tx_time += t,
+        RADIO_MODE_RX | RADIO_MODE_CAD => stats.rx_time += t,
+        _ => stats.idle_time += t,
+    }
+}
+
+pub fn sleep_until(t: Instant) {
+    let now = Instant::now();
+    if t > now {
+        thread::sleep(t - now);
+    }
+}
+
+// calls a function once per tick period at most, so the blocking
+// transmissions can pet the watchdog
+pub struct Ticker<'a> {
+    tick: &'a mut dyn FnMut(),
+    last: Instant,
+}
+
+impl<'a> Ticker<'a> {
+    pub fn new(tick: &'a mut dyn FnMut()) -> Self {
+        Self {
+            tick,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn poll(&mut self) {
+        if self.last.elapsed() >= Duration::from_millis(TICK_PERIOD_MS) {
+            (self.tick)();
+            self.last = Instant::now();
+        }
+    }
+}
+
+// LoRa modem parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bandwidth {
+    Bw7_8,
+    Bw10_4,
+    Bw15_6,
+    Bw20_8,
+    Bw31_25,
+    Bw41_7,
+    Bw62_5,
+    Bw125,
+    Bw250,
+    Bw500,
+}
+
+impl Bandwidth {
+    pub fn from_hz(hz: u32) -> Option<Self> {
+        match hz {
+            7800 => Some(Bandwidth::Bw7_8),
+            10400 => Some(Bandwidth::Bw10_4),
+            15600 => Some(Bandwidth::Bw15_6),
+            20800 => Some(Bandwidth::Bw20_8),
+            31250 => Some(Bandwidth::Bw31_25),
+            41700 => Some(Bandwidth::Bw41_7),
+            62500 => Some(Bandwidth::Bw62_5),
+            125000 => Some(Bandwidth::Bw125),
+            250000 => Some(Bandwidth::Bw250),
+            500000 => Some(Bandwidth::Bw500),
+            _ => None,
+        }
+    }
+
+    pub fn hz(self) -> f32 {
+        match self {
+            Bandwidth::Bw7_8 => 7800.0,
+            Bandwidth::Bw10_4 => 10400.0,
+            Bandwidth::Bw15_6 => 15600.0,
+            Bandwidth::Bw20_8 => 20800.0,
+            Bandwidth::Bw31_25 => 31250.0,
+            Bandwidth::Bw41_7 => 41700.0,
+            Bandwidth::Bw62_5 => 62500.0,
+            Bandwidth::Bw125 => 125000.0,
+            Bandwidth::Bw250 => 250000.0,
+            Bandwidth::Bw500 => 500000.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpreadingFactor {
+    Sf6,
+    Sf7,
+    Sf8,
+    Sf9,
+    Sf10,
+    Sf11,
+    Sf12,
+}
+
+impl SpreadingFactor {
+    pub fn from_value(sf: u8) -> Option<Self> {
+        match sf {
+            6 => Some(SpreadingFactor::Sf6),
+            7 => Some(SpreadingFactor::Sf7),
+            8 => Some(SpreadingFactor::Sf8),
+            9 => Some(SpreadingFactor::Sf9),
+            10 => Some(SpreadingFactor::Sf10),
+            11 => Some(SpreadingFactor::Sf11),
+            12 => Some(SpreadingFactor::Sf12),
+            _ => None,
+        }
+    }
+
+    pub fn value(self) -> u8 {
+        match self {
+            SpreadingFactor::Sf6 => 6,
+            SpreadingFactor::Sf7 => 7,
+            SpreadingFactor::Sf8 => 8,
+            SpreadingFactor::Sf9 => 9,
+            SpreadingFactor::Sf10 => 10,
+            SpreadingFactor::Sf11 => 11,
+            SpreadingFactor::Sf12 => 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodingRate {
+    Cr4_5,
+    Cr4_6,
+    Cr4_7,
+    Cr4_8,
+}
+
+impl CodingRate {
+    // from the denominator, 5 for 4/5...
+    pub fn from_value(cr: u8) -> Option<Self> {
+        match cr {
+            5 => Some(CodingRate::Cr4_5),
+            6 => Some(CodingRate::Cr4_6),
+            7 => Some(CodingRate::Cr4_7),
+            8 => Some(CodingRate::Cr4_8),
+            _ => None,
+        }
+    }
+
+    pub fn value(self) -> u8 {
+        match self {
+            CodingRate::Cr4_5 => 5,
+            CodingRate::Cr4_6 => 6,
+            CodingRate::Cr4_7 => 7,
+            CodingRate::Cr4_8 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModemConfig {
+    pub bandwidth: Bandwidth,
+    pub spreading_factor: SpreadingFactor,
+    pub coding_rate: CodingRate,
+    pub implicit_header: bool,
+    pub crc: bool,
+    pub agc_auto: bool,
+}
+
+impl ModemConfig {
+    // symbol time in seconds
+    pub fn symbol_time(&self) -> f32 {
+        (1u32 << self.spreading_factor.value()) as f32 / self.bandwidth.hz()
+    }
+
+    // mandatory when the symbol time is longer than 16ms
+    pub fn low_data_rate_optimize(&self) -> bool {
+        self.symbol_time() > LOW_DATA_RATE_SYMBOL_TIME
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.spreading_factor == SpreadingFactor::Sf6 && !self.implicit_header {
+            return Err("SF6 needs implicit header mode");
+        }
+        Ok(())
+    }
+
+    // preset values, changing the parameters that are not 0
+    pub fn with_overrides(mut self, bw: u32, sf: u8, cr: u8) -> Result<Self, &'static str> {
+        if bw != 0 {
+            self.bandwidth = Bandwidth::from_hz(bw).ok_or("Invalid LoRa bandwidth")?;
+        }
+        if sf != 0 {
+            self.spreading_factor = SpreadingFactor::from_value(sf).ok_or("Invalid LoRa spreading factor")?;
+        }
+        if cr != 0 {
+            self.coding_rate = CodingRate::from_value(cr).ok_or("Invalid LoRa coding rate")?;
+        }
+        Ok(self)
+    }
+
+    // time a packet of len bytes occupies the channel
+    // (SX1276 datasheet 4.1.1.7, SX1261/2 datasheet 6.1.4)
+    pub fn time_on_air(&self, preamble_len: u16, len: usize) -> Duration {
+        let sf = self.spreading_factor.value() as f32;
+        let cr = (self.coding_rate.value() - 4) as f32;
+        let implicit = if self.implicit_header { 1.0 } else { 0.0 };
+        let crc = if self.crc { 1.0 } else { 0.0 };
+        let ldro = if self.low_data_rate_optimize() { 1.0 } else { 0.0 };
+
+        let t_sym = self.symbol_time();
+        let t_preamble = (preamble_len as f32 + 4.25) * t_sym;
+        let payload_bits = 8.0 * len as f32 - 4.0 * sf + 28.0 + 16.0 * crc - 20.0 * implicit;
+        let payload_symbols =
+            8.0 + ((payload_bits / (4.0 * (sf - 2.0 * ldro))).ceil() * (cr + 4.0)).max(0.0);
+        let t_packet = t_preamble + payload_symbols * t_sym;
+
+        Duration::from_micros((t_packet * 1e6) as u64)
+    }
+}
+
+// RadioHead modem presets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModemPreset {
+    Bw125Cr45Sf128,
+    Bw500Cr45Sf128,
+    Bw31_25Cr48Sf512,
+    Bw125Cr48Sf4096,
+}
+
+impl ModemPreset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Bw125Cr45Sf128" => Some(ModemPreset::Bw125Cr45Sf128),
+            "Bw500Cr45Sf128" => Some(ModemPreset::Bw500Cr45Sf128),
+            "Bw31_25Cr48Sf512" => Some(ModemPreset::Bw31_25Cr48Sf512),
+            "Bw125Cr48Sf4096" => Some(ModemPreset::Bw125Cr48Sf4096),
+            _ => None,
+        }
+    }
+
+    pub fn config(self) -> ModemConfig {
+        let (bandwidth, coding_rate, spreading_factor) = match self {
+            ModemPreset::Bw125Cr45Sf128 => (Bandwidth::Bw125, CodingRate::Cr4_5, SpreadingFactor::Sf7),
+            ModemPreset::Bw500Cr45Sf128 => (Bandwidth::Bw500, CodingRate::Cr4_5, SpreadingFactor::Sf7),
+            ModemPreset::Bw31_25Cr48Sf512 => (Bandwidth::Bw31_25, CodingRate::Cr4_8, SpreadingFactor::Sf9),
+            ModemPreset::Bw125Cr48Sf4096 => (Bandwidth::Bw125, CodingRate::Cr4_8, SpreadingFactor::Sf12),
+        };
+        ModemConfig {
+            bandwidth,
+            spreading_factor,
+            coding_rate,
+            implicit_header: false,
+            crc: true,
+            agc_auto: false,
+        }
+    }
+}
+
+// metadata of a received packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacketInfo {
+    // dBm
+    pub rssi: i16,
+    // dB
+    pub snr: f32,
+    // estimated frequency error, Hz
+    pub freq_error: f32,
+}
+
+// frequency, modem and power used for a kind of traffic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RadioProfile {
+    pub name: &'static str,
+    // MHz
+    pub frequency: f32,
+    pub modem: ModemConfig,
+    // dBm
+    pub power: u8,
+    // use the frequency hopping table instead of the frequency
+    pub hopping: bool,
+}
+
+// LoRa frequency hopping. The radio changes to the next channel of the
+// table every period symbols during TX and RX. Packets start on the
+// first channel, the receiver must use the same table and period.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrequencyHopping {
+    // MHz
+    pub channels: Vec<f32>,
+    // symbols between hops
+    pub period: u8,
+}
+
+impl FrequencyHopping {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.channels.is_empty() || self.channels.len() > MAX_HOP_CHANNELS {
+            return Err("Hopping needs 1 to 64 channels");
+        }
+        if self.period == 0 {
+            return Err("Invalid hop period");
+        }
+        Ok(())
+    }
+
+    // the hops must be slow enough to be followed with this modem
+    pub fn check_period(&self, modem: &ModemConfig) -> Result<(), &'static str> {
+        if self.period as f32 * modem.symbol_time() < MIN_HOP_TIME {
+            return Err("Hop period too short");
+        }
+        Ok(())
+    }
+}
+
+// RadioHead packet header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RadioHeadHeader {
+    pub to: u8,
+    pub from: u8,
+    pub id: u8,
+    pub flags: u8,
+}
+
+// RTTY, sent in FSK mode moving the carrier between
+// the mark (carrier + shift/2) and space (carrier - shift/2) frequencies
+#[derive(Debug, Clone, Copy)]
+pub struct RttyConfig {
+    // carrier frequency, MHz
+    pub frequency: f32,
+    // 50, 100 or 300
+    pub baud: u32,
+    // Hz
+    pub shift: u32,
+    // data bits, 7 or 8
+    pub bits: u8,
+    pub stop_bits: u8,
+    // mark tone before the data so the decoder can lock, ms
+    pub preamble: u32,
+}
+
+impl RttyConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self.baud {
+            50 | 100 | 300 => {}
+            _ => return Err("RTTY baud rate must be 50, 100 or 300"),
+        }
+        if self.bits != 7 && self.bits != 8 {
+            return Err("RTTY data bits must be 7 or 8");
+        }
+        if self.stop_bits != 1 && self.stop_bits != 2 {
+            return Err("RTTY stop bits must be 1 or 2");
+        }
+        Ok(())
+    }
+
+    pub fn bit_time(&self) -> Duration {
+        Duration::from_micros(1000000 / self.baud as u64)
+    }
+
+    // bits of the text, true for mark. Each character is a start bit (space),
+    // the data bits, LSB first, and the stop bits (mark).
+    pub fn encode(&self, text: &[u8]) -> Vec<bool> {
+        let mut bits = Vec::with_capacity(text.len() * (self.bits + self.stop_bits + 1) as usize);
+        for c in text {
+            bits.push(false);
+            for i in 0..self.bits {
+                bits.push(c & (1 << i) != 0);
+            }
+            let len = bits.len() + self.stop_bits as usize;
+            bits.resize(len, true);
+        }
+        bits
+    }
+
+    // total transmission time of the text, including the preamble
+    pub fn time_on_air(&self, len: usize) -> Duration {
+        let bits = (len * (self.bits + self.stop_bits + 1) as usize) as u32;
+        Duration::from_millis(self.preamble as u64) + self.bit_time() * bits
+    }
+}
+
+// 4FSK, tones at frequency + n * tone_spacing for symbols n = 0-3
+#[derive(Debug, Clone, Copy)]
+pub struct FourFskConfig {
+    // lowest tone, MHz
+    pub frequency: f32,
+    pub baud: u32,
+    // Hz
+    pub tone_spacing: u32,
+}
+
+impl FourFskConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.baud == 0 || self.baud > 1000 {
+            return Err("4FSK baud rate must be 1-1000");
+        }
+        if self.tone_spacing < MIN_TONE_SPACING {
+            return Err("4FSK tone spacing must be at least 61Hz");
+        }
+        Ok(())
+    }
+
+    pub fn symbol_time(&self) -> Duration {
+        Duration::from_micros(1000000 / self.baud as u64)
+    }
+
+    // symbols of the data, 2 bits each, MSB first
+    pub fn encode(&self, data: &[u8]) -> Vec<usize> {
+        let mut symbols = Vec::with_capacity(data.len() * 4);
+        for b in data {
+            for i in 0..4 {
+                symbols.push(((b >> (6 - 2 * i)) & 0x03) as usize);
+            }
+        }
+        symbols
+    }
+
+    // 4 symbols per byte
+    pub fn time_on_air(&self, len: usize) -> Duration {
+        self.symbol_time() * (len * 4) as u32
+    }
+}
+
+// CW (Morse) beacon
+#[derive(Debug, Clone, Copy)]
+pub struct CwConfig {
+    // MHz
+    pub frequency: f32,
+    // words per minute
+    pub wpm: u32,
+}
+
+impl CwConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.wpm < 5 || self.wpm > 40 {
+            return Err("CW speed must be 5-40 WPM");
+        }
+        Ok(())
+    }
+
+    // dot time, PARIS standard
+    pub fn unit(&self) -> Duration {
+        Duration::from_millis(1200 / self.wpm as u64)
+    }
+
+    pub fn time_on_air(&self, elements: &[(bool, u32)]) -> Duration {
+        let units: u32 = elements.iter().filter(|e| e.0).map(|e| e.1).sum();
+        self.unit() * units
+    }
+}
+
+// listen before talk policy: check the channel before sending and,
+// if it's busy, wait a random backoff time and try again
+#[derive(Debug, Clone, Copy)]
+pub struct ListenBeforeTalk {
+    pub attempts: u8,
+    // ms
+    pub min_backoff: u32,
+    pub max_backoff: u32,
+}
+
+impl ListenBeforeTalk {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.attempts == 0 {
+            return Err("LBT attempts must be at least 1");
+        }
+        if self.min_backoff > self.max_backoff {
+            return Err("LBT min backoff must not exceed the max backoff");
+        }
+        if self.max_backoff > MAX_LBT_BACKOFF {
+            return Err("LBT max backoff must be up to 10000ms");
+        }
+        if self.max_wait() > Duration::from_millis(MAX_LBT_WAIT) {
+            return Err("LBT attempts x max backoff must be up to 10000ms");
+        }
+        Ok(())
+    }
+
+    // longest time the backoffs can take, there is none after the last attempt
+    pub fn max_wait(&self) -> Duration {
+        let waits = self.attempts.max(1) as u64 - 1;
+        Duration::from_millis(waits * self.max_backoff as u64)
+    }
+
+    // backoff time (ms) in the range for a random number
+    pub fn backoff(&self, random: u32) -> u32 {
+        match self.max_backoff.saturating_sub(self.min_backoff).checked_add(1) {
+            Some(range) => self.min_backoff + random % range,
+            // 0 to u32::MAX
+            None => random,
+        }
+    }
+}
+
+// register values read from the chip, (address, value)
+#[derive(Debug, Clone)]
+pub struct RegisterSnapshot {
+    pub registers: Vec<(u16, u8)>,
+}
+
+// register that doesn't have the configured value
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterDrift {
+    pub register: u16,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    // chip and version
+    pub version: String,
+    // uncalibrated, ºC, if the chip can measure it
+    pub temperature: Option<i16>,
+    // error flags reported by the chip (cleared after reading), 0 if none
+    pub device_errors: u16,
+    pub snapshot: RegisterSnapshot,
+    pub drift: Vec<RegisterDrift>,
+}
+
+// radio link counters
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    pub tx_packets: u32,
+    pub tx_bytes: u64,
+    pub tx_airtime: Duration,
+    pub rx_packets: u32,
+    pub rx_bytes: u64,
+    pub rx_crc_errors: u32,
+    // too short for a header or addressed to another node
+    pub rx_dropped: u32,
+    pub tx_timeouts: u32,
+    pub resets: u32,
+    pub channel_busy: u32,
+    // time spent moving packets to and from the FIFO
+    pub fifo_time: Duration,
+    // time spent in each mode, for power accounting
+    pub sleep_time: Duration,
+    pub idle_time: Duration,
+    pub tx_time: Duration,
+    pub rx_time: Duration,
+    pub last_packet: Option<PacketInfo>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lbt(min_backoff: u32, max_backoff: u32) -> ListenBeforeTalk {
+        ListenBeforeTalk {
+            attempts: 5,
+            min_backoff,
+            max_backoff,
+        }
+    }
+
+    #[test]
+    fn lbt_backoff_range() {
+        let l = lbt(50, 500);
+        for r in &[0, 1, 450, 451, 12345, u32::MAX] {
+            let b = l.backoff(*r);
+            assert!((50..=500).contains(&b), "{} -> {}", r, b);
+        }
+        assert_eq!(lbt(100, 100).backoff(u32::MAX), 100);
+    }
+
+    #[test]
+    fn lbt_backoff_no_overflow() {
+        assert_eq!(lbt(0, u32::MAX).backoff(u32::MAX), u32::MAX);
+        assert_eq!(lbt(1, u32::MAX).backoff(u32::MAX - 1), u32::MAX);
+        // inverted range, the min backoff
+        assert_eq!(lbt(500, 50).backoff(7), 500);
+    }
+
+    #[test]
+    fn lbt_validate() {
+        assert!(lbt(50, 500).validate().is_ok());
+        assert!(lbt(500, 50).validate().is_err());
+        assert!(lbt(0, u32::MAX).validate().is_err());
+        let mut l = lbt(50, 500);
+        l.attempts = 0;
+        assert!(l.validate().is_err());
+        // 4 backoffs of up to 2.5s fit, 5 don't
+        let mut l = lbt(50, 2500);
+        assert!(l.validate().is_ok());
+        l.attempts = 6;
+        assert!(l.validate().is_err());
+    }
+
+    #[test]
+    fn lbt_max_wait() {
+        assert_eq!(lbt(50, 500).max_wait(), Duration::from_millis(2000));
+        let mut l = lbt(50, 500);
+        l.attempts = 1;
+        assert_eq!(l.max_wait(), Duration::from_millis(0));
+        l.attempts = 0;
+        assert_eq!(l.max_wait(), Duration::from_millis(0));
+    }
+}
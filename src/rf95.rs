@@ -22,14 +22,19 @@
 extern crate spidev;
 extern crate sysfs_gpio;
 
-use spidev::{Spidev, SpidevOptions, SpidevTransfer, SPI_MODE_0};
-use std::io::prelude::*;
+use spidev::Spidev;
 use std::{thread, time};
 use sysfs_gpio::{Direction, Pin};
 
+use radio::*;
+
 const FXOSC: f32 = 32000000.0;
 const FSTEP: f32 = FXOSC / 524288.0;
 
+// SPI clock (Hz), the SX1276 supports up to 10MHz
+const DEFAULT_SPI_SPEED: u32 = 1000000;
+const MAX_SPI_SPEED: u32 = 10000000;
+
 // Register names (LoRa Mode, from table 85)
 const REG_00_FIFO: u8 = 0x00;
 const REG_01_OP_MODE: u8 = 0x01;
@@ -71,6 +76,8 @@ const REG_24_HOP_PERIOD: u8 = 0x24;
 const REG_25_FIFO_RX_BYTE_ADDR: u8 = 0x25;
 const REG_26_MODEM_CONFIG3: u8 = 0x26;
 const REG_28_FREQ_ERROR: u8 = 0x28;
+const REG_29_FREQ_ERROR_MID: u8 = 0x29;
+const REG_2A_FREQ_ERROR_LSB: u8 = 0x2a;
 const REG_31_DETECT_OPT: u8 = 0x31;
 const REG_37_DETECTION_THRESHOLD: u8 = 0x37;
 
@@ -78,6 +85,12 @@ const REG_40_DIO_MAPPING1: u8 = 0x40;
 const REG_41_DIO_MAPPING2: u8 = 0x41;
 const REG_42_VERSION: u8 = 0x42;
 
+// FSK mode registers, used to read the temperature and for RTTY
+const REG_04_FDEV_MSB: u8 = 0x04;
+const REG_05_FDEV_LSB: u8 = 0x05;
+const REG_31_PACKET_CONFIG2: u8 = 0x31;
+const REG_3B_IMAGE_CAL: u8 = 0x3b;
+
 const REG_4B_TCXO: u8 = 0x4b;
 const REG_4D_PA_DAC: u8 = 0x4d;
 const REG_5B_FORMER_TEMP: u8 = 0x5b;
@@ -184,8 +197,8 @@ const CODING_RATE_4_6: u8 = 0x04;
 const CODING_RATE_4_7: u8 = 0x06;
 const CODING_RATE_4_8: u8 = 0x08;
 
-const IMPLICIT_HEADER_MODE_ON: u8 = 0x00;
-const IMPLICIT_HEADER_MODE_OFF: u8 = 0x01;
+const IMPLICIT_HEADER_MODE_ON: u8 = 0x01;
+const IMPLICIT_HEADER_MODE_OFF: u8 = 0x00;
 
 // REG_1E_MODEM_CONFIG2                       0x1e;
 const SPREADING_FACTOR_64CPS: u8 = 0x60;
@@ -197,96 +210,173 @@ const SPREADING_FACTOR_2048CPS: u8 = 0xb0;
 const SPREADING_FACTOR_4096CPS: u8 = 0xc0;
 const TX_CONTINUOUS_MODE_ON: u8 = 0x08;
 const TX_CONTINUOUS_MODE_OFF: u8 = 0x00;
-const RX_PAYLOAD_CRC_ON: u8 = 0x02;
+const RX_PAYLOAD_CRC_ON: u8 = 0x04;
 const RX_PAYLOAD_CRC_OFF: u8 = 0x00;
 const SYM_TIMEOUT_MSB: u8 = 0x03;
 
 // REG_26_MODEM_CONFIG3;
+const LOW_DATA_RATE_OPTIMIZE: u8 = 0x08;
 const AGC_AUTO_ON: u8 = 0x04;
 const AGC_AUTO_OFF: u8 = 0x00;
 
+// REG_3B_IMAGE_CAL (FSK mode)                0x3b;
+const IMAGE_CAL_START: u8 = 0x40;
+const IMAGE_CAL_RUNNING: u8 = 0x20;
+
+// REG_31_PACKET_CONFIG2 (FSK mode)           0x31;
+const DATA_MODE_CONTINUOUS: u8 = 0x00;
+
+// REG_42_VERSION                             0x42;
+const SX1276_VERSION: u8 = 0x12;
+
 // REG_4D_PA_DAC                              0x4d;
 const PA_DAC_DISABLE: u8 = 0x04;
 const PA_DAC_ENABLE: u8 = 0x07;
 
 const MAX_MESSAGE_LEN: u8 = 255;
 
-// default params;
-const BW125_CR45_SF128: (u8, u8, u8) = (0x72, 0x74, 0x00);
-const BW500_CR45_SF128: (u8, u8, u8) = (0x92, 0x74, 0x00);
-const BW31_25_CR48_SF512: (u8, u8, u8) = (0x48, 0x94, 0x00);
-const BW125_CR48_SF4096: (u8, u8, u8) = (0x78, 0xc4, 0x00);
+// a transmission fails if it takes longer than
+// TX_TIMEOUT_FACTOR times its time on air plus TX_TIMEOUT_MARGIN ms
+const TX_TIMEOUT_FACTOR: u32 = 2;
+const TX_TIMEOUT_MARGIN: u64 = 500;
+
+// a channel activity detection takes about 2 symbols,
+// we wait CAD_TIMEOUT_SYMBOLS plus TX_TIMEOUT_MARGIN ms
+const CAD_TIMEOUT_SYMBOLS: f32 = 8.0;
+
+// RSSI offsets of the high (>779MHz) and low frequency ports, datasheet 5.5.5
+const HF_PORT_MIN_FREQ: f32 = 779.0;
+const RSSI_OFFSET_HF: i16 = -157;
+const RSSI_OFFSET_LF: i16 = -164;
 
 // SPI;
 const SPI_WRITE_MASK: u8 = 0x80;
 const SPI_READ_MASK: u8 = 0x7F;
 
-// Modes;
-const RADIO_MODE_INITIALISING: u8 = 0;
-const RADIO_MODE_SLEEP: u8 = 1;
-const RADIO_MODE_IDLE: u8 = 2;
-const RADIO_MODE_TX: u8 = 3;
-const RADIO_MODE_RX: u8 = 4;
-const RADIO_MODE_CAD: u8 = 5;
+// SX1276 register values of the modem parameters
+impl Bandwidth {
+    fn bits(self) -> u8 {
+        match self {
+            Bandwidth::Bw7_8 => BW_7K8HZ,
+            Bandwidth::Bw10_4 => BW_10K4HZ,
+            Bandwidth::Bw15_6 => BW_15K6HZ,
+            Bandwidth::Bw20_8 => BW_20K8HZ,
+            Bandwidth::Bw31_25 => BW_31K25HZ,
+            Bandwidth::Bw41_7 => BW_41K7HZ,
+            Bandwidth::Bw62_5 => BW_62K5HZ,
+            Bandwidth::Bw125 => BW_125KHZ,
+            Bandwidth::Bw250 => BW_250KHZ,
+            Bandwidth::Bw500 => BW_500KHZ,
+        }
+    }
+}
+
+impl SpreadingFactor {
+    fn bits(self) -> u8 {
+        match self {
+            SpreadingFactor::Sf6 => SPREADING_FACTOR_64CPS,
+            SpreadingFactor::Sf7 => SPREADING_FACTOR_128CPS,
+            SpreadingFactor::Sf8 => SPREADING_FACTOR_256CPS,
+            SpreadingFactor::Sf9 => SPREADING_FACTOR_512CPS,
+            SpreadingFactor::Sf10 => SPREADING_FACTOR_1024CPS,
+            SpreadingFactor::Sf11 => SPREADING_FACTOR_2048CPS,
+            SpreadingFactor::Sf12 => SPREADING_FACTOR_4096CPS,
+        }
+    }
+}
+
+impl CodingRate {
+    fn bits(self) -> u8 {
+        match self {
+            CodingRate::Cr4_5 => CODING_RATE_4_5,
+            CodingRate::Cr4_6 => CODING_RATE_4_6,
+            CodingRate::Cr4_7 => CODING_RATE_4_7,
+            CodingRate::Cr4_8 => CODING_RATE_4_8,
+        }
+    }
+}
+
+impl ModemConfig {
+    // REG_1D_MODEM_CONFIG1, REG_1E_MODEM_CONFIG2 and REG_26_MODEM_CONFIG3 values
+    fn registers(&self) -> (u8, u8, u8) {
+        let config1 = self.bandwidth.bits()
+            | self.coding_rate.bits()
+            | if self.implicit_header { IMPLICIT_HEADER_MODE_ON } else { IMPLICIT_HEADER_MODE_OFF };
+        let config2 = self.spreading_factor.bits()
+            | if self.crc { RX_PAYLOAD_CRC_ON } else { RX_PAYLOAD_CRC_OFF };
+        let config3 = if self.low_data_rate_optimize() { LOW_DATA_RATE_OPTIMIZE } else { 0 }
+            | if self.agc_auto { AGC_AUTO_ON } else { AGC_AUTO_OFF };
+        (config1, config2, config3)
+    }
+}
 
 #[allow(dead_code)]
-pub struct RF95 {
-    mode: u8,
-    buf: [u8; 256],
-    buflen: u8,
-    last_rssi: i16,
-    rx_bad: u16,
-    rx_good: u16,
-    tx_good: u16,
-    rx_buf_valid: bool,
-    pub spidev: Spidev,
+pub struct RF95<S = Spidev> {
+    state: RadioState,
+    tx_len: usize,
+    tx_deadline: Option<time::Instant>,
+    tx_power: Option<u8>,
+    spi_speed: u32,
+    hop_table: Option<FrequencyHopping>,
+    hopping: bool,
+    pub spi: S,
     pub channel: u8,
     pub int_pin_number: u8,
     int_pin: Pin,
     use_int: bool,
     int_thread: thread::Builder,
     cad: u8,
+    modem: ModemConfig,
+    preamble_len: u16,
+    frequency: f32,
 }
 
 impl RF95 {
     pub fn new(ch: u8, int: u8, use_i: bool) -> Self {
+        let spidev = Spidev::open(String::from("/dev/spidev0.") + &ch.to_string()).unwrap();
+        Self::with_spi(spidev, ch, int, use_i)
+    }
+}
+
+impl<S: SpiBus> RF95<S> {
+    // on any SPI bus, ch is just informative
+    pub fn with_spi(spi: S, ch: u8, int: u8, use_i: bool) -> Self {
         Self {
-            mode: RADIO_MODE_INITIALISING,
-            buf: [0; 256],
-            buflen: 0,
-            last_rssi: -99,
-            rx_bad: 0,
-            rx_good: 0,
-            tx_good: 0,
-            rx_buf_valid: false,
+            state: RadioState::new(),
+            tx_len: 0,
+            tx_deadline: None,
+            tx_power: None,
+            spi_speed: DEFAULT_SPI_SPEED,
+            hop_table: None,
+            hopping: false,
             channel: ch,
             int_pin_number: int,
-            spidev: Spidev::open(String::from("/dev/spidev0.") + &ch.to_string()).unwrap(),
+            spi,
             int_pin: Pin::new(int as u64),
             use_int: use_i,
             int_thread: thread::Builder::new().name("rf95_int".into()),
             cad: 0,
+            modem: ModemPreset::Bw125Cr45Sf128.config(),
+            preamble_len: DEFAULT_PREAMBLE_LEN,
+            frequency: 0.0,
         }
     }
 
     // write one byte of data to register addr
     pub fn spi_write(&mut self, reg: u8, byte: u8) {
-        self.spidev.write(&[reg | SPI_WRITE_MASK, byte]).unwrap();
+        self.spi.write_bytes(&[reg | SPI_WRITE_MASK, byte]).unwrap();
     }
 
     // read one byte of data from register addr
     pub fn spi_read(&mut self, reg: u8) -> u8 {
         let mut rx = [0_u8, 2];
         let tx: [u8; 2] = [reg, 0];
-        {
-            let mut transfer = SpidevTransfer::read_write(&tx, &mut rx);
-            self.spidev.transfer(&mut transfer).unwrap();
-        }
+        self.spi.transfer_bytes(&tx, &mut rx).unwrap();
 
         rx[1]
     }
 
-    // write a slice (array) of data to register addr
+    // write a slice (array) of data to register addr, in a single burst
     pub fn spi_write_data(&mut self, reg: u8, data: &[u8]) {
         // bounds
         if data.len() > MAX_MESSAGE_LEN as usize {
@@ -294,32 +384,338 @@ impl RF95 {
         }
 
         // fill tx buf
-        let mut tx: Vec<u8> = Vec::new();
+        let mut tx: Vec<u8> = Vec::with_capacity(data.len() + 1);
         tx.push(reg | SPI_WRITE_MASK);
 
         tx.extend(data.iter().cloned());
 
-        self.spidev.write(&tx).unwrap();
+        self.spi.write_bytes(&tx).unwrap();
     }
 
+    // read len bytes from register addr in a single burst,
+    // the FIFO address doesn't autoincrement so we get the FIFO data
     pub fn spi_read_data(&mut self, reg: u8, len: u8) -> [u8; 256] {
         let mut data = [0_u8; 256];
-        for i in 0..len {
-            data[i as usize] = self.spi_read(reg + i);
-        }
+        let mut tx = vec![0_u8; len as usize + 1];
+        let mut rx = vec![0_u8; len as usize + 1];
+        tx[0] = reg;
+        self.spi.transfer_bytes(&tx, &mut rx).unwrap();
+        data[..len as usize].copy_from_slice(&rx[1..]);
 
         data
     }
 
+    pub fn spi_speed(&self) -> u32 {
+        self.spi_speed
+    }
+
+    pub fn set_mode_rx(&mut self) {
+        if self.state.mode != RADIO_MODE_RX {
+            self.spi_write(REG_01_OP_MODE, MODE_RXCONTINUOUS | LONG_RANGE_MODE);
+            self.spi_write(REG_40_DIO_MAPPING1, 0x00u8);
+            self.state.enter_mode(RADIO_MODE_RX);
+        }
+    }
+
+    pub fn set_mode_cad(&mut self) {
+        if self.state.mode != RADIO_MODE_CAD {
+            self.spi_write(REG_01_OP_MODE, MODE_CAD | LONG_RANGE_MODE);
+            self.spi_write(REG_40_DIO_MAPPING1, 0x80u8);
+            self.state.enter_mode(RADIO_MODE_CAD);
+        }
+    }
+
+    pub fn set_mode_tx(&mut self) {
+        if self.state.mode != RADIO_MODE_TX {
+            self.spi_write(REG_01_OP_MODE, MODE_TX | LONG_RANGE_MODE);
+            self.spi_write(REG_40_DIO_MAPPING1, 0x40u8);
+            self.state.enter_mode(RADIO_MODE_TX);
+        }
+    }
+
+    // REG_09_PA_CONFIG and REG_4D_PA_DAC values for a power level
+    fn pa_registers(p: u8) -> (u8, u8) {
+        let mut power = p;
+
+        // bounds
+        if power > 23 {
+            power = 23;
+        }
+
+        if power < 5 {
+            power = 5;
+        }
+
+        // A_DAC_ENABLE actually adds about 3dBm to all
+        // power levels. We will us it for 21, 22 and 23dBm
+
+        let pa_dac = if power > 20 {
+            power -= 3;
+            PA_DAC_ENABLE
+        } else {
+            PA_DAC_DISABLE
+        };
+
+        (PA_SELECT | (power - 5), pa_dac)
+    }
+
+    pub fn set_preamble_length(&mut self, len: u16) {
+        self.preamble_len = len;
+        self.spi_write(REG_20_PREAMBLE_MSB, (len >> 8) as u8);
+        self.spi_write(REG_21_PREAMBLE_LSB, (len & 0xff) as u8);
+    }
+
+    // read the registers we configure
+    pub fn snapshot(&mut self) -> RegisterSnapshot {
+        let registers = self
+            .expected_registers()
+            .into_iter()
+            .map(|(reg, _)| (reg as u16, self.spi_read(reg)))
+            .collect();
+
+        RegisterSnapshot { registers }
+    }
+
+    // values the configuration registers should have
+    fn expected_registers(&self) -> Vec<(u8, u8)> {
+        let mut freq_value: i32 = ((self.frequency * 1000000.0) / FSTEP) as i32;
+        let mut hop_period = 0;
+        if let Some(t) = self.hop_table.as_ref().filter(|_| self.hopping) {
+            // resting on the first hop channel
+            freq_value = Self::frf(t.channels[0] as f64 * 1e6) as i32;
+            hop_period = t.period;
+        }
+        let (config1, config2, config3) = self.modem.registers();
+        let sf6 = self.modem.spreading_factor == SpreadingFactor::Sf6;
+        let (detect_opt, detection_threshold) = if sf6 { (0xc5, 0x0c) } else { (0xc3, 0x0a) };
+
+        let mut regs = vec![
+            (REG_06_FRF_MSB, ((freq_value >> 16) & 0xff) as u8),
+            (REG_07_FRF_MID, ((freq_value >> 8) & 0xff) as u8),
+            (REG_08_FRF_LSB, (freq_value & 0xff) as u8),
+            (REG_0E_FIFO_TX_BASE_ADDR, 0),
+            (REG_0F_FIFO_RX_BASE_ADDR, 0),
+            (REG_1D_MODEM_CONFIG1, config1),
+            (REG_1E_MODEM_CONFIG2, config2),
+            (REG_20_PREAMBLE_MSB, (self.preamble_len >> 8) as u8),
+            (REG_21_PREAMBLE_LSB, (self.preamble_len & 0xff) as u8),
+            (REG_24_HOP_PERIOD, hop_period),
+            (REG_26_MODEM_CONFIG3, config3),
+            (REG_31_DETECT_OPT, detect_opt),
+            (REG_37_DETECTION_THRESHOLD, detection_threshold),
+        ];
+        if let Some(p) = self.tx_power {
+            let (pa_config, pa_dac) = Self::pa_registers(p);
+            regs.push((REG_09_PA_CONFIG, pa_config));
+            regs.push((REG_4D_PA_DAC, pa_dac));
+        }
+
+        regs
+    }
+
+    // on-chip temperature (uncalibrated, about 1ºC per step), measured
+    // by an image calibration in FSK mode and read from REG_5B_FORMER_TEMP.
+    // The radio is left in idle mode, or listening if it was.
+    pub fn read_temperature(&mut self) -> Result<i16, &'static str> {
+        let previous = self.state.mode;
+
+        // LoRa/FSK can only be changed in sleep mode
+        self.set_mode_sleep()?;
+        self.spi_write(REG_01_OP_MODE, MODE_SLEEP);
+        self.spi_write(REG_01_OP_MODE, MODE_STDBY);
+
+        let cal = self.spi_read(REG_3B_IMAGE_CAL);
+        self.spi_write(REG_3B_IMAGE_CAL, cal | IMAGE_CAL_START);
+        let deadline = time::Instant::now() + time::Duration::from_millis(TX_TIMEOUT_MARGIN);
+        while self.spi_read(REG_3B_IMAGE_CAL) & IMAGE_CAL_RUNNING != 0 {
+            if time::Instant::now() >= deadline {
+                self.recover()?;
+                return Err("Temperature read timeout");
+            }
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        let raw = self.spi_read(REG_5B_FORMER_TEMP);
+
+        // back to LoRa
+        self.spi_write(REG_01_OP_MODE, MODE_SLEEP);
+        self.spi_write(REG_01_OP_MODE, MODE_SLEEP | LONG_RANGE_MODE);
+        self.set_mode_idle()?;
+        if previous == RADIO_MODE_RX {
+            self.set_mode_rx();
+        }
+
+        // signed, -1ºC per step
+        Ok(-((raw as i8) as i16))
+    }
+
+    // enable or disable frequency hopping in TX and RX, the radio must
+    // not be transmitting or receiving
+    pub fn set_hopping(&mut self, enabled: bool) -> Result<(), &'static str> {
+        if !enabled {
+            self.hopping = false;
+            self.spi_write(REG_24_HOP_PERIOD, 0);
+            let freq = self.frequency;
+            self.set_frequency(freq)?;
+            return Ok(());
+        }
+
+        let period = match self.hop_table {
+            Some(ref t) => {
+                t.check_period(&self.modem)?;
+                t.period
+            }
+            None => return Err("No hop table"),
+        };
+        self.hopping = true;
+        self.spi_write(REG_24_HOP_PERIOD, period);
+        self.hop_to(0);
+        Ok(())
+    }
+
+    // program a channel of the hop table
+    fn hop_to(&mut self, channel: usize) {
+        if !self.hopping {
+            return;
+        }
+        let freq = match self.hop_table {
+            Some(ref t) => t.channels[channel % t.channels.len()],
+            None => return,
+        };
+        self.set_frf(Self::frf(freq as f64 * 1e6));
+    }
+
+    // FhssChangeChannel, move to the channel the radio is counting
+    fn hop(&mut self) {
+        let channel = self.spi_read(REG_1C_HOP_CHANNEL) & FHSS_PRESENT_CHANNEL;
+        self.hop_to(channel as usize);
+        self.spi_write(REG_12_IRQ_FLAGS, FHSS_CHANGE_CHANNEL);
+    }
+
+    // FSK mode with no deviation and continuous mode with DIO2 (data)
+    // unused, so we get a plain carrier we can move or key ourselves
+    fn enter_carrier_mode(&mut self) -> Result<(), &'static str> {
+        self.wait_packet_sent()?;
+
+        // FSK mode, can only be changed in sleep mode
+        self.set_mode_sleep()?;
+        self.spi_write(REG_01_OP_MODE, MODE_SLEEP);
+        self.spi_write(REG_01_OP_MODE, MODE_STDBY);
+
+        self.spi_write(REG_04_FDEV_MSB, 0);
+        self.spi_write(REG_05_FDEV_LSB, 0);
+        self.spi_write(REG_31_PACKET_CONFIG2, DATA_MODE_CONTINUOUS);
+
+        Ok(())
+    }
+
+    // back to LoRa, with its configuration
+    fn leave_carrier_mode(&mut self) -> Result<(), &'static str> {
+        self.spi_write(REG_01_OP_MODE, MODE_SLEEP);
+        self.spi_write(REG_01_OP_MODE, MODE_SLEEP | LONG_RANGE_MODE);
+        self.state.enter_mode(RADIO_MODE_SLEEP);
+        self.reconfigure()
+    }
+
+    // frequency register value for a frequency in Hz
+    fn frf(freq: f64) -> u32 {
+        (freq / FSTEP as f64).round() as u32
+    }
+
+    // the new frequency is used once the LSB is written
+    fn set_frf(&mut self, frf: u32) {
+        self.spi_write(REG_06_FRF_MSB, ((frf >> 16) & 0xff) as u8);
+        self.spi_write(REG_07_FRF_MID, ((frf >> 8) & 0xff) as u8);
+        self.spi_write(REG_08_FRF_LSB, (frf & 0xff) as u8);
+    }
+
+    // init the chip and restore the LoRa configuration
+    fn reconfigure(&mut self) -> Result<(), &'static str> {
+        let mode = self.state.mode;
+        let modem = self.modem;
+        let frequency = self.frequency;
+        let preamble_len = self.preamble_len;
+
+        self.state.enter_mode(RADIO_MODE_INITIALISING);
+        self.init()?;
+        self.set_frequency(frequency)?;
+        self.set_modem_config(&modem)?;
+        self.set_preamble_length(preamble_len);
+        if let Some(p) = self.tx_power {
+            self.set_tx_power(p)?;
+        }
+        if self.hopping {
+            self.set_hopping(true)?;
+        }
+        // an interrupted transmission is not resumed
+        if mode == RADIO_MODE_RX {
+            self.set_mode_rx();
+        }
+
+        Ok(())
+    }
+
+    pub fn set_header_flags(&mut self, flags: u8) {
+        self.state.tx_header.flags = flags;
+    }
+
+    // current RSSI of the channel (dBm)
+    pub fn rssi(&mut self) -> i16 {
+        self.rssi_offset() + self.spi_read(REG_1B_RSSI_VALUE) as i16
+    }
+
+    fn rssi_offset(&self) -> i16 {
+        if self.frequency >= HF_PORT_MIN_FREQ {
+            RSSI_OFFSET_HF
+        } else {
+            RSSI_OFFSET_LF
+        }
+    }
+
+    // packet RSSI and SNR (datasheet 5.5.5) and frequency error (4.1.5)
+    fn read_packet_info(&mut self) -> PacketInfo {
+        // SNR is two's complement, in 0.25dB steps
+        let snr = (self.spi_read(REG_19_PKT_SNR_VALUE) as i8) as f32 / 4.0;
+
+        let pkt_rssi = self.spi_read(REG_1A_PKT_RSSI_VALUE) as f32;
+        let rssi = if snr < 0.0 {
+            pkt_rssi + snr
+        } else {
+            pkt_rssi * 16.0 / 15.0
+        };
+
+        // 20 bit two's complement
+        let msb = (self.spi_read(REG_28_FREQ_ERROR) & 0x0f) as i32;
+        let mid = self.spi_read(REG_29_FREQ_ERROR_MID) as i32;
+        let lsb = self.spi_read(REG_2A_FREQ_ERROR_LSB) as i32;
+        let mut ferr = (msb << 16) | (mid << 8) | lsb;
+        if ferr & 0x80000 != 0 {
+            ferr -= 0x100000;
+        }
+        let freq_error = ferr as f32 * (1u32 << 24) as f32 / FXOSC
+            * (self.modem.bandwidth.hz() / 500000.0);
+
+        PacketInfo {
+            rssi: self.rssi_offset() + rssi.round() as i16,
+            snr,
+            freq_error,
+        }
+    }
+}
+
+impl<S: SpiBus> Radio for RF95<S> {
+    fn state(&self) -> &RadioState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut RadioState {
+        &mut self.state
+    }
+
     // configure SPI bus and RF95 LoRa default mode
-    pub fn init(&mut self) -> Result<(), &'static str> {
+    fn init(&mut self) -> Result<(), &'static str> {
         // configure SPI and initialize RF95
-        let options = SpidevOptions::new()
-            .bits_per_word(8)
-            .max_speed_hz(5000)
-            .mode(SPI_MODE_0)
-            .build();
-        self.spidev.configure(&options).unwrap();
+        let speed = self.spi_speed;
+        self.spi.setup(speed).unwrap();
 
         // set LoRa mode
         self.spi_write(REG_01_OP_MODE, MODE_SLEEP | LONG_RANGE_MODE);
@@ -331,14 +727,19 @@ impl RF95 {
             return Err("Lora not configured");
         }
 
+        // and that it's really a SX1276
+        if self.spi_read(REG_42_VERSION) != SX1276_VERSION {
+            return Err("Unknown radio version");
+        }
+
         // set up FIFO
         self.spi_write(REG_0E_FIFO_TX_BASE_ADDR, 0);
         self.spi_write(REG_0F_FIFO_RX_BASE_ADDR, 0);
 
         // default mode
-        self.set_mode_idle();
+        self.set_mode_idle()?;
 
-        self.set_modem_config(BW125_CR45_SF128);
+        self.set_modem_config(&ModemPreset::Bw125Cr45Sf128.config())?;
         self.set_preamble_length(8);
 
         // setup gpio
@@ -350,204 +751,651 @@ impl RF95 {
         Ok(())
     }
 
-    pub fn set_frequency(&mut self, freq: f32) {
+    // SPI clock in Hz, applied in init()
+    fn set_spi_speed(&mut self, hz: u32) {
+        self.spi_speed = hz.clamp(1, MAX_SPI_SPEED);
+    }
+
+    fn set_frequency(&mut self, freq: f32) -> Result<(), &'static str> {
+        self.frequency = freq;
         let freq_value: i32 = ((freq * 1000000.0) / FSTEP) as i32;
 
         self.spi_write(REG_06_FRF_MSB, ((freq_value >> 16) & 0xff) as u8);
         self.spi_write(REG_07_FRF_MID, ((freq_value >> 8) & 0xff) as u8);
         self.spi_write(REG_08_FRF_LSB, ((freq_value) & 0xff) as u8);
+        Ok(())
     }
 
-    pub fn set_mode_idle(&mut self) {
-        if self.mode != RADIO_MODE_IDLE {
-            self.spi_write(REG_01_OP_MODE, MODE_STDBY);
-            self.mode = RADIO_MODE_IDLE;
-        }
+    fn frequency(&self) -> f32 {
+        self.frequency
     }
 
-    pub fn set_mode_sleep(&mut self) {
-        if self.mode != RADIO_MODE_SLEEP {
-            self.spi_write(REG_01_OP_MODE, MODE_SLEEP);
-            self.mode = RADIO_MODE_SLEEP;
-        }
+    fn set_tx_power(&mut self, p: u8) -> Result<(), &'static str> {
+        self.tx_power = Some(p);
+        let (pa_config, pa_dac) = Self::pa_registers(p);
+        self.spi_write(REG_4D_PA_DAC, pa_dac);
+        self.spi_write(REG_09_PA_CONFIG, pa_config);
+        Ok(())
     }
 
-    pub fn set_mode_rx(&mut self) {
-        if self.mode != RADIO_MODE_RX {
-            self.spi_write(REG_01_OP_MODE, MODE_RXCONTINUOUS);
-            self.spi_write(REG_40_DIO_MAPPING1, 0x00u8);
-            self.mode = RADIO_MODE_RX;
+    // set modem parameters, from a preset or custom values
+    fn set_modem_config(&mut self, config: &ModemConfig) -> Result<(), &'static str> {
+        config.validate()?;
+
+        let (config1, config2, config3) = config.registers();
+        self.spi_write(REG_1D_MODEM_CONFIG1, config1);
+        self.spi_write(REG_1E_MODEM_CONFIG2, config2);
+        self.spi_write(REG_26_MODEM_CONFIG3, config3);
+
+        // SF6 needs special detection settings
+        if config.spreading_factor == SpreadingFactor::Sf6 {
+            self.spi_write(REG_31_DETECT_OPT, 0xc5);
+            self.spi_write(REG_37_DETECTION_THRESHOLD, 0x0c);
+        } else {
+            self.spi_write(REG_31_DETECT_OPT, 0xc3);
+            self.spi_write(REG_37_DETECTION_THRESHOLD, 0x0a);
         }
+
+        self.modem = *config;
+        Ok(())
     }
 
-    pub fn set_mode_tx(&mut self) {
-        if self.mode != RADIO_MODE_TX {
-            self.spi_write(REG_01_OP_MODE, MODE_TX);
-            self.spi_write(REG_40_DIO_MAPPING1, 0x40u8);
-            self.mode = RADIO_MODE_TX;
-        }
+    fn modem_config(&self) -> ModemConfig {
+        self.modem
     }
 
-    pub fn set_tx_power(&mut self, p: u8) {
-        let mut power = p;
+    // retune to a profile, waiting for the current packet,
+    // only the registers that change are written
+    fn set_profile(&mut self, profile: &RadioProfile) -> Result<(), &'static str> {
+        self.wait_packet_sent()?;
 
-        // bounds
-        if power > 23 {
-            power = 23;
+        let listening = self.state.mode == RADIO_MODE_RX;
+        self.set_mode_idle()?;
+        if profile.frequency != self.frequency {
+            self.set_frequency(profile.frequency)?;
         }
-
-        if power < 5 {
-            power = 5;
+        if profile.modem != self.modem {
+            self.set_modem_config(&profile.modem)?;
         }
-
-        // A_DAC_ENABLE actually adds about 3dBm to all
-        // power levels. We will us it for 21, 22 and 23dBm
-
-        if power > 20 {
-            self.spi_write(REG_4D_PA_DAC, PA_DAC_ENABLE);
-            power -= 3;
-        } else {
-            self.spi_write(REG_4D_PA_DAC, PA_DAC_DISABLE);
+        if Some(profile.power) != self.tx_power {
+            self.set_tx_power(profile.power)?;
+        }
+        // set_frequency() moves away from the first hop channel
+        if profile.hopping || self.hopping {
+            self.set_hopping(profile.hopping)?;
+        }
+        if listening {
+            self.set_mode_rx();
         }
 
-        // write it
-        self.spi_write(REG_09_PA_CONFIG, PA_SELECT | (power - 5));
+        Ok(())
     }
 
-    // set mode from default modes
-    pub fn set_modem_config(&mut self, mode: (u8, u8, u8)) {
-        self.spi_write(REG_1D_MODEM_CONFIG1, mode.0);
-        self.spi_write(REG_1E_MODEM_CONFIG2, mode.1);
-        self.spi_write(REG_26_MODEM_CONFIG3, mode.2);
+    // time a packet of len bytes occupies the channel with the
+    // current modem configuration
+    fn time_on_air(&self, len: usize) -> time::Duration {
+        self.modem.time_on_air(self.preamble_len, len)
     }
 
-    pub fn set_modem_config_custom(
-        &mut self,
-        bandwidth: u8,
-        coding_rate: u8,
-        implicit_header: u8,
-        spreading_factor: u8,
-        crc: u8,
-        continuous_tx: u8,
-        timeout: u8,
-        agc_auto: u8,
-    ) {
-        self.spi_write(
-            REG_1D_MODEM_CONFIG1,
-            bandwidth | coding_rate | implicit_header,
-        );
-        self.spi_write(
-            REG_1E_MODEM_CONFIG2,
-            spreading_factor | continuous_tx | crc | timeout,
-        );
-        self.spi_write(REG_26_MODEM_CONFIG3, agc_auto);
+    fn set_mode_idle(&mut self) -> Result<(), &'static str> {
+        if self.state.mode != RADIO_MODE_IDLE {
+            // without LONG_RANGE_MODE the chip wakes up in FSK mode
+            self.spi_write(REG_01_OP_MODE, MODE_STDBY | LONG_RANGE_MODE);
+            if self.state.mode == RADIO_MODE_SLEEP {
+                // oscillator start up, the registers are kept while sleeping
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            self.state.enter_mode(RADIO_MODE_IDLE);
+            self.hop_to(0);
+        }
+        Ok(())
     }
 
-    pub fn set_preamble_length(&mut self, len: u16) {
-        self.spi_write(REG_20_PREAMBLE_MSB, (len >> 8) as u8);
-        self.spi_write(REG_21_PREAMBLE_LSB, (len & 0xff) as u8);
+    fn set_mode_sleep(&mut self) -> Result<(), &'static str> {
+        if self.state.mode != RADIO_MODE_SLEEP {
+            self.spi_write(REG_01_OP_MODE, MODE_SLEEP | LONG_RANGE_MODE);
+            self.state.enter_mode(RADIO_MODE_SLEEP);
+            self.hop_to(0);
+        }
+        Ok(())
     }
 
     // Send data
-    pub fn send(&mut self, data: &[u8]) -> bool {
-        if data.len() > MAX_MESSAGE_LEN as usize {
-            return false;
+    fn send(&mut self, data: &[u8]) -> Result<bool, &'static str> {
+        if data.len() + self.header_len() > MAX_MESSAGE_LEN as usize {
+            return Ok(false);
         }
 
-        self.wait_packet_sent();
+        self.wait_packet_sent()?;
 
-        self.set_mode_idle();
+        if let Some(lbt) = self.state.lbt {
+            self.listen_before_talk(&lbt)?;
+        }
+
+        self.set_mode_idle()?;
 
         // beggining of FIFO
         self.spi_write(REG_0D_FIFO_ADDR_PTR, 0);
 
+        // header and data
+        let packet = self.state.packet(data);
+
         // write data
-        self.spi_write_data(REG_00_FIFO, data);
-        self.spi_write(REG_22_PAYLOAD_LENGTH, data.len() as u8);
+        let start = time::Instant::now();
+        self.spi_write_data(REG_00_FIFO, &packet);
+        self.state.stats.fifo_time += start.elapsed();
+        self.spi_write(REG_22_PAYLOAD_LENGTH, packet.len() as u8);
+        self.tx_len = packet.len();
+        self.tx_deadline = Some(
+            time::Instant::now()
+                + self.time_on_air(packet.len()) * TX_TIMEOUT_FACTOR
+                + time::Duration::from_millis(TX_TIMEOUT_MARGIN),
+        );
 
         self.set_mode_tx();
 
-        true
+        Ok(true)
     }
 
-    pub fn wait_packet_sent(&mut self) -> bool {
-        if !self.use_int {
-            // If we are not currently in transmit mode,
-            // there is no packet to wait for
-            if self.mode != RADIO_MODE_TX {
-                return false;
-            }
+    // wait for the current transmission, Err if it doesn't finish in time.
+    // The radio is then re-initialised and the packet is lost.
+    fn wait_packet_sent(&mut self) -> Result<bool, &'static str> {
+        // If we are not currently in transmit mode,
+        // there is no packet to wait for
+        if self.state.mode != RADIO_MODE_TX {
+            return Ok(false);
+        }
 
-            while (self.spi_read(REG_12_IRQ_FLAGS) & TX_DONE) == 0 {
-                thread::sleep(time::Duration::from_millis(10));
+        let deadline = self.tx_deadline.unwrap_or_else(|| {
+            time::Instant::now() + time::Duration::from_millis(TX_TIMEOUT_MARGIN)
+        });
+        // hops must be followed quickly
+        let poll = time::Duration::from_millis(if self.hopping { 1 } else { 10 });
+        loop {
+            let done = if self.use_int {
+                self.state.mode != RADIO_MODE_TX
+            } else {
+                let irq_flags = self.spi_read(REG_12_IRQ_FLAGS);
+                if irq_flags & FHSS_CHANGE_CHANNEL != 0 && irq_flags & TX_DONE == 0 {
+                    self.hop();
+                }
+                (irq_flags & TX_DONE) != 0
+            };
+            if done {
+                break;
             }
+            if time::Instant::now() >= deadline {
+                self.state.stats.tx_timeouts += 1;
+                self.tx_deadline = None;
+                self.recover()?;
+                return Err("TX timeout");
+            }
+            thread::sleep(poll);
+        }
+        self.tx_deadline = None;
 
-            self.tx_good += 1;
+        if !self.use_int {
+            self.state.stats.tx_packets += 1;
+            self.state.stats.tx_bytes += self.tx_len as u64;
+            self.state.stats.tx_airtime += self.time_on_air(self.tx_len);
 
             // clear IRQ flags
             self.spi_write(REG_12_IRQ_FLAGS, 0xff);
 
-            self.set_mode_idle();
-
-            true
-        } else {
-            while self.mode == RADIO_MODE_TX {
-                thread::sleep(time::Duration::from_millis(10));
+            if self.state.sleep_after_tx {
+                self.set_mode_sleep()?;
+            } else {
+                self.set_mode_idle()?;
             }
-
-            true
         }
+
+        Ok(true)
     }
 
-    pub fn available(&mut self) -> Result<bool, &'static str> {
+    fn available(&mut self) -> Result<bool, &'static str> {
         if !self.use_int {
             // read the interrupt register
             let irq_flags = self.spi_read(REG_12_IRQ_FLAGS);
 
-            if (self.mode == RADIO_MODE_RX) && (irq_flags & RX_DONE != 0) {
+            if (self.state.mode == RADIO_MODE_RX)
+                && (irq_flags & FHSS_CHANGE_CHANNEL != 0)
+                && (irq_flags & RX_DONE == 0)
+            {
+                // a packet is being received, follow it
+                self.hop();
+                return Ok(false);
+            }
+
+            if (self.state.mode == RADIO_MODE_RX)
+                && (irq_flags & RX_DONE != 0)
+                && (irq_flags & PAYLOAD_CRC_ERROR != 0)
+            {
+                // corrupted packet, discard it
+                self.state.stats.rx_crc_errors += 1;
+                self.state.last_packet = Some(self.read_packet_info());
+                self.state.stats.last_packet = self.state.last_packet;
+            } else if (self.state.mode == RADIO_MODE_RX) && (irq_flags & RX_DONE != 0) {
                 // Have received a packet
                 let length = self.spi_read(REG_13_RX_NB_BYTES);
 
                 // Reset the fifo read ptr to the beginning of the packet
                 let ptr = self.spi_read(REG_10_FIFO_RX_CURRENT_ADDR);
                 self.spi_write(REG_0D_FIFO_ADDR_PTR, ptr);
-                self.buf = self.spi_read_data(REG_00_FIFO, length);
-                self.buflen = length;
+                let start = time::Instant::now();
+                self.state.buf = self.spi_read_data(REG_00_FIFO, length);
+                self.state.stats.fifo_time += start.elapsed();
+                self.state.buflen = length;
                 // clear IRQ flags
                 self.spi_write(REG_12_IRQ_FLAGS, 0xff);
 
-                // Remember the RSSI of this packet
-                // this is according to the doc, but is it really correct?
-                // weakest receiveable signals are reported RSSI at about -66
-                self.last_rssi = (self.spi_read(REG_1A_PKT_RSSI_VALUE) as i16) - 137;
+                // Remember the RSSI, SNR and frequency error of this packet
+                self.state.last_packet = Some(self.read_packet_info());
+                self.state.stats.last_packet = self.state.last_packet;
 
                 // We have received a message.
-                // validateRxBuf();  TO BE IMPLEMENTED
-                self.rx_good += 1;
-                self.rx_buf_valid = true;
-                if self.rx_buf_valid {
-                    self.set_mode_idle();
+                self.state.stats.rx_bytes += length as u64;
+                self.state.validate_rx_buf();
+                if self.state.rx_buf_valid {
+                    self.state.stats.rx_packets += 1;
+                    self.set_mode_idle()?;
+                } else {
+                    self.state.stats.rx_dropped += 1;
                 }
-            } else if (self.mode == RADIO_MODE_CAD) && (irq_flags & CAD_DONE != 0) {
+            } else if (self.state.mode == RADIO_MODE_CAD) && (irq_flags & CAD_DONE != 0) {
                 self.cad = irq_flags & CAD_DETECTED;
-                self.set_mode_idle();
+                self.set_mode_idle()?;
             }
 
-            self.spi_write(REG_12_IRQ_FLAGS, 0xff); // Clear all IRQ flags
+            if irq_flags & RX_DONE != 0 {
+                // the next packet starts on the first channel
+                self.hop_to(0);
+            }
+
+            if self.hopping {
+                // don't lose a hop raised since they were read
+                self.spi_write(REG_12_IRQ_FLAGS, irq_flags);
+            } else {
+                self.spi_write(REG_12_IRQ_FLAGS, 0xff); // Clear all IRQ flags
+            }
 
-            if self.mode == RADIO_MODE_TX {
+            if self.state.mode == RADIO_MODE_TX {
                 return Err("Radio in TX mode");
             }
 
             self.set_mode_rx();
-            Ok(self.rx_buf_valid)
+            Ok(self.state.rx_buf_valid)
         } else {
             Ok(false)
         }
     }
 
-    pub fn clear_rx_buf(&mut self) {
-        self.rx_buf_valid = false;
-        self.buflen = 0;
+    fn channel_activity(&mut self) -> Result<bool, &'static str> {
+        if self.state.mode == RADIO_MODE_TX {
+            return Err("Radio in TX mode");
+        }
+
+        let previous = self.state.mode;
+        self.set_mode_idle()?;
+        self.spi_write(REG_12_IRQ_FLAGS, 0xff);
+        self.set_mode_cad();
+
+        let timeout = time::Duration::from_micros(
+            (self.modem.symbol_time() * CAD_TIMEOUT_SYMBOLS * 1e6) as u64,
+        ) + time::Duration::from_millis(TX_TIMEOUT_MARGIN);
+        let deadline = time::Instant::now() + timeout;
+        let irq_flags = loop {
+            let flags = self.spi_read(REG_12_IRQ_FLAGS);
+            if flags & CAD_DONE != 0 {
+                break flags;
+            }
+            if time::Instant::now() >= deadline {
+                self.recover()?;
+                return Err("CAD timeout");
+            }
+            thread::sleep(time::Duration::from_millis(1));
+        };
+
+        self.cad = irq_flags & CAD_DETECTED;
+        self.spi_write(REG_12_IRQ_FLAGS, 0xff);
+        self.set_mode_idle()?;
+        if previous == RADIO_MODE_RX {
+            self.set_mode_rx();
+        }
+
+        Ok(self.cad != 0)
+    }
+
+    // channels and period used when hopping is enabled
+    fn set_hop_table(&mut self, table: FrequencyHopping) -> Result<(), &'static str> {
+        table.validate()?;
+        self.hop_table = Some(table);
+        if self.hopping {
+            self.set_hopping(true)?;
+        }
+        Ok(())
+    }
+
+    fn hopping(&self) -> bool {
+        self.hopping
+    }
+
+    fn send_tones(
+        &mut self,
+        frequency: f32,
+        offsets: &[f64],
+        lead: Option<(usize, time::Duration)>,
+        symbols: &[usize],
+        symbol_time: time::Duration,
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str> {
+        self.enter_carrier_mode()?;
+        let mut ticker = Ticker::new(tick);
+
+        let carrier = frequency as f64 * 1000000.0;
+        let tones: Vec<u32> = offsets.iter().map(|o| Self::frf(carrier + o)).collect();
+
+        let (lead_tone, lead_time) = lead.unwrap_or((0, time::Duration::from_secs(0)));
+        self.set_frf(tones[lead_tone]);
+        self.spi_write(REG_01_OP_MODE, MODE_TX);
+        self.state.enter_mode(RADIO_MODE_TX);
+        let start = time::Instant::now();
+        sleep_until(start + lead_time);
+
+        // symbol times from the start, so errors don't accumulate
+        for (n, s) in symbols.iter().enumerate() {
+            self.set_frf(tones[*s]);
+            // during the symbol, the sleep absorbs its time
+            ticker.poll();
+            sleep_until(start + lead_time + symbol_time * (n as u32 + 1));
+        }
+
+        self.spi_write(REG_01_OP_MODE, MODE_STDBY);
+        self.state.enter_mode(RADIO_MODE_IDLE);
+        self.state.stats.tx_airtime += start.elapsed();
+
+        self.leave_carrier_mode()
+    }
+
+    // send Morse code elements (key down, units) as an unmodulated
+    // carrier. Blocks until it's sent and goes back to LoRa mode.
+    fn send_cw(
+        &mut self,
+        cw: &CwConfig,
+        elements: &[(bool, u32)],
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str> {
+        cw.validate()?;
+        self.enter_carrier_mode()?;
+        let mut ticker = Ticker::new(tick);
+
+        self.set_frf(Self::frf(cw.frequency as f64 * 1000000.0));
+        // key up with the synthesizer running, so key down is fast
+        self.spi_write(REG_01_OP_MODE, MODE_FSTX);
+        self.state.enter_mode(RADIO_MODE_TX);
+
+        let start = time::Instant::now();
+        let unit = cw.unit();
+        let mut units = 0;
+        let mut on_air = time::Duration::from_secs(0);
+        for &(key_down, n) in elements {
+            let t = time::Instant::now();
+            self.spi_write(REG_01_OP_MODE, if key_down { MODE_TX } else { MODE_FSTX });
+            units += n;
+            ticker.poll();
+            sleep_until(start + unit * units);
+            if key_down {
+                on_air += t.elapsed();
+            }
+        }
+
+        self.spi_write(REG_01_OP_MODE, MODE_STDBY);
+        self.state.enter_mode(RADIO_MODE_IDLE);
+        self.state.stats.tx_airtime += on_air;
+
+        self.leave_carrier_mode()
+    }
+
+    // check the chip version, the on-chip temperature and that the
+    // configuration registers still have the values we wrote
+    fn diagnose(&mut self) -> Result<Diagnostics, &'static str> {
+        if self.state.mode == RADIO_MODE_TX {
+            return Err("Radio in TX mode");
+        }
+
+        let version = self.spi_read(REG_42_VERSION);
+        if version != SX1276_VERSION {
+            return Err("Unknown radio version");
+        }
+
+        let temperature = self.read_temperature()?;
+        let snapshot = self.snapshot();
+        let drift = self
+            .expected_registers()
+            .into_iter()
+            .zip(snapshot.registers.iter())
+            .filter(|&((_, expected), &(_, actual))| expected != actual)
+            .map(|((register, expected), &(_, actual))| RegisterDrift {
+                register: register as u16,
+                expected,
+                actual,
+            })
+            .collect();
+
+        Ok(Diagnostics {
+            version: format!("SX1276 0x{:02x}", version),
+            temperature: Some(temperature),
+            device_errors: 0,
+            snapshot,
+            drift,
+        })
+    }
+
+    // re-initialise the chip after a failure, restoring
+    // its frequency, modem configuration, power and mode
+    fn recover(&mut self) -> Result<(), &'static str> {
+        self.state.stats.resets += 1;
+        self.reconfigure()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Write {
+        OpMode(u8),
+        Frf(u32),
+    }
+
+    // registers in memory, the operating mode and frequency
+    // writes are recorded with their time
+    struct MockSpi {
+        regs: [u8; 128],
+        writes: Vec<(Write, Instant)>,
+    }
+
+    impl SpiBus for MockSpi {
+        fn setup(&mut self, _speed_hz: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+            let reg = data[0] & SPI_READ_MASK;
+            if reg == REG_00_FIFO {
+                return Ok(());
+            }
+            self.regs[reg as usize] = data[1];
+            let now = Instant::now();
+            if reg == REG_01_OP_MODE {
+                self.writes.push((Write::OpMode(data[1]), now));
+            } else if reg == REG_08_FRF_LSB {
+                let r = &self.regs;
+                let frf = (r[6] as u32) << 16 | (r[7] as u32) << 8 | r[8] as u32;
+                self.writes.push((Write::Frf(frf), now));
+            }
+            Ok(())
+        }
+
+        fn transfer_bytes(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+            let reg = tx[0] & SPI_READ_MASK;
+            for b in rx[1..].iter_mut() {
+                *b = match reg {
+                    // packets are sent at once
+                    REG_12_IRQ_FLAGS => TX_DONE,
+                    _ => self.regs[reg as usize],
+                };
+            }
+            Ok(())
+        }
+    }
+
+    fn radio() -> RF95<MockSpi> {
+        let mut spi = MockSpi {
+            regs: [0; 128],
+            writes: Vec::new(),
+        };
+        spi.regs[REG_42_VERSION as usize] = SX1276_VERSION;
+
+        let mut rf95 = RF95::with_spi(spi, 0, 25, false);
+        rf95.init().unwrap();
+        rf95.set_frequency(434.0).unwrap();
+        rf95.spi.writes.clear();
+        rf95
+    }
+
+    fn op_modes(rf95: &RF95<MockSpi>) -> Vec<u8> {
+        rf95.spi
+            .writes
+            .iter()
+            .filter_map(|w| match w.0 {
+                Write::OpMode(m) => Some(m),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lora_after_sleep() {
+        let mut rf95 = radio();
+        rf95.set_sleep_after_tx(true);
+        rf95.set_mode_sleep().unwrap();
+        for data in &[b"one", b"two"] {
+            assert!(rf95.send(*data).unwrap());
+            assert!(rf95.wait_packet_sent().unwrap());
+        }
+
+        let modes = op_modes(&rf95);
+        assert!(modes.iter().all(|m| m & LONG_RANGE_MODE != 0), "{:02x?}", modes);
+        let sent = modes.iter().filter(|&&m| m == MODE_TX | LONG_RANGE_MODE).count();
+        assert_eq!(sent, 2);
+        assert_eq!(modes.last(), Some(&(MODE_SLEEP | LONG_RANGE_MODE)));
+    }
+
+    #[test]
+    fn temperature_in_fsk_mode() {
+        let mut rf95 = radio();
+        rf95.read_temperature().unwrap();
+
+        // FSK is entered and left from sleep
+        let modes = op_modes(&rf95);
+        let fsk = modes.iter().position(|&m| m == MODE_STDBY).unwrap();
+        assert_eq!(modes[fsk - 1], MODE_SLEEP);
+        assert_eq!(modes.last(), Some(&(MODE_STDBY | LONG_RANGE_MODE)));
+    }
+
+    #[test]
+    fn cw_ticks() {
+        let cw = CwConfig {
+            frequency: 434.25,
+            wpm: 40,
+        };
+        // dots, 2.1s
+        let elements: Vec<(bool, u32)> = (0..70).map(|n| (n % 2 == 0, 1)).collect();
+        let mut rf95 = radio();
+        let mut ticks = 0;
+        rf95.send_cw(&cw, &elements, &mut || ticks += 1).unwrap();
+        assert_eq!(ticks, 2);
+    }
+
+    // 7N2 "AU": start bit (space), 7 data bits LSB first, 2 stop bits (mark)
+    const AU_7N2: [bool; 20] = [
+        false, true, false, false, false, false, false, true, true, true,
+        false, true, false, true, false, true, false, true, true, true,
+    ];
+
+    fn check_rtty(baud: u32) {
+        let rtty = RttyConfig {
+            frequency: 434.25,
+            baud,
+            shift: 450,
+            bits: 7,
+            stop_bits: 2,
+            preamble: 0,
+        };
+        let mut rf95 = radio();
+        rf95.send_rtty(&rtty, b"AU", &mut || {}).unwrap();
+
+        let carrier = rtty.frequency as f64 * 1e6;
+        let mark = RF95::<MockSpi>::frf(carrier + 225.0);
+        let space = RF95::<MockSpi>::frf(carrier - 225.0);
+        assert!(mark > space);
+
+        // FSK TX mode, the symbols, standby
+        let writes = &rf95.spi.writes;
+        let tx = writes
+            .iter()
+            .position(|w| w.0 == Write::OpMode(MODE_TX))
+            .unwrap();
+        let end = tx + writes[tx..]
+            .iter()
+            .position(|w| w.0 == Write::OpMode(MODE_STDBY))
+            .unwrap();
+        // the lead tone is mark
+        assert_eq!(writes[tx - 1].0, Write::Frf(mark));
+
+        let symbols = &writes[tx + 1..end];
+        let expected: Vec<Write> = AU_7N2
+            .iter()
+            .map(|&m| Write::Frf(if m { mark } else { space }))
+            .collect();
+        assert_eq!(symbols.iter().map(|w| w.0).collect::<Vec<_>>(), expected);
+
+        // no bit starts early, and the bit period holds over the
+        // whole text, the margin is for the scheduler
+        let bit = Duration::from_micros(1000000 / baud as u64);
+        let start = writes[tx].1;
+        for (n, w) in symbols.iter().enumerate() {
+            let t = w.1.duration_since(start);
+            let slot = bit * n as u32;
+            assert!(t >= slot, "{} baud, bit {} at {:?}, before {:?}", baud, n, t, slot);
+        }
+        let total = writes[end].1.duration_since(start);
+        let expected = bit * symbols.len() as u32;
+        assert!(total >= expected, "{} baud, {:?} for {:?}", baud, total, expected);
+        assert!(
+            total < expected + Duration::from_millis(10),
+            "{} baud, {:?} for {:?}",
+            baud,
+            total,
+            expected
+        );
+    }
+
+    #[test]
+    fn rtty_7n2_50_baud() {
+        check_rtty(50);
+    }
+
+    #[test]
+    fn rtty_7n2_100_baud() {
+        check_rtty(100);
+    }
+
+    #[test]
+    fn rtty_7n2_300_baud() {
+        check_rtty(300);
     }
 }
@@ -0,0 +1,327 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Reed-Solomon (255,223) code with the CCSDS parameters, as used by
+// SSDV (Phil Karn's encode_rs_8, conventional basis). Shortened
+// blocks are handled with zero padding at the start.
+
+#![allow(dead_code)]
+
+// field generator polynomial, first consecutive root and
+// primitive element of the code roots
+const GF_POLY: u16 = 0x187;
+const FCR: usize = 112;
+const PRIM: usize = 11;
+// PRIM-th root of 1, IPRIM * PRIM = 1 mod 255
+const IPRIM: usize = 116;
+
+const NN: usize = 255;
+pub const NROOTS: usize = 32;
+// log of zero
+const A0: u8 = 255;
+
+pub struct ReedSolomon {
+    alpha_to: [u8; 256],
+    index_of: [u8; 256],
+    // generator polynomial, index form
+    genpoly: [u8; NROOTS + 1],
+}
+
+impl ReedSolomon {
+    pub fn new() -> Self {
+        let mut alpha_to = [0_u8; 256];
+        let mut index_of = [0_u8; 256];
+
+        index_of[0] = A0;
+        alpha_to[A0 as usize] = 0;
+        let mut sr: u16 = 1;
+        for (i, a) in alpha_to.iter_mut().take(NN).enumerate() {
+            index_of[sr as usize] = i as u8;
+            *a = sr as u8;
+            sr <<= 1;
+            if sr & 0x100 != 0 {
+                sr ^= GF_POLY;
+            }
+        }
+
+        let mut rs = Self {
+            alpha_to,
+            index_of,
+            genpoly: [0; NROOTS + 1],
+        };
+
+        // product of (x - alpha^(PRIM * (FCR + i)))
+        let mut genpoly = [0_u8; NROOTS + 1];
+        genpoly[0] = 1;
+        let mut root = FCR * PRIM;
+        for i in 0..NROOTS {
+            genpoly[i + 1] = 1;
+            for j in (1..=i).rev() {
+                genpoly[j] = if genpoly[j] != 0 {
+                    genpoly[j - 1] ^ rs.alpha(rs.index_of[genpoly[j] as usize] as usize + root)
+                } else {
+                    genpoly[j - 1]
+                };
+            }
+            genpoly[0] = rs.alpha(rs.index_of[genpoly[0] as usize] as usize + root);
+            root += PRIM;
+        }
+        for (g, p) in rs.genpoly.iter_mut().zip(genpoly.iter()) {
+            *g = index_of[*p as usize];
+        }
+
+        rs
+    }
+
+    fn alpha(&self, index: usize) -> u8 {
+        self.alpha_to[index % NN]
+    }
+
+    // parity bytes of up to 223 bytes of data
+    pub fn encode(&self, data: &[u8]) -> [u8; NROOTS] {
+        let mut parity = [0_u8; NROOTS];
+        for b in data {
+            let feedback = self.index_of[(b ^ parity[0]) as usize];
+            if feedback != A0 {
+                for (j, p) in parity.iter_mut().enumerate().skip(1) {
+                    *p ^= self.alpha(feedback as usize + self.genpoly[NROOTS - j] as usize);
+                }
+            }
+            parity.copy_within(1.., 0);
+            parity[NROOTS - 1] = if feedback != A0 {
+                self.alpha(feedback as usize + self.genpoly[0] as usize)
+            } else {
+                0
+            };
+        }
+        parity
+    }
+
+    // correct a block of data and parity bytes in place, returns
+    // the number of corrected bytes or Err if it can't be corrected
+    pub fn decode(&self, block: &mut [u8]) -> Result<usize, ()> {
+        if block.len() <= NROOTS || block.len() > NN {
+            return Err(());
+        }
+        let pad = NN - block.len();
+
+        // syndromes, evaluating the block at the roots
+        let mut s = [block[0]; NROOTS];
+        for b in block[1..].iter() {
+            for (i, si) in s.iter_mut().enumerate() {
+                *si = if *si == 0 {
+                    *b
+                } else {
+                    b ^ self.alpha(self.index_of[*si as usize] as usize + (FCR + i) * PRIM)
+                };
+            }
+        }
+        if s.iter().all(|si| *si == 0) {
+            return Ok(0);
+        }
+        for si in s.iter_mut() {
+            *si = self.index_of[*si as usize];
+        }
+
+        // Berlekamp-Massey, error locator polynomial lambda
+        let mut lambda = [0_u8; NROOTS + 1];
+        lambda[0] = 1;
+        let mut b = [A0; NROOTS + 1];
+        b[0] = 0;
+        let mut el = 0;
+        for r in 1..=NROOTS {
+            let mut discr: u8 = 0;
+            for i in 0..r {
+                if lambda[i] != 0 && s[r - i - 1] != A0 {
+                    discr ^= self.alpha(
+                        self.index_of[lambda[i] as usize] as usize + s[r - i - 1] as usize,
+                    );
+                }
+            }
+            let discr = self.index_of[discr as usize];
+
+            if discr == A0 {
+                b.copy_within(..NROOTS, 1);
+                b[0] = A0;
+                continue;
+            }
+
+            let mut t = [0_u8; NROOTS + 1];
+            t[0] = lambda[0];
+            for i in 0..NROOTS {
+                t[i + 1] = if b[i] != A0 {
+                    lambda[i + 1] ^ self.alpha(discr as usize + b[i] as usize)
+                } else {
+                    lambda[i + 1]
+                };
+            }
+            if 2 * el < r {
+                el = r - el;
+                for i in 0..=NROOTS {
+                    b[i] = if lambda[i] == 0 {
+                        A0
+                    } else {
+                        ((self.index_of[lambda[i] as usize] as usize + NN - discr as usize) % NN)
+                            as u8
+                    };
+                }
+            } else {
+                b.copy_within(..NROOTS, 1);
+                b[0] = A0;
+            }
+            lambda = t;
+        }
+
+        let mut deg_lambda = 0;
+        for (i, l) in lambda.iter_mut().enumerate() {
+            *l = self.index_of[*l as usize];
+            if *l != A0 {
+                deg_lambda = i;
+            }
+        }
+
+        // Chien search for the roots of lambda, the error locations
+        let mut reg = lambda;
+        let mut roots = Vec::new();
+        let mut locations = Vec::new();
+        for i in 1..=NN {
+            let mut q: u8 = 1;
+            for (j, rj) in reg.iter_mut().enumerate().take(deg_lambda + 1).skip(1) {
+                if *rj != A0 {
+                    *rj = ((*rj as usize + j) % NN) as u8;
+                    q ^= self.alpha_to[*rj as usize];
+                }
+            }
+            if q != 0 {
+                continue;
+            }
+            roots.push(i);
+            locations.push((IPRIM - 1 + (i - 1) * IPRIM) % NN);
+            if roots.len() == deg_lambda {
+                break;
+            }
+        }
+        if roots.len() != deg_lambda {
+            return Err(());
+        }
+
+        // error evaluator omega = s * lambda mod x^NROOTS
+        let deg_omega = deg_lambda - 1;
+        let mut omega = [A0; NROOTS + 1];
+        for i in 0..=deg_omega {
+            let mut tmp: u8 = 0;
+            for j in 0..=i {
+                if s[i - j] != A0 && lambda[j] != A0 {
+                    tmp ^= self.alpha(s[i - j] as usize + lambda[j] as usize);
+                }
+            }
+            omega[i] = self.index_of[tmp as usize];
+        }
+
+        // Forney, error values
+        for (root, loc) in roots.iter().zip(locations.iter()) {
+            let mut num1: u8 = 0;
+            for (i, o) in omega.iter().enumerate().take(deg_omega + 1) {
+                if *o != A0 {
+                    num1 ^= self.alpha(*o as usize + i * root);
+                }
+            }
+            let num2 = self.alpha(root * (FCR - 1) + NN);
+            let mut den: u8 = 0;
+            let mut i = deg_lambda.min(NROOTS - 1) & !1;
+            loop {
+                if lambda[i + 1] != A0 {
+                    den ^= self.alpha(lambda[i + 1] as usize + i * root);
+                }
+                if i < 2 {
+                    break;
+                }
+                i -= 2;
+            }
+            if num1 == 0 {
+                continue;
+            }
+            // an error in the padding means the block is too damaged
+            if *loc < pad || den == 0 {
+                return Err(());
+            }
+            block[loc - pad] ^= self.alpha(
+                self.index_of[num1 as usize] as usize + self.index_of[num2 as usize] as usize + NN
+                    - self.index_of[den as usize] as usize,
+            );
+        }
+
+        Ok(roots.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // GF(256) multiplication, bit by bit with the CCSDS field polynomial
+    fn mul(mut a: u8, mut b: u8) -> u8 {
+        let mut p = 0;
+        while b != 0 {
+            if b & 1 != 0 {
+                p ^= a;
+            }
+            let carry = a & 0x80 != 0;
+            a <<= 1;
+            if carry {
+                a ^= (GF_POLY & 0xff) as u8;
+            }
+            b >>= 1;
+        }
+        p
+    }
+
+    fn pow(a: u8, n: usize) -> u8 {
+        (0..n).fold(1, |p, _| mul(p, a))
+    }
+
+    fn block(len: usize) -> Vec<u8> {
+        let data: Vec<u8> = (0..len).map(|i| (i * 89 + 7) as u8).collect();
+        let parity = ReedSolomon::new().encode(&data);
+        [&data[..], &parity[..]].concat()
+    }
+
+    #[test]
+    fn codewords_have_the_ccsds_roots() {
+        // alpha^(11 * (112 + i)), i = 0-31, are roots of every codeword,
+        // the first byte is the highest degree coefficient
+        for len in &[NN - NROOTS, 100] {
+            let codeword = block(*len);
+            for i in 0..NROOTS {
+                let root = pow(2, PRIM * (FCR + i) % NN);
+                let s = codeword.iter().fold(0, |s, c| mul(s, root) ^ c);
+                assert_eq!(s, 0, "root {}, {} bytes", i, len);
+            }
+        }
+    }
+
+    #[test]
+    fn corrects_16_errors() {
+        let rs = ReedSolomon::new();
+        let good = block(NN - NROOTS);
+        let mut bad = good.clone();
+        for i in 0..NROOTS / 2 {
+            bad[i * 15 + 3] ^= 0x5a;
+        }
+        assert_eq!(rs.decode(&mut bad), Ok(NROOTS / 2));
+        assert_eq!(bad, good);
+    }
+}
@@ -0,0 +1,347 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Simple cooperative scheduler for the mission tasks. Each task has its own
+// interval, priority and enabling conditions (flight phase, battery).
+
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use state::FlightPhase;
+
+// seconds between radio link status packets in the default schedule
+const STATUS_INTERVAL: u32 = 600;
+
+// CW beacon every 10 minutes, and every 2 minutes after
+// landing to help with the recovery
+const BEACON_INTERVAL: u32 = 600;
+const BEACON_LANDED_INTERVAL: u32 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TaskKind {
+    Telemetry,
+    Datalog,
+    Photo,
+    Ssdv,
+    Sensors,
+    Uplink,
+    Status,
+    Beacon,
+}
+
+impl fmt::Display for TaskKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            TaskKind::Telemetry => "telemetry",
+            TaskKind::Datalog => "datalog",
+            TaskKind::Photo => "photo",
+            TaskKind::Ssdv => "ssdv",
+            TaskKind::Sensors => "sensors",
+            TaskKind::Uplink => "uplink",
+            TaskKind::Status => "status",
+            TaskKind::Beacon => "beacon",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskConfig {
+    pub task: TaskKind,
+    // seconds between runs, 0 to run whenever there is work to do
+    pub interval: u32,
+    // higher priority tasks run first when several are due
+    pub priority: u8,
+    pub enabled: bool,
+    // flight phases where the task runs, empty for all of them
+    pub phases: Vec<FlightPhase>,
+    // minimum battery voltage to run the task, 0 for no limit
+    pub min_batt: f32,
+}
+
+impl ::std::default::Default for TaskConfig {
+    fn default() -> Self {
+        Self {
+            task: TaskKind::Telemetry,
+            interval: 0,
+            priority: 0,
+            enabled: true,
+            phases: Vec::new(),
+            min_batt: 0.0,
+        }
+    }
+}
+
+impl TaskConfig {
+    pub fn new(task: TaskKind, interval: u32, priority: u8) -> Self {
+        Self {
+            task,
+            interval,
+            priority,
+            ..Default::default()
+        }
+    }
+}
+
+// current mission status the tasks depend on
+pub struct Conditions {
+    pub phase: FlightPhase,
+    pub vbatt: f32,
+    pub ssdv_pending: bool,
+    pub ssdv_to_queue: bool,
+}
+
+struct Task {
+    conf: TaskConfig,
+    last_run: Option<Instant>,
+    blocked: Option<String>,
+}
+
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    events: Vec<String>,
+}
+
+impl Scheduler {
+    pub fn new(confs: Vec<TaskConfig>) -> Self {
+        let mut tasks: Vec<Task> = confs
+            .into_iter()
+            .filter(|c| c.enabled)
+            .map(|c| Task {
+                conf: c,
+                last_run: None,
+                blocked: None,
+            })
+            .collect();
+        // stable sort, same priority keeps configuration order
+        tasks.sort_by_key(|t| Reverse(t.conf.priority));
+
+        Self {
+            tasks,
+            events: Vec::new(),
+        }
+    }
+
+    // schedule used when the configuration file doesn't define one:
+    // telemetry every packet_delay seconds and a SSDV image every
+    // packet_repeat telemetry packets, plus a low rate link status
+    pub fn default_tasks(packet_delay: u32, packet_repeat: u32) -> Vec<TaskConfig> {
+        vec![
+            TaskConfig::new(TaskKind::Telemetry, packet_delay, 5),
+            TaskConfig::new(TaskKind::Datalog, packet_delay, 4),
+            TaskConfig::new(TaskKind::Photo, packet_delay * (packet_repeat + 1), 3),
+            TaskConfig::new(TaskKind::Ssdv, 0, 1),
+            TaskConfig::new(TaskKind::Status, STATUS_INTERVAL, 0),
+        ]
+    }
+
+    // CW identification beacon tasks added to the default schedule when enabled
+    pub fn beacon_tasks() -> Vec<TaskConfig> {
+        vec![
+            TaskConfig {
+                phases: vec![
+                    FlightPhase::Ground,
+                    FlightPhase::Ascent,
+                    FlightPhase::Float,
+                    FlightPhase::Descent,
+                ],
+                ..TaskConfig::new(TaskKind::Beacon, BEACON_INTERVAL, 2)
+            },
+            TaskConfig {
+                phases: vec![FlightPhase::Landed],
+                ..TaskConfig::new(TaskKind::Beacon, BEACON_LANDED_INTERVAL, 2)
+            },
+        ]
+    }
+
+    pub fn tasks(&self) -> Vec<&TaskConfig> {
+        self.tasks.iter().map(|t| &t.conf).collect()
+    }
+
+    // returns the highest priority task that is due and allowed to run now
+    pub fn next(&mut self, now: Instant, cond: &Conditions) -> Option<TaskKind> {
+        let mut chosen = None;
+
+        for t in self.tasks.iter_mut() {
+            let due = match t.last_run {
+                Some(last) => {
+                    now.duration_since(last) >= Duration::from_secs(t.conf.interval as u64)
+                }
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+
+            // check the enabling conditions, and report changes
+            let blocked = Self::blocked_by(&t.conf, cond);
+            if blocked != t.blocked {
+                match blocked {
+                    Some(ref why) => self.events.push(format!("Task {} on hold: {}", t.conf.task, why)),
+                    None => self.events.push(format!("Task {} enabled", t.conf.task)),
+                }
+                t.blocked = blocked.clone();
+            }
+            if blocked.is_some() || !Self::has_work(t.conf.task, cond) {
+                continue;
+            }
+
+            if chosen.is_none() {
+                if t.conf.interval > 0 {
+                    let late = match t.last_run {
+                        Some(last) => now.duration_since(last).as_secs() - t.conf.interval as u64,
+                        None => 0,
+                    };
+                    self.events.push(format!("Running task {} ({}s late)", t.conf.task, late));
+                }
+                t.last_run = Some(now);
+                chosen = Some(t.conf.task);
+            }
+        }
+
+        chosen
+    }
+
+    // scheduler decisions to be logged
+    pub fn take_events(&mut self) -> Vec<String> {
+        self.events.drain(..).collect()
+    }
+
+    fn blocked_by(conf: &TaskConfig, cond: &Conditions) -> Option<String> {
+        if !conf.phases.is_empty() && !conf.phases.contains(&cond.phase) {
+            return Some(format!("flight phase {}", cond.phase));
+        }
+        if conf.min_batt > 0.0 && cond.vbatt < conf.min_batt {
+            return Some(format!("battery {:.2}V < {:.2}V", cond.vbatt, conf.min_batt));
+        }
+        None
+    }
+
+    // SSDV packets are only queued while there's an image and room in
+    // the radio queue, and new pictures wait until the previous one
+    // has been transmitted
+    fn has_work(task: TaskKind, cond: &Conditions) -> bool {
+        match task {
+            TaskKind::Ssdv => cond.ssdv_to_queue,
+            TaskKind::Photo => !cond.ssdv_pending,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cond(phase: FlightPhase, vbatt: f32) -> Conditions {
+        Conditions {
+            phase,
+            vbatt,
+            ssdv_pending: false,
+            ssdv_to_queue: false,
+        }
+    }
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn priority_and_interval() {
+        let mut s = Scheduler::new(vec![
+            TaskConfig::new(TaskKind::Datalog, 10, 4),
+            TaskConfig::new(TaskKind::Telemetry, 5, 5),
+        ]);
+        let c = cond(FlightPhase::Ascent, 4.0);
+        let t0 = Instant::now();
+        assert_eq!(s.next(t0, &c), Some(TaskKind::Telemetry));
+        assert_eq!(s.next(t0, &c), Some(TaskKind::Datalog));
+        assert_eq!(s.next(t0, &c), None);
+        assert_eq!(s.next(t0 + secs(5), &c), Some(TaskKind::Telemetry));
+        assert_eq!(s.next(t0 + secs(9), &c), None);
+        assert_eq!(s.next(t0 + secs(10), &c), Some(TaskKind::Telemetry));
+        assert_eq!(s.next(t0 + secs(10), &c), Some(TaskKind::Datalog));
+    }
+
+    #[test]
+    fn phase_gating() {
+        let mut s = Scheduler::new(Scheduler::beacon_tasks());
+        let t0 = Instant::now();
+        assert_eq!(s.next(t0, &cond(FlightPhase::Ground, 4.0)), Some(TaskKind::Beacon));
+        assert_eq!(s.next(t0 + secs(120), &cond(FlightPhase::Ascent, 4.0)), None);
+
+        // only the landed beacon runs after landing, every 2 minutes
+        let landed = cond(FlightPhase::Landed, 4.0);
+        assert_eq!(s.next(t0 + secs(600), &landed), Some(TaskKind::Beacon));
+        assert_eq!(s.next(t0 + secs(600), &landed), None);
+        assert_eq!(s.next(t0 + secs(720), &landed), Some(TaskKind::Beacon));
+        let events = s.take_events();
+        assert!(events.contains(&"Task beacon on hold: flight phase landed".to_string()));
+    }
+
+    #[test]
+    fn battery_gating() {
+        let mut s = Scheduler::new(vec![TaskConfig {
+            min_batt: 3.5,
+            ..TaskConfig::new(TaskKind::Photo, 0, 3)
+        }]);
+        let t0 = Instant::now();
+        assert_eq!(s.next(t0, &cond(FlightPhase::Float, 3.4)), None);
+        assert_eq!(
+            s.take_events(),
+            vec!["Task photo on hold: battery 3.40V < 3.50V".to_string()]
+        );
+        // reported once
+        assert_eq!(s.next(t0, &cond(FlightPhase::Float, 3.4)), None);
+        assert!(s.take_events().is_empty());
+
+        assert_eq!(s.next(t0, &cond(FlightPhase::Float, 3.6)), Some(TaskKind::Photo));
+        assert_eq!(s.take_events(), vec!["Task photo enabled".to_string()]);
+    }
+
+    #[test]
+    fn waits_for_work() {
+        let mut s = Scheduler::new(vec![
+            TaskConfig::new(TaskKind::Photo, 0, 3),
+            TaskConfig::new(TaskKind::Ssdv, 0, 1),
+        ]);
+        let c = Conditions {
+            phase: FlightPhase::Ascent,
+            vbatt: 4.0,
+            ssdv_pending: true,
+            ssdv_to_queue: true,
+        };
+        assert_eq!(s.next(Instant::now(), &c), Some(TaskKind::Ssdv));
+        let c = Conditions {
+            ssdv_pending: false,
+            ssdv_to_queue: false,
+            ..c
+        };
+        assert_eq!(s.next(Instant::now(), &c), Some(TaskKind::Photo));
+    }
+
+    #[test]
+    fn disabled_tasks() {
+        let s = Scheduler::new(vec![TaskConfig {
+            enabled: false,
+            ..TaskConfig::new(TaskKind::Uplink, 60, 2)
+        }]);
+        assert!(s.tasks().is_empty());
+    }
+}
@@ -14,22 +14,63 @@
 // along with this program.
 // If not, see <http://www.gnu.org/licenses/>.
 
-// Uses the SSDV binary to encode pictures to be sent over a radio link
+// SSDV encoder, produces the same packet format as the ssdv program
+// (https://github.com/fsphil/ssdv) to send pictures over a radio link.
+//
+// Packet: sync, type, callsign (base 40), image ID, packet ID,
+// width and height in MCUs, flags, offset and index of the first MCU
+// starting in the packet, payload, CRC32 and 32 Reed-Solomon bytes
+// (not in no-FEC packets). The payload is the baseline JPEG entropy
+// coded data (standard Huffman tables, no byte stuffing) of 16x16
+// YCbCr 4:2:0 MCUs. The first MCU starting in a packet is byte
+// aligned and its DC coefficients are absolute, so every packet
+// can be decoded on its own.
+
+#![allow(dead_code)]
 
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::process::{Command, Stdio};
+use std::io::{BufReader, ErrorKind, SeekFrom};
+
+extern crate image;
+use image::RgbImage;
 
-// ssdv program
-// https://github.com/fsphil/ssdv
-static SSDV_PROGRAM: &'static str = "ssdv";
+use jpeg::*;
+use rs8::*;
+
+// packet format
+pub const SYNC_BYTE: u8 = 0x55;
+pub const TYPE_FEC: u8 = 0x66;
+pub const TYPE_NOFEC: u8 = 0x67;
+pub const PACKET_LEN: usize = 256;
+// shorter packets would spend most of the airtime on the header
+const MIN_PAYLOAD_LEN: usize = 16;
+pub const HEADER_LEN: usize = 15;
+pub const CRC_LEN: usize = 4;
+pub const FEC_LEN: usize = NROOTS;
+pub const NO_MCU_OFFSET: u8 = 0xff;
+pub const NO_MCU_ID: u16 = 0xffff;
+pub const FLAG_EOI: u8 = 0x04;
+// 2x2 chroma subsampling, 16x16 MCUs
+pub const MCU_MODE_420: u8 = 0x00;
+pub const MCU_SIZE: u32 = 16;
+
+// quality levels 0-7, the JPEG quality factor of each
+pub const DEFAULT_QUALITY: u8 = 4;
+pub const QUALITY_FACTOR: [u8; 8] = [13, 18, 29, 43, 50, 71, 86, 100];
 
 // Errors
 #[derive(Debug)]
 pub enum SSDVErrorType {
-    External,
+    Image,
+    Size,
+    Options,
+    Sync,
+    Type,
+    Header,
+    Crc,
+    Truncated,
     IO,
     Access,
 }
@@ -43,6 +84,452 @@ impl SSDVError {
     pub fn new(e: SSDVErrorType) -> Self {
         Self { error_type: e }
     }
+
+    pub fn error_type(&self) -> &SSDVErrorType {
+        &self.error_type
+    }
+}
+
+// largest base 40 callsign, 6 characters
+const MAX_CALLSIGN: u32 = 40 * 40 * 40 * 40 * 40 * 40;
+
+// callsign in base 40, up to 6 characters, unknown characters
+// are encoded as spaces
+pub fn encode_callsign(callsign: &str) -> u32 {
+    let mut code: u32 = 0;
+    for c in callsign.chars().take(6).collect::<Vec<char>>().iter().rev() {
+        code *= 40;
+        code += match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32 + 14,
+            c @ '0'..='9' => c as u32 - '0' as u32 + 1,
+            _ => 0,
+        };
+    }
+    code
+}
+
+pub fn decode_callsign(code: u32) -> String {
+    let mut callsign = String::new();
+    let mut code = code;
+    while code > 0 {
+        let c = code % 40;
+        callsign.push(match c {
+            1..=10 => (b'0' + c as u8 - 1) as char,
+            14..=39 => (b'A' + c as u8 - 14) as char,
+            _ => '-',
+        });
+        code /= 40;
+    }
+    callsign
+}
+
+// payload bytes of a packet
+pub fn payload_len(packet_len: usize, fec: bool) -> usize {
+    packet_len - HEADER_LEN - CRC_LEN - if fec { FEC_LEN } else { 0 }
+}
+
+// check the encoding options: quality level 0-7 and packet length
+// up to 256 bytes, with at least MIN_PAYLOAD_LEN bytes of payload
+pub fn check_options(quality: u8, fec: bool, packet_len: usize) -> Result<(), &'static str> {
+    if quality > 7 {
+        return Err("SSDV quality must be 0-7");
+    }
+    let overhead = HEADER_LEN + CRC_LEN + if fec { FEC_LEN } else { 0 };
+    if packet_len > PACKET_LEN || packet_len < overhead + MIN_PAYLOAD_LEN {
+        return Err("SSDV packet length too short or longer than 256 bytes");
+    }
+    Ok(())
+}
+
+// quality level of the packet flags
+pub fn flags_quality(flags: u8) -> u8 {
+    ((flags >> 3) & 0x07) ^ 0x04
+}
+
+// CRC32 (zlib)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffff_ffff;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 0x01 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub struct Encoder {
+    pub callsign: u32,
+    pub image_id: u8,
+    // 0-7
+    pub quality: u8,
+    pub fec: bool,
+    pub packet_len: usize,
+    rs: ReedSolomon,
+}
+
+impl Encoder {
+    pub fn new(callsign: &str, image_id: u8) -> Self {
+        Self {
+            callsign: encode_callsign(callsign),
+            image_id,
+            quality: DEFAULT_QUALITY,
+            fec: true,
+            packet_len: PACKET_LEN,
+            rs: ReedSolomon::new(),
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        payload_len(self.packet_len, self.fec)
+    }
+
+    // encode a picture, its size is padded to whole MCUs
+    // repeating the last row and column
+    pub fn encode(&self, img: &RgbImage) -> Result<Vec<Vec<u8>>, SSDVError> {
+        if check_options(self.quality, self.fec, self.packet_len).is_err() {
+            return Err(SSDVError::new(SSDVErrorType::Options));
+        }
+        let (width, height) = img.dimensions();
+        let mcu_w = width.div_ceil(MCU_SIZE);
+        let mcu_h = height.div_ceil(MCU_SIZE);
+        if width == 0 || height == 0 || mcu_w > 255 || mcu_h > 255 {
+            return Err(SSDVError::new(SSDVErrorType::Size));
+        }
+
+        let factor = QUALITY_FACTOR[self.quality as usize];
+        let luminance_qt = quant_table(false, factor);
+        let chrominance_qt = quant_table(true, factor);
+        let luminance = ComponentEncoder::luminance();
+        let chrominance = ComponentEncoder::chrominance();
+
+        let mut writer = PacketWriter::new(self, mcu_w as u8, mcu_h as u8);
+        let mut y = [0_f32; 64 * 4];
+        let mut cb = [0_f32; 64];
+        let mut cr = [0_f32; 64];
+
+        for mcu in 0..mcu_w * mcu_h {
+            let x0 = (mcu % mcu_w) * MCU_SIZE;
+            let y0 = (mcu / mcu_w) * MCU_SIZE;
+
+            // colour conversion, chroma averaged over 2x2 pixels
+            cb.iter_mut().for_each(|v| *v = 0.0);
+            cr.iter_mut().for_each(|v| *v = 0.0);
+            for py in 0..MCU_SIZE {
+                for px in 0..MCU_SIZE {
+                    let p = img.get_pixel((x0 + px).min(width - 1), (y0 + py).min(height - 1));
+                    let (l, b, r) = rgb_to_ycbcr(p[0], p[1], p[2]);
+                    let block = (py / 8) * 2 + px / 8;
+                    y[(block * 64 + (py % 8) * 8 + px % 8) as usize] = l;
+                    let c = ((py / 2) * 8 + px / 2) as usize;
+                    cb[c] += b / 4.0;
+                    cr[c] += r / 4.0;
+                }
+            }
+
+            let mut blocks = Vec::with_capacity(6);
+            for b in 0..4 {
+                let mut block = [0_f32; 64];
+                block.copy_from_slice(&y[b * 64..(b + 1) * 64]);
+                blocks.push(fdct_quantise(&block, &luminance_qt));
+            }
+            blocks.push(fdct_quantise(&cb, &chrominance_qt));
+            blocks.push(fdct_quantise(&cr, &chrominance_qt));
+
+            writer.start_mcu(mcu as u16);
+            for (i, block) in blocks.iter().enumerate() {
+                let (tables, component) = match i {
+                    0..=3 => (&luminance, 0),
+                    4 => (&chrominance, 1),
+                    _ => (&chrominance, 2),
+                };
+                let mut predictor = writer.dc[component];
+                tables.write_block(&mut writer.bits, block, &mut predictor);
+                writer.dc[component] = predictor;
+            }
+            writer.end_mcu();
+        }
+
+        Ok(writer.finish())
+    }
+
+    // complete packet with header, CRC and FEC
+    fn packet(
+        &self,
+        packet_id: u16,
+        size: (u8, u8),
+        mcu: (u8, u16),
+        eoi: bool,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut p = vec![0_u8; self.packet_len];
+        p[0] = SYNC_BYTE;
+        p[1] = if self.fec { TYPE_FEC } else { TYPE_NOFEC };
+        p[2..6].copy_from_slice(&self.callsign.to_be_bytes());
+        p[6] = self.image_id;
+        p[7..9].copy_from_slice(&packet_id.to_be_bytes());
+        p[9] = size.0;
+        p[10] = size.1;
+        p[11] = ((self.quality ^ 0x04) & 0x07) << 3
+            | if eoi { FLAG_EOI } else { 0 }
+            | MCU_MODE_420;
+        p[12] = mcu.0;
+        p[13..15].copy_from_slice(&mcu.1.to_be_bytes());
+        p[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+        let crc_pos = HEADER_LEN + self.payload_len();
+        let crc = crc32(&p[1..crc_pos]);
+        p[crc_pos..crc_pos + CRC_LEN].copy_from_slice(&crc.to_be_bytes());
+
+        if self.fec {
+            let fec_pos = crc_pos + CRC_LEN;
+            let parity = self.rs.encode(&p[1..fec_pos]);
+            p[fec_pos..].copy_from_slice(&parity);
+        }
+        p
+    }
+}
+
+// splits the entropy coded data in packets
+struct PacketWriter<'a> {
+    encoder: &'a Encoder,
+    size: (u8, u8),
+    bits: BitWriter,
+    // DC predictors of Y, Cb and Cr
+    dc: [i16; 3],
+    mcu_offset: u8,
+    mcu_id: u16,
+    packets: Vec<Vec<u8>>,
+}
+
+impl<'a> PacketWriter<'a> {
+    fn new(encoder: &'a Encoder, width: u8, height: u8) -> Self {
+        Self {
+            encoder,
+            size: (width, height),
+            bits: BitWriter::new(),
+            dc: [0; 3],
+            mcu_offset: NO_MCU_OFFSET,
+            mcu_id: NO_MCU_ID,
+            packets: Vec::new(),
+        }
+    }
+
+    // the first MCU of a packet is byte aligned and resets the
+    // DC predictors
+    fn start_mcu(&mut self, mcu: u16) {
+        if self.mcu_id != NO_MCU_ID {
+            return;
+        }
+        self.bits.align();
+        let len = self.encoder.payload_len();
+        while self.bits.bytes.len() >= len {
+            self.flush();
+        }
+        self.mcu_offset = self.bits.bytes.len() as u8;
+        self.mcu_id = mcu;
+        self.dc = [0; 3];
+    }
+
+    // send the full packets, keep the last one until we know
+    // if more data follows
+    fn end_mcu(&mut self) {
+        while self.bits.bytes.len() > self.encoder.payload_len() {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let len = self.encoder.payload_len();
+        let payload: Vec<u8> = self.bits.bytes.drain(..len).collect();
+        self.emit(&payload, false);
+    }
+
+    fn emit(&mut self, payload: &[u8], eoi: bool) {
+        let packet = self.encoder.packet(
+            self.packets.len() as u16,
+            self.size,
+            (self.mcu_offset, self.mcu_id),
+            eoi,
+            payload,
+        );
+        self.packets.push(packet);
+        self.mcu_offset = NO_MCU_OFFSET;
+        self.mcu_id = NO_MCU_ID;
+    }
+
+    fn finish(mut self) -> Vec<Vec<u8>> {
+        self.bits.align();
+        let payload: Vec<u8> = self.bits.bytes.drain(..).collect();
+        self.emit(&payload, true);
+        self.packets
+    }
+}
+
+// A checked SSDV packet, with the sync byte
+pub struct SsdvPacket {
+    pub fec: bool,
+    pub callsign: u32,
+    pub image_id: u8,
+    pub packet_id: u16,
+    // MCUs
+    pub width: u8,
+    pub height: u8,
+    pub flags: u8,
+    pub mcu_offset: u8,
+    pub mcu_id: u16,
+    data: Vec<u8>,
+}
+
+impl SsdvPacket {
+    // parse a whole packet, its length is the packet length
+    pub fn parse(data: &[u8]) -> Result<Self, SSDVError> {
+        if data.len() < HEADER_LEN + CRC_LEN + 1 || data.len() > PACKET_LEN {
+            return Err(SSDVError::new(SSDVErrorType::Truncated));
+        }
+        if data[0] != SYNC_BYTE {
+            return Err(SSDVError::new(SSDVErrorType::Sync));
+        }
+        let fec = match data[1] {
+            TYPE_FEC => true,
+            TYPE_NOFEC => false,
+            _ => return Err(SSDVError::new(SSDVErrorType::Type)),
+        };
+        if fec && data.len() < HEADER_LEN + CRC_LEN + FEC_LEN + 1 {
+            return Err(SSDVError::new(SSDVErrorType::Truncated));
+        }
+
+        let len = payload_len(data.len(), fec);
+        let crc_pos = HEADER_LEN + len;
+        let crc = u32::from_be_bytes([
+            data[crc_pos],
+            data[crc_pos + 1],
+            data[crc_pos + 2],
+            data[crc_pos + 3],
+        ]);
+        if crc32(&data[1..crc_pos]) != crc {
+            return Err(SSDVError::new(SSDVErrorType::Crc));
+        }
+
+        let packet = Self {
+            fec,
+            callsign: u32::from_be_bytes([data[2], data[3], data[4], data[5]]),
+            image_id: data[6],
+            packet_id: u16::from_be_bytes([data[7], data[8]]),
+            width: data[9],
+            height: data[10],
+            flags: data[11],
+            mcu_offset: data[12],
+            mcu_id: u16::from_be_bytes([data[13], data[14]]),
+            data: data.to_vec(),
+        };
+
+        // only 16x16 MCUs are supported, the first MCU must be in
+        // the payload and the image
+        let mcus = packet.width as usize * packet.height as usize;
+        let mcu_ok = if packet.mcu_id == NO_MCU_ID {
+            packet.mcu_offset == NO_MCU_OFFSET
+        } else {
+            (packet.mcu_offset as usize) < len && (packet.mcu_id as usize) < mcus
+        };
+        if packet.callsign == 0
+            || packet.callsign >= MAX_CALLSIGN
+            || mcus == 0
+            || packet.flags & 0xc3 != MCU_MODE_420
+            || !mcu_ok
+        {
+            return Err(SSDVError::new(SSDVErrorType::Header));
+        }
+
+        Ok(packet)
+    }
+
+    pub fn callsign_string(&self) -> String {
+        decode_callsign(self.callsign)
+    }
+
+    // quality level 0-7
+    pub fn quality(&self) -> u8 {
+        flags_quality(self.flags)
+    }
+
+    // last packet of the image
+    pub fn eoi(&self) -> bool {
+        self.flags & FLAG_EOI != 0
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[HEADER_LEN..HEADER_LEN + payload_len(self.data.len(), self.fec)]
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    // the radio doesn't send the sync byte
+    pub fn radio_bytes(&self) -> &[u8] {
+        &self.data[1..]
+    }
+}
+
+// reads the packets of an encoded file one by one
+pub struct PacketReader {
+    reader: BufReader<File>,
+    packet_len: usize,
+    done: bool,
+}
+
+impl PacketReader {
+    pub fn open(filename: &str, packet_len: usize) -> Result<Self, SSDVError> {
+        match File::open(filename) {
+            Ok(f) => Ok(Self {
+                reader: BufReader::new(f),
+                packet_len,
+                done: false,
+            }),
+            Err(_e) => Err(SSDVError::new(SSDVErrorType::IO)),
+        }
+    }
+}
+
+impl Iterator for PacketReader {
+    type Item = Result<SsdvPacket, SSDVError>;
+
+    // None at the end of the file, a last partial packet
+    // is an error
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut buf = vec![0_u8; self.packet_len];
+        let mut n = 0;
+        while n < buf.len() {
+            match self.reader.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(r) => n += r,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_e) => {
+                    self.done = true;
+                    return Some(Err(SSDVError::new(SSDVErrorType::IO)));
+                }
+            }
+        }
+
+        if n == 0 {
+            self.done = true;
+            None
+        } else if n < buf.len() {
+            self.done = true;
+            Some(Err(SSDVError::new(SSDVErrorType::Truncated)))
+        } else {
+            Some(SsdvPacket::parse(&buf))
+        }
+    }
 }
 
 pub struct SSDV {
@@ -52,6 +539,12 @@ pub struct SSDV {
     filename: String,
     pub binaryname: String,
     pub packets: u64,
+    // encoding options, see check_options
+    pub quality: u8,
+    pub fec: bool,
+    pub packet_len: usize,
+    // encoded file, open for reading
+    file: Option<File>,
 }
 
 impl SSDV {
@@ -63,66 +556,187 @@ impl SSDV {
             filename: img,
             binaryname: p + &b + ".bin",
             packets: 0,
+            quality: DEFAULT_QUALITY,
+            fec: true,
+            packet_len: PACKET_LEN,
+            file: None,
         }
     }
 
     pub fn encode(&mut self) -> Result<(), SSDVError> {
-        let status = Command::new(SSDV_PROGRAM)
-            .arg("-e")
-            .arg("-c")
-            .arg(&self.id)
-            .arg("-i")
-            .arg(&format!("{}", self.count))
-            .arg(&self.filename)
-            .arg(&self.binaryname)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status();
-        let exit_code: i32;
-        match status {
-            Ok(s) => exit_code = s.code().unwrap(),
+        self.packets = 0;
+        self.file = None;
+
+        let img = match image::open(&self.filename) {
+            Ok(i) => i.to_rgb8(),
+            Err(_e) => return Err(SSDVError::new(SSDVErrorType::Image)),
+        };
+
+        let mut encoder = Encoder::new(&self.id, self.count);
+        encoder.quality = self.quality;
+        encoder.fec = self.fec;
+        encoder.packet_len = self.packet_len;
+        let packets = encoder.encode(&img)?;
+
+        let mut f = match File::create(&self.binaryname) {
+            Ok(f) => f,
             Err(_e) => return Err(SSDVError::new(SSDVErrorType::IO)),
+        };
+        for p in &packets {
+            if f.write_all(p).is_err() {
+                return Err(SSDVError::new(SSDVErrorType::IO));
+            }
+        }
+        if f.sync_all().is_err() {
+            return Err(SSDVError::new(SSDVErrorType::IO));
         }
 
-        // ssdv worked, get number of packets and return
-        if exit_code == 0 {
-            let _f = match fs::metadata(&self.binaryname) {
-                Ok(f) => {
-                    // get number of packets of the file
-                    self.packets = f.len() / 256;
-                    return Ok(());
-                }
-                Err(_e) => return Err(SSDVError::new(SSDVErrorType::IO)),
-            };
-        } else {
-            // exit code not 0
-            Err(SSDVError::new(SSDVErrorType::External))
+        // check the file has all the packets
+        let len = match fs::metadata(&self.binaryname) {
+            Ok(m) => m.len(),
+            Err(_e) => return Err(SSDVError::new(SSDVErrorType::IO)),
+        };
+        if len != (packets.len() * self.packet_len) as u64 {
+            return Err(SSDVError::new(SSDVErrorType::Truncated));
         }
+
+        self.file = match File::open(&self.binaryname) {
+            Ok(f) => Some(f),
+            Err(_e) => return Err(SSDVError::new(SSDVErrorType::IO)),
+        };
+        self.packets = packets.len() as u64;
+        Ok(())
     }
 
-    pub fn get_packet(&mut self, packet: u64) -> Result<[u8; 255], SSDVError> {
-        // return if no packets
-        if self.packets == 0 {
+    // packet number n (from 0) of the encoded file
+    pub fn get_packet(&mut self, packet: u64) -> Result<SsdvPacket, SSDVError> {
+        if packet >= self.packets {
             return Err(SSDVError::new(SSDVErrorType::Access));
         }
+        let f = match self.file {
+            Some(ref mut f) => f,
+            None => return Err(SSDVError::new(SSDVErrorType::Access)),
+        };
 
-        // return if invalid index
-        if packet > self.packets {
-            return Err(SSDVError::new(SSDVErrorType::Access));
+        if f.seek(SeekFrom::Start(packet * self.packet_len as u64)).is_err() {
+            return Err(SSDVError::new(SSDVErrorType::IO));
+        }
+        let mut buf = vec![0_u8; self.packet_len];
+        match f.read_exact(&mut buf) {
+            Ok(()) => SsdvPacket::parse(&buf),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                Err(SSDVError::new(SSDVErrorType::Truncated))
+            }
+            Err(_e) => Err(SSDVError::new(SSDVErrorType::IO)),
+        }
+    }
+
+    // all the packets of the encoded file
+    pub fn iter(&self) -> Result<PacketReader, SSDVError> {
+        PacketReader::open(&self.binaryname, self.packet_len)
+    }
+}
+
+// The known answers are worked out by hand from the SSDV packet
+// format and the T.81 Annex K tables. The packets are also checked
+// with the ssdv program when it's installed.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use std::env;
+    use std::process::Command;
+
+    fn grey(level: u8, width: u32, height: u32) -> RgbImage {
+        RgbImage::from_pixel(width, height, Rgb([level, level, level]))
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn callsign() {
+        // last character first: B, A, H, S, A
+        assert_eq!(encode_callsign("ASHAB"), 39330894);
+        assert_eq!(encode_callsign("ashab"), 39330894);
+        assert_eq!(decode_callsign(39330894), "ASHAB");
+        assert_eq!(encode_callsign("AB"), 15 * 40 + 14);
+    }
+
+    #[test]
+    fn header() {
+        let packets = Encoder::new("ASHAB", 7).encode(&grey(128, 32, 16)).unwrap();
+        assert_eq!(packets.len(), 1);
+        let p = &packets[0];
+        assert_eq!(p.len(), PACKET_LEN);
+        assert_eq!(
+            &p[..HEADER_LEN],
+            &[
+                0x55, 0x66, 0x02, 0x58, 0x24, 0x4e, // sync, type, callsign
+                7, 0, 0, // image, packet
+                2, 1, // MCUs
+                0x04, // quality 4, EOI, 4:2:0
+                0, 0, 0, // first MCU offset and index
+            ]
+        );
+
+        let crc_pos = PACKET_LEN - FEC_LEN - CRC_LEN;
+        assert_eq!(&p[crc_pos..crc_pos + CRC_LEN], &crc32(&p[1..crc_pos]).to_be_bytes());
+        let mut block = p[1..].to_vec();
+        assert_eq!(ReedSolomon::new().decode(&mut block), Ok(0));
+    }
+
+    #[test]
+    fn mid_grey_mcu() {
+        // all the coefficients are 0: luminance DC 00 and EOB 1010,
+        // chrominance DC 00 and EOB 00
+        let packets = Encoder::new("ASHAB", 0).encode(&grey(128, 16, 16)).unwrap();
+        assert_eq!(&packets[0][HEADER_LEN..HEADER_LEN + 4], &[0x28, 0xa2, 0x8a, 0x00]);
+    }
+
+    #[test]
+    fn grey_mcu() {
+        // Y 144, DC coefficient 8 * 16 quantised by 16: category 4 (101)
+        // and 1000 in the first block, then differences of 0,
+        // padded with ones
+        let packets = Encoder::new("ASHAB", 0).encode(&grey(144, 16, 16)).unwrap();
+        assert_eq!(
+            &packets[0][HEADER_LEN..HEADER_LEN + 5],
+            &[0xb1, 0x45, 0x14, 0x50, 0x07]
+        );
+    }
+
+    #[test]
+    fn ssdv_program_decodes_our_packets() {
+        if Command::new("ssdv").arg("-h").output().is_err() {
+            eprintln!("ssdv not installed, skipped");
+            return;
+        }
+
+        let mut img = RgbImage::new(64, 48);
+        for (x, y, p) in img.enumerate_pixels_mut() {
+            *p = Rgb([(x * 4) as u8, (y * 5) as u8, 128]);
         }
+        let packets = Encoder::new("ASHAB", 1).encode(&img).unwrap();
+
+        let dir = env::temp_dir();
+        let input = dir.join(format!("ssdv-test-{}.bin", std::process::id()));
+        let output = dir.join(format!("ssdv-test-{}.jpg", std::process::id()));
+        fs::write(&input, packets.concat()).unwrap();
+        let status = Command::new("ssdv").arg("-d").arg(&input).arg(&output).status().unwrap();
+        let decoded = image::open(&output).map(|i| i.to_rgb8());
+        let _ = fs::remove_file(&input);
+        let _ = fs::remove_file(&output);
 
-        // Ok, get packet
-        let mut _file = match File::open(&self.binaryname) {
-            Ok(mut f) => {
-                // move the cursor, we can unwrap as we already checked number of packets
-                // don't read first byte from packet (sync byte)
-                f.seek(SeekFrom::Start((packet * 256) + 1)).unwrap();
-                // read the buffer
-                let mut buf = [0; 255];
-                f.read(&mut buf).unwrap();
-                return Ok(buf);
+        assert!(status.success());
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.dimensions(), img.dimensions());
+        for (a, b) in decoded.pixels().zip(img.pixels()) {
+            for c in 0..3 {
+                assert!((a[c] as i16 - b[c] as i16).abs() < 24, "{:?} for {:?}", a, b);
             }
-            Err(_e) => return Err(SSDVError::new(SSDVErrorType::IO)),
-        };
+        }
     }
 }
@@ -0,0 +1,143 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Ground side SSDV decoder. Reads SSDV packets from a file, or from
+// the standard input as they are received, and writes the JPEG of
+// each image to the output directory as packets arrive.
+//
+// ssdvdecode [-l packet_length] <packets file | -> <output dir>
+
+extern crate image;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+mod jpeg;
+mod rs8;
+mod ssdv;
+use ssdv::*;
+mod ssdvrx;
+use ssdvrx::*;
+
+fn usage() -> ! {
+    eprintln!("Usage: ssdvdecode [-l packet_length] <packets file | -> <output dir>");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut packet_len = PACKET_LEN;
+    let mut files: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "-l" {
+            i += 1;
+            packet_len = match args.get(i).map(|l| l.parse::<usize>()) {
+                Some(Ok(l)) if check_options(DEFAULT_QUALITY, false, l).is_ok() => l,
+                _ => usage(),
+            };
+        } else {
+            files.push(args[i].clone());
+        }
+        i += 1;
+    }
+    if files.len() != 2 {
+        usage();
+    }
+
+    let mut input: Box<dyn Read> = if files[0] == "-" {
+        Box::new(io::stdin())
+    } else {
+        match File::open(&files[0]) {
+            Ok(f) => Box::new(f),
+            Err(e) => {
+                eprintln!("Can't open {}: {}", files[0], e);
+                process::exit(1);
+            }
+        }
+    };
+
+    let mut dir = files[1].clone();
+    if !dir.ends_with('/') {
+        dir.push('/');
+    }
+    let mut rx = Receiver::new(&dir, packet_len);
+    let mut errors = 0;
+    let mut write_errors = 0;
+    // end of the last rejected packet, the sync bytes found
+    // inside it are not new packets
+    let mut rejected_end = 0;
+
+    // look for the sync byte and try a packet from there,
+    // skipping one byte if it's not valid
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = [0_u8; 4096];
+    loop {
+        let n = match input.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("Read error: {}", e);
+                process::exit(1);
+            }
+        };
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut pos = 0;
+        while buf.len() - pos >= packet_len {
+            if buf[pos] != SYNC_BYTE {
+                pos += 1;
+                continue;
+            }
+            match rx.push(&buf[pos..pos + packet_len]) {
+                Ok(status) => {
+                    println!("{}", status);
+                    pos += packet_len;
+                }
+                // the packet was received, but the image can't be written
+                Err(ref e) if matches!(e.error_type(), SSDVErrorType::IO) => {
+                    eprintln!("Can't write the image to {}", dir);
+                    write_errors += 1;
+                    pos += packet_len;
+                }
+                Err(_e) => {
+                    if pos >= rejected_end {
+                        errors += 1;
+                        rejected_end = pos + packet_len;
+                    }
+                    pos += 1;
+                }
+            }
+        }
+        buf.drain(..pos);
+        rejected_end = rejected_end.saturating_sub(pos);
+    }
+
+    println!(
+        "Done, {} packets corrected, {} rejected, {} write errors.",
+        rx.corrected, errors, write_errors
+    );
+    for status in rx.status() {
+        println!(
+            "{} {}",
+            status,
+            if status.complete() { "complete" } else { "partial" }
+        );
+    }
+}
@@ -0,0 +1,427 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// SSDV receiver for the ground side. Packets are checked (and
+// corrected with the Reed-Solomon bytes if the CRC fails), grouped
+// in images by callsign and image ID, and each image is written as
+// a JPEG every time a packet arrives. MCUs of missing packets are
+// left grey.
+
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+
+extern crate image;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, RgbImage};
+
+use jpeg::*;
+use rs8::*;
+use ssdv::*;
+
+// quality of the written JPEGs
+const OUTPUT_QUALITY: u8 = 90;
+
+struct Image {
+    callsign: u32,
+    image_id: u8,
+    // MCUs
+    width: u8,
+    height: u8,
+    quality: u8,
+    packets: BTreeMap<u16, SsdvPacket>,
+    // ID of the packet with the EOI flag
+    last_packet: Option<u16>,
+    // decoded MCUs on the last write
+    mcus: usize,
+}
+
+impl Image {
+    fn total_mcus(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    fn filename(&self, dir: &str) -> String {
+        format!(
+            "{}{}_{:03}.jpg",
+            dir,
+            decode_callsign(self.callsign),
+            self.image_id
+        )
+    }
+
+    // coefficients of each MCU, None if not received
+    fn decode(&self) -> Vec<Option<Vec<[i16; 64]>>> {
+        let total = self.total_mcus();
+        let mut mcus = vec![None; total];
+        let luminance = ComponentDecoder::luminance();
+        let chrominance = ComponentDecoder::chrominance();
+
+        for (id, packet) in self.packets.iter() {
+            if packet.mcu_id == NO_MCU_ID {
+                continue;
+            }
+
+            // data from the first MCU of the packet to the first MCU
+            // of the next packet that has one, while there are no gaps
+            let mut data = packet.payload()[packet.mcu_offset as usize..].to_vec();
+            let mut next_mcu = total;
+            let mut next = *id as u32 + 1;
+            while next <= 0xffff {
+                let p = match self.packets.get(&(next as u16)) {
+                    Some(p) => p,
+                    None => break,
+                };
+                if p.mcu_id != NO_MCU_ID {
+                    data.extend_from_slice(&p.payload()[..p.mcu_offset as usize]);
+                    next_mcu = (p.mcu_id as usize).min(total);
+                    break;
+                }
+                data.extend_from_slice(p.payload());
+                next += 1;
+            }
+
+            let mut r = BitReader::new(&data);
+            let mut dc = [0_i16; 3];
+            for mcu in mcus
+                .iter_mut()
+                .take(next_mcu)
+                .skip(packet.mcu_id as usize)
+            {
+                let mut blocks = Vec::with_capacity(6);
+                for b in 0..6 {
+                    let (tables, component) = match b {
+                        0..=3 => (&luminance, 0),
+                        4 => (&chrominance, 1),
+                        _ => (&chrominance, 2),
+                    };
+                    match tables.read_block(&mut r, &mut dc[component]) {
+                        Some(block) => blocks.push(block),
+                        None => break,
+                    }
+                }
+                // the data ends in the middle of this MCU
+                if blocks.len() < 6 {
+                    break;
+                }
+                *mcu = Some(blocks);
+            }
+        }
+
+        mcus
+    }
+
+    fn render(&self, mcus: &[Option<Vec<[i16; 64]>>]) -> RgbImage {
+        let factor = QUALITY_FACTOR[self.quality as usize];
+        let luminance_qt = quant_table(false, factor);
+        let chrominance_qt = quant_table(true, factor);
+        let w = self.width as u32;
+        let mut img = RgbImage::new(w * MCU_SIZE, self.height as u32 * MCU_SIZE);
+
+        for (i, mcu) in mcus.iter().enumerate() {
+            let x0 = (i as u32 % w) * MCU_SIZE;
+            let y0 = (i as u32 / w) * MCU_SIZE;
+            let blocks = match *mcu {
+                Some(ref b) => b,
+                None => {
+                    for y in 0..MCU_SIZE {
+                        for x in 0..MCU_SIZE {
+                            img.put_pixel(x0 + x, y0 + y, image::Rgb([128, 128, 128]));
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let mut y = Vec::with_capacity(4);
+            for block in blocks.iter().take(4) {
+                y.push(dequantise_idct(block, &luminance_qt));
+            }
+            let cb = dequantise_idct(&blocks[4], &chrominance_qt);
+            let cr = dequantise_idct(&blocks[5], &chrominance_qt);
+
+            for py in 0..MCU_SIZE as usize {
+                for px in 0..MCU_SIZE as usize {
+                    let l = y[(py / 8) * 2 + px / 8][(py % 8) * 8 + px % 8];
+                    let c = (py / 2) * 8 + px / 2;
+                    img.put_pixel(
+                        x0 + px as u32,
+                        y0 + py as u32,
+                        image::Rgb(ycbcr_to_rgb(l, cb[c], cr[c])),
+                    );
+                }
+            }
+        }
+
+        img
+    }
+
+    fn status(&self, dir: &str) -> ImageStatus {
+        ImageStatus {
+            callsign: decode_callsign(self.callsign),
+            image_id: self.image_id,
+            packets: self.packets.len(),
+            total_packets: self.last_packet.map(|p| p as usize + 1),
+            mcus: self.mcus,
+            total_mcus: self.total_mcus(),
+            filename: self.filename(dir),
+        }
+    }
+}
+
+// completeness of a received image
+pub struct ImageStatus {
+    pub callsign: String,
+    pub image_id: u8,
+    pub packets: usize,
+    // known when the last packet arrives
+    pub total_packets: Option<usize>,
+    pub mcus: usize,
+    pub total_mcus: usize,
+    pub filename: String,
+}
+
+impl ImageStatus {
+    pub fn complete(&self) -> bool {
+        self.mcus == self.total_mcus
+    }
+}
+
+impl fmt::Display for ImageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = match self.total_packets {
+            Some(t) => format!("{}", t),
+            None => "?".to_string(),
+        };
+        write!(
+            f,
+            "{} image {}: {}/{} packets, {}/{} MCUs ({}%) -> {}",
+            self.callsign,
+            self.image_id,
+            self.packets,
+            total,
+            self.mcus,
+            self.total_mcus,
+            self.mcus * 100 / self.total_mcus.max(1),
+            self.filename
+        )
+    }
+}
+
+pub struct Receiver {
+    // output directory, with the trailing separator
+    dir: String,
+    packet_len: usize,
+    rs: ReedSolomon,
+    images: Vec<Image>,
+    // packets fixed by the Reed-Solomon code
+    pub corrected: u64,
+}
+
+impl Receiver {
+    pub fn new(dir: &str, packet_len: usize) -> Self {
+        Self {
+            dir: dir.to_string(),
+            packet_len,
+            rs: ReedSolomon::new(),
+            images: Vec::new(),
+            corrected: 0,
+        }
+    }
+
+    // check a packet, with or without the sync byte (the radio
+    // doesn't send it), add it to its image and write the image
+    pub fn push(&mut self, data: &[u8]) -> Result<ImageStatus, SSDVError> {
+        let mut p = Vec::with_capacity(self.packet_len);
+        if data.len() == self.packet_len - 1 {
+            p.push(SYNC_BYTE);
+        }
+        p.extend_from_slice(data);
+        if p.len() != self.packet_len {
+            return Err(SSDVError::new(SSDVErrorType::Truncated));
+        }
+
+        // if the packet is wrong try to correct it, even if the
+        // type doesn't say FEC, it could be the wrong byte
+        let packet = match SsdvPacket::parse(&p) {
+            Ok(packet) => packet,
+            Err(e) => {
+                if self.rs.decode(&mut p[1..]).is_err() {
+                    return Err(e);
+                }
+                let packet = SsdvPacket::parse(&p)?;
+                self.corrected += 1;
+                packet
+            }
+        };
+        let (callsign, image_id) = (packet.callsign, packet.image_id);
+        let (width, height) = (packet.width, packet.height);
+
+        // a different size means the image ID was reused
+        let index = match self
+            .images
+            .iter()
+            .position(|i| i.callsign == callsign && i.image_id == image_id)
+        {
+            Some(i) if self.images[i].width == width && self.images[i].height == height => i,
+            found => {
+                if let Some(i) = found {
+                    self.images.remove(i);
+                }
+                self.images.push(Image {
+                    callsign,
+                    image_id,
+                    width,
+                    height,
+                    quality: packet.quality(),
+                    packets: BTreeMap::new(),
+                    last_packet: None,
+                    mcus: 0,
+                });
+                self.images.len() - 1
+            }
+        };
+
+        let dir = self.dir.clone();
+        let img = &mut self.images[index];
+        if packet.eoi() {
+            img.last_packet = Some(packet.packet_id);
+        }
+        img.packets.insert(packet.packet_id, packet);
+
+        let mcus = img.decode();
+        img.mcus = mcus.iter().filter(|m| m.is_some()).count();
+        let picture = img.render(&mcus);
+        write_jpeg(&img.filename(&dir), &picture)?;
+
+        Ok(img.status(&dir))
+    }
+
+    pub fn status(&self) -> Vec<ImageStatus> {
+        self.images.iter().map(|i| i.status(&self.dir)).collect()
+    }
+}
+
+fn write_jpeg(filename: &str, img: &RgbImage) -> Result<(), SSDVError> {
+    let mut f = match File::create(filename) {
+        Ok(f) => f,
+        Err(_e) => return Err(SSDVError::new(SSDVErrorType::IO)),
+    };
+    let mut encoder = JpegEncoder::new_with_quality(&mut f, OUTPUT_QUALITY);
+    match encoder.encode(img, img.width(), img.height(), ColorType::Rgb8) {
+        Ok(()) => Ok(()),
+        Err(_e) => Err(SSDVError::new(SSDVErrorType::IO)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use std::env;
+    use std::fs;
+
+    fn gradient() -> RgbImage {
+        let mut img = RgbImage::new(320, 240);
+        for (x, y, p) in img.enumerate_pixels_mut() {
+            *p = Rgb([(x * 255 / 319) as u8, (y * 255 / 239) as u8, 128]);
+        }
+        img
+    }
+
+    #[test]
+    fn round_trip() {
+        let img = gradient();
+        let mut packets = Encoder::new("ASHAB", 3).encode(&img).unwrap();
+        assert!(packets.len() > 1);
+        // the radio doesn't send the sync byte, and a few bytes are wrong
+        packets[0].remove(0);
+        for i in 0..8 {
+            packets[1][20 + i * 9] ^= 0xff;
+        }
+
+        let dir = env::temp_dir().join(format!("ssdvrx-test-{}/", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut rx = Receiver::new(dir.to_str().unwrap(), PACKET_LEN);
+        let mut status = None;
+        for p in &packets {
+            status = Some(rx.push(p).unwrap());
+        }
+        let status = status.unwrap();
+        let decoded = image::open(&status.filename).map(|i| i.to_rgb8());
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(rx.corrected, 1);
+        assert!(status.complete());
+        assert_eq!(status.total_packets, Some(packets.len()));
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.dimensions(), img.dimensions());
+        for (a, b) in decoded.pixels().zip(img.pixels()) {
+            for c in 0..3 {
+                assert!((a[c] as i16 - b[c] as i16).abs() < 24, "{:?} for {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn missing_packet() {
+        let img = gradient();
+        let mut packets = Encoder::new("ASHAB", 4).encode(&img).unwrap();
+        let k = packets.len() / 2;
+        let dropped = SsdvPacket::parse(&packets[k]).unwrap();
+        let next = SsdvPacket::parse(&packets[k + 1]).unwrap();
+        assert!(dropped.mcu_id != NO_MCU_ID && next.mcu_id != NO_MCU_ID);
+        packets.remove(k);
+
+        let dir = env::temp_dir().join(format!("ssdvrx-missing-{}/", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut rx = Receiver::new(dir.to_str().unwrap(), PACKET_LEN);
+        let mut status = None;
+        for p in &packets {
+            status = Some(rx.push(p).unwrap());
+        }
+        let status = status.unwrap();
+        let decoded = image::open(&status.filename).map(|i| i.to_rgb8());
+        let _ = fs::remove_dir_all(&dir);
+
+        // the MCUs starting in the lost packet, and the one it continues
+        let first_lost = dropped.mcu_id as usize - (dropped.mcu_offset > 0) as usize;
+        let lost = first_lost..next.mcu_id as usize;
+        assert!(!status.complete());
+        assert_eq!(status.mcus, status.total_mcus - lost.len());
+
+        // grey where lost, the rest of the image decodes after the gap
+        let decoded = decoded.unwrap();
+        let w = img.width() / MCU_SIZE;
+        for mcu in 0..status.total_mcus {
+            let x0 = (mcu as u32 % w) * MCU_SIZE;
+            let y0 = (mcu as u32 / w) * MCU_SIZE;
+            for (x, y) in &[(0, 0), (MCU_SIZE - 1, MCU_SIZE - 1), (MCU_SIZE / 2, 3)] {
+                let a = decoded.get_pixel(x0 + x, y0 + y);
+                let b = img.get_pixel(x0 + x, y0 + y);
+                for c in 0..3 {
+                    if lost.contains(&mcu) {
+                        assert_eq!(a[c], 128, "MCU {}", mcu);
+                    } else {
+                        assert!((a[c] as i16 - b[c] as i16).abs() < 24, "MCU {}: {:?} for {:?}", mcu, a, b);
+                    }
+                }
+            }
+        }
+    }
+}
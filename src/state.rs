@@ -0,0 +1,202 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Mission state that must survive a reboot in the middle of the flight
+// (picture numbers, counters, flight phase...). Stored as a small TOML file.
+
+extern crate chrono;
+extern crate toml;
+
+use chrono::prelude::*;
+use dutycycle::DutyRecord;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+// ascent and descent rates (m/s) used to detect the flight phase
+const ASCENT_RATE: f32 = 2.0;
+const DESCENT_RATE: f32 = -3.0;
+const STILL_RATE: f32 = 0.5;
+// minimum altitude (m) to consider we are floating
+const FLOAT_MIN_ALT: f32 = 5000.0;
+// consecutive samples that must agree before changing phase
+const PHASE_SAMPLES: u32 = 3;
+// altitude gain (m) over the ground altitude to detect the launch
+const LAUNCH_MIN_GAIN: f32 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FlightPhase {
+    Ground,
+    Ascent,
+    Float,
+    Descent,
+    Landed,
+}
+
+impl fmt::Display for FlightPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            FlightPhase::Ground => "ground",
+            FlightPhase::Ascent => "ascent",
+            FlightPhase::Float => "float",
+            FlightPhase::Descent => "descent",
+            FlightPhase::Landed => "landed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MissionState {
+    pub picture_number: u8,
+    pub telemetry_count: u32,
+    pub max_altitude: f32,
+    pub launch_time: String,
+    pub phase: FlightPhase,
+    // duty-cycle history, so a reboot doesn't reset the budget
+    pub duty_history: Vec<DutyRecord>,
+    // phase detection, not saved
+    #[serde(skip)]
+    last_fix: bool,
+    #[serde(skip)]
+    ground_altitude: Option<f32>,
+    #[serde(skip)]
+    candidate: Option<FlightPhase>,
+    #[serde(skip)]
+    candidate_samples: u32,
+}
+
+impl ::std::default::Default for MissionState {
+    fn default() -> Self {
+        Self {
+            picture_number: 0,
+            telemetry_count: 0,
+            max_altitude: 0.0,
+            launch_time: "".to_string(),
+            phase: FlightPhase::Ground,
+            duty_history: Vec::new(),
+            last_fix: false,
+            ground_altitude: None,
+            candidate: None,
+            candidate_samples: 0,
+        }
+    }
+}
+
+impl MissionState {
+    // load state from file, None if there isn't a saved state
+    pub fn load(path: &str) -> Result<Option<Self>, io::Error> {
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut s = String::new();
+        f.read_to_string(&mut s)?;
+
+        match toml::from_str(&s) {
+            Ok(state) => Ok(Some(state)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    // write to a temporary file and rename it, so a power cut
+    // never leaves a half written state file
+    pub fn save(&self, path: &str) -> Result<(), io::Error> {
+        let data = match toml::to_string(self) {
+            Ok(d) => d,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+
+        let tmp = format!("{}.tmp", path);
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(data.as_bytes())?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // update max altitude and flight phase, returns the new phase if it changed.
+    // Samples without a GPS fix are ignored, and so is the first one after
+    // the fix is acquired, as its ascent rate comes from a wrong altitude.
+    // A new phase must be seen PHASE_SAMPLES times in a row, and the launch
+    // also needs LAUNCH_MIN_GAIN meters over the ground altitude.
+    pub fn update(&mut self, alt: f32, arate: f32, fix: bool) -> Option<FlightPhase> {
+        let valid = fix && self.last_fix;
+        self.last_fix = fix;
+        if !valid {
+            self.candidate = None;
+            self.candidate_samples = 0;
+            return None;
+        }
+
+        if alt > self.max_altitude {
+            self.max_altitude = alt;
+        }
+        if self.phase == FlightPhase::Ground {
+            let ground = self.ground_altitude.map_or(alt, |g| g.min(alt));
+            self.ground_altitude = Some(ground);
+        }
+
+        let next = match self.phase {
+            FlightPhase::Ground
+                if arate > ASCENT_RATE
+                    && alt - self.ground_altitude.unwrap_or(alt) >= LAUNCH_MIN_GAIN =>
+            {
+                FlightPhase::Ascent
+            }
+            FlightPhase::Ascent if arate < DESCENT_RATE => FlightPhase::Descent,
+            FlightPhase::Ascent if arate.abs() < STILL_RATE && alt > FLOAT_MIN_ALT => {
+                FlightPhase::Float
+            }
+            FlightPhase::Float if arate < DESCENT_RATE => FlightPhase::Descent,
+            FlightPhase::Descent if arate.abs() < STILL_RATE && alt < FLOAT_MIN_ALT => {
+                FlightPhase::Landed
+            }
+            p => p,
+        };
+
+        if next == self.phase {
+            self.candidate = None;
+            self.candidate_samples = 0;
+            return None;
+        }
+
+        if self.candidate == Some(next) {
+            self.candidate_samples += 1;
+        } else {
+            self.candidate = Some(next);
+            self.candidate_samples = 1;
+        }
+        if self.candidate_samples < PHASE_SAMPLES {
+            return None;
+        }
+
+        self.candidate = None;
+        self.candidate_samples = 0;
+        if next == FlightPhase::Ascent && self.launch_time.is_empty() {
+            self.launch_time = Utc::now().to_rfc3339();
+        }
+        self.phase = next;
+        Some(next)
+    }
+}
@@ -0,0 +1,1062 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Library to receive and send data using an SX1262 LoRa Module.
+// The SX126x are driven with commands instead of registers, and the
+// chip must not be busy (BUSY pin low) before each command.
+
+#![allow(dead_code)]
+extern crate spidev;
+extern crate sysfs_gpio;
+
+use spidev::Spidev;
+use std::{thread, time};
+use sysfs_gpio::{Direction, Pin};
+
+use radio::*;
+
+const FXOSC: f64 = 32000000.0;
+const FSTEP: f64 = FXOSC / 33554432.0;
+
+// SPI clock (Hz), the SX1262 supports up to 16MHz
+const DEFAULT_SPI_SPEED: u32 = 1000000;
+const MAX_SPI_SPEED: u32 = 16000000;
+
+// Commands (datasheet 13)
+const CMD_SET_SLEEP: u8 = 0x84;
+const CMD_SET_STANDBY: u8 = 0x80;
+const CMD_SET_FS: u8 = 0xc1;
+const CMD_SET_TX: u8 = 0x83;
+const CMD_SET_RX: u8 = 0x82;
+const CMD_SET_CAD: u8 = 0xc5;
+const CMD_SET_TX_CONTINUOUS_WAVE: u8 = 0xd1;
+const CMD_SET_REGULATOR_MODE: u8 = 0x96;
+const CMD_CALIBRATE: u8 = 0x89;
+const CMD_CALIBRATE_IMAGE: u8 = 0x98;
+const CMD_SET_PA_CONFIG: u8 = 0x95;
+const CMD_WRITE_REGISTER: u8 = 0x0d;
+const CMD_READ_REGISTER: u8 = 0x1d;
+const CMD_WRITE_BUFFER: u8 = 0x0e;
+const CMD_READ_BUFFER: u8 = 0x1e;
+const CMD_SET_DIO_IRQ_PARAMS: u8 = 0x08;
+const CMD_GET_IRQ_STATUS: u8 = 0x12;
+const CMD_CLEAR_IRQ_STATUS: u8 = 0x02;
+const CMD_SET_DIO2_AS_RF_SWITCH_CTRL: u8 = 0x9d;
+const CMD_SET_DIO3_AS_TCXO_CTRL: u8 = 0x97;
+const CMD_SET_RF_FREQUENCY: u8 = 0x86;
+const CMD_SET_PACKET_TYPE: u8 = 0x8a;
+const CMD_SET_TX_PARAMS: u8 = 0x8e;
+const CMD_SET_MODULATION_PARAMS: u8 = 0x8b;
+const CMD_SET_PACKET_PARAMS: u8 = 0x8c;
+const CMD_SET_CAD_PARAMS: u8 = 0x88;
+const CMD_SET_BUFFER_BASE_ADDRESS: u8 = 0x8f;
+const CMD_GET_STATUS: u8 = 0xc0;
+const CMD_GET_RX_BUFFER_STATUS: u8 = 0x13;
+const CMD_GET_PACKET_STATUS: u8 = 0x14;
+const CMD_GET_DEVICE_ERRORS: u8 = 0x17;
+const CMD_CLEAR_DEVICE_ERRORS: u8 = 0x07;
+
+const NOP: u8 = 0x00;
+
+// Registers
+const REG_VERSION_STRING: u16 = 0x0320;
+const REG_LORA_SYNC_WORD_MSB: u16 = 0x0740;
+const REG_LORA_SYNC_WORD_LSB: u16 = 0x0741;
+const REG_TX_MODULATION: u16 = 0x0889;
+const REG_OCP: u16 = 0x08e7;
+
+const VERSION_STRING_LEN: u8 = 16;
+
+// private network sync word, the same as 0x12 in the SX1276
+const LORA_SYNC_WORD_PRIVATE: [u8; 2] = [0x14, 0x24];
+
+// SetStandby
+const STDBY_RC: u8 = 0x00;
+const STDBY_XOSC: u8 = 0x01;
+
+// SetSleep, warm start keeps the configuration
+const SLEEP_WARM_START: u8 = 0x04;
+
+// SetPacketType
+const PACKET_TYPE_LORA: u8 = 0x01;
+
+// SetRegulatorMode
+const REGULATOR_DC_DC: u8 = 0x01;
+
+// Calibrate, all the blocks
+const CALIBRATE_ALL: u8 = 0x7f;
+
+// SetPaConfig for up to +22dBm: paDutyCycle, hpMax, deviceSel, paLut
+const PA_CONFIG_SX1262: [u8; 4] = [0x04, 0x07, 0x00, 0x01];
+// over current protection set by SetPaConfig, 140mA
+const OCP_SX1262: u8 = 0x38;
+const MAX_POWER: u8 = 22;
+
+// SetTxParams
+const RAMP_200U: u8 = 0x04;
+
+// SetTx and SetRx timeouts
+const TX_SINGLE: [u8; 3] = [0x00, 0x00, 0x00];
+const RX_CONTINUOUS: [u8; 3] = [0xff, 0xff, 0xff];
+
+// SetPacketParams
+const LORA_HEADER_EXPLICIT: u8 = 0x00;
+const LORA_HEADER_IMPLICIT: u8 = 0x01;
+const LORA_IQ_STANDARD: u8 = 0x00;
+
+// SetCadParams: 4 symbols, detection peak (+ SF) and minimum, CAD only
+const CAD_ON_4_SYMB: u8 = 0x02;
+const CAD_DET_PEAK_OFFSET: u8 = 13;
+const CAD_DET_MIN: u8 = 10;
+const CAD_EXIT_STDBY: u8 = 0x00;
+
+// TCXO start up time, in 15.625us steps (5ms)
+const TCXO_DELAY: u32 = 320;
+
+// IRQ flags
+const IRQ_TX_DONE: u16 = 0x0001;
+const IRQ_RX_DONE: u16 = 0x0002;
+const IRQ_HEADER_VALID: u16 = 0x0010;
+const IRQ_HEADER_ERR: u16 = 0x0020;
+const IRQ_CRC_ERR: u16 = 0x0040;
+const IRQ_CAD_DONE: u16 = 0x0080;
+const IRQ_CAD_DETECTED: u16 = 0x0100;
+const IRQ_TIMEOUT: u16 = 0x0200;
+const IRQ_ALL: u16 = 0x03ff;
+
+// GetStatus chip modes (bits 6:4)
+const CHIP_MODE_MASK: u8 = 0x70;
+const CHIP_MODE_STBY_RC: u8 = 0x20;
+const CHIP_MODE_STBY_XOSC: u8 = 0x30;
+
+const MAX_MESSAGE_LEN: usize = 255;
+
+// max time the chip can be busy after a command, ms
+const BUSY_TIMEOUT: u64 = 100;
+
+// a transmission fails if it takes longer than
+// TX_TIMEOUT_FACTOR times its time on air plus TX_TIMEOUT_MARGIN ms
+const TX_TIMEOUT_FACTOR: u32 = 2;
+const TX_TIMEOUT_MARGIN: u64 = 500;
+
+// a channel activity detection takes about 4 symbols here,
+// we wait CAD_TIMEOUT_SYMBOLS plus TX_TIMEOUT_MARGIN ms
+const CAD_TIMEOUT_SYMBOLS: f32 = 8.0;
+
+pub struct SX1262<S = Spidev, B = Pin> {
+    state: RadioState,
+    tx_len: usize,
+    tx_deadline: Option<time::Instant>,
+    tx_power: Option<u8>,
+    spi_speed: u32,
+    pub spi: S,
+    pub channel: u8,
+    busy_pin: B,
+    reset_pin: Option<Pin>,
+    // V, 0 for a crystal
+    tcxo_voltage: f32,
+    dio2_rf_switch: bool,
+    // image calibration done for this band
+    image_cal: Option<[u8; 2]>,
+    // being reset, a busy timeout doesn't start another reset
+    resetting: bool,
+    modem: ModemConfig,
+    preamble_len: u16,
+    frequency: f32,
+}
+
+impl SX1262 {
+    // reset 0 if the reset pin is not connected
+    pub fn new(ch: u8, busy: u8, reset: u8, tcxo_voltage: f32, dio2_rf_switch: bool) -> Self {
+        let spidev = Spidev::open(String::from("/dev/spidev0.") + &ch.to_string()).unwrap();
+        Self::with_spi(spidev, Pin::new(busy as u64), ch, reset, tcxo_voltage, dio2_rf_switch)
+    }
+}
+
+impl<S: SpiBus, B: InputPin> SX1262<S, B> {
+    // on any SPI bus and BUSY line, ch is just informative
+    pub fn with_spi(
+        spi: S,
+        busy: B,
+        ch: u8,
+        reset: u8,
+        tcxo_voltage: f32,
+        dio2_rf_switch: bool,
+    ) -> Self {
+        Self {
+            state: RadioState::new(),
+            tx_len: 0,
+            tx_deadline: None,
+            tx_power: None,
+            spi_speed: DEFAULT_SPI_SPEED,
+            channel: ch,
+            spi,
+            busy_pin: busy,
+            reset_pin: if reset > 0 { Some(Pin::new(reset as u64)) } else { None },
+            tcxo_voltage,
+            dio2_rf_switch,
+            image_cal: None,
+            resetting: false,
+            modem: ModemPreset::Bw125Cr45Sf128.config(),
+            preamble_len: DEFAULT_PREAMBLE_LEN,
+            frequency: 0.0,
+        }
+    }
+
+    // full duplex SPI transfer
+    fn transfer(&mut self, tx: &[u8]) -> Vec<u8> {
+        let mut rx = vec![0_u8; tx.len()];
+        self.spi.transfer_bytes(tx, &mut rx).unwrap();
+        rx
+    }
+
+    // NSS going low wakes the chip up, it's in STDBY_RC when BUSY goes low
+    fn wake_up(&mut self) {
+        self.transfer(&[CMD_GET_STATUS, NOP]);
+        self.state.enter_mode(RADIO_MODE_IDLE);
+    }
+
+    // wait for the chip to be ready for a command. BUSY stays high
+    // while sleeping. A chip stuck busy is reset and Err is returned,
+    // the command is not sent.
+    fn wait_busy(&mut self) -> Result<(), &'static str> {
+        if self.state.mode == RADIO_MODE_SLEEP {
+            self.wake_up();
+        }
+        let deadline = time::Instant::now() + time::Duration::from_millis(BUSY_TIMEOUT);
+        while self.busy_pin.is_high() {
+            if time::Instant::now() >= deadline {
+                if !self.resetting {
+                    self.recover()?;
+                }
+                return Err("Radio busy");
+            }
+            thread::sleep(time::Duration::from_micros(100));
+        }
+        Ok(())
+    }
+
+    // send a command with its parameters
+    pub fn command(&mut self, opcode: u8, params: &[u8]) -> Result<(), &'static str> {
+        self.wait_busy()?;
+        let mut tx: Vec<u8> = Vec::with_capacity(params.len() + 1);
+        tx.push(opcode);
+        tx.extend_from_slice(params);
+        self.transfer(&tx);
+        Ok(())
+    }
+
+    // send a command with its parameters and read len bytes of response.
+    // The response comes after the status byte.
+    pub fn read_command(
+        &mut self,
+        opcode: u8,
+        params: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, &'static str> {
+        self.wait_busy()?;
+        let mut tx: Vec<u8> = Vec::with_capacity(params.len() + len + 2);
+        tx.push(opcode);
+        tx.extend_from_slice(params);
+        tx.resize(params.len() + len + 2, NOP);
+        Ok(self.transfer(&tx).split_off(params.len() + 2))
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: &[u8]) -> Result<(), &'static str> {
+        let mut params = vec![(addr >> 8) as u8, (addr & 0xff) as u8];
+        params.extend_from_slice(data);
+        self.command(CMD_WRITE_REGISTER, &params)
+    }
+
+    pub fn read_register(&mut self, addr: u16, len: u8) -> Result<Vec<u8>, &'static str> {
+        self.read_command(CMD_READ_REGISTER, &[(addr >> 8) as u8, (addr & 0xff) as u8], len as usize)
+    }
+
+    fn get_status(&mut self) -> Result<u8, &'static str> {
+        self.wait_busy()?;
+        Ok(self.transfer(&[CMD_GET_STATUS, NOP])[1])
+    }
+
+    fn irq_status(&mut self) -> Result<u16, &'static str> {
+        let r = self.read_command(CMD_GET_IRQ_STATUS, &[], 2)?;
+        Ok(((r[0] as u16) << 8) | r[1] as u16)
+    }
+
+    fn clear_irq(&mut self, flags: u16) -> Result<(), &'static str> {
+        self.command(CMD_CLEAR_IRQ_STATUS, &[(flags >> 8) as u8, (flags & 0xff) as u8])
+    }
+
+    fn set_rf_frequency(&mut self, freq: f64) -> Result<(), &'static str> {
+        let frf = (freq / FSTEP).round() as u32;
+        self.command(
+            CMD_SET_RF_FREQUENCY,
+            &[(frf >> 24) as u8, (frf >> 16) as u8, (frf >> 8) as u8, frf as u8],
+        )
+    }
+
+    // image calibration for the band of the frequency (datasheet 9.2.1),
+    // must be done in STDBY_RC
+    fn calibrate_image(&mut self, freq: f32) -> Result<(), &'static str> {
+        let band = if freq > 900.0 {
+            [0xe1, 0xe9]
+        } else if freq > 850.0 {
+            [0xd7, 0xdb]
+        } else if freq > 770.0 {
+            [0xc1, 0xc5]
+        } else if freq > 460.0 {
+            [0x75, 0x81]
+        } else {
+            [0x6b, 0x6f]
+        };
+        if self.image_cal != Some(band) {
+            self.command(CMD_CALIBRATE_IMAGE, &band)?;
+            self.image_cal = Some(band);
+        }
+        Ok(())
+    }
+
+    // SetDIO3AsTcxoCtrl voltage value
+    fn tcxo_code(voltage: f32) -> Option<u8> {
+        let code = match (voltage * 10.0).round() as u32 {
+            16 => 0x00,
+            17 => 0x01,
+            18 => 0x02,
+            22 => 0x03,
+            24 => 0x04,
+            27 => 0x05,
+            30 => 0x06,
+            33 => 0x07,
+            _ => return None,
+        };
+        Some(code)
+    }
+
+    // SetModulationParams values
+    fn bandwidth_code(bw: Bandwidth) -> u8 {
+        match bw {
+            Bandwidth::Bw7_8 => 0x00,
+            Bandwidth::Bw10_4 => 0x08,
+            Bandwidth::Bw15_6 => 0x01,
+            Bandwidth::Bw20_8 => 0x09,
+            Bandwidth::Bw31_25 => 0x02,
+            Bandwidth::Bw41_7 => 0x0a,
+            Bandwidth::Bw62_5 => 0x03,
+            Bandwidth::Bw125 => 0x04,
+            Bandwidth::Bw250 => 0x05,
+            Bandwidth::Bw500 => 0x06,
+        }
+    }
+
+    // the payload length is only used in TX and with implicit header
+    fn set_packet_params(&mut self, payload_len: u8) -> Result<(), &'static str> {
+        let header = if self.modem.implicit_header { LORA_HEADER_IMPLICIT } else { LORA_HEADER_EXPLICIT };
+        let params = [
+            (self.preamble_len >> 8) as u8,
+            (self.preamble_len & 0xff) as u8,
+            header,
+            payload_len,
+            self.modem.crc as u8,
+            LORA_IQ_STANDARD,
+        ];
+        self.command(CMD_SET_PACKET_PARAMS, &params)
+    }
+
+    pub fn set_preamble_length(&mut self, len: u16) -> Result<(), &'static str> {
+        self.preamble_len = len;
+        self.set_packet_params(MAX_MESSAGE_LEN as u8)
+    }
+
+    pub fn set_mode_rx(&mut self) -> Result<(), &'static str> {
+        if self.state.mode != RADIO_MODE_RX {
+            self.set_packet_params(MAX_MESSAGE_LEN as u8)?;
+            self.clear_irq(IRQ_ALL)?;
+            self.command(CMD_SET_RX, &RX_CONTINUOUS)?;
+            self.state.enter_mode(RADIO_MODE_RX);
+        }
+        Ok(())
+    }
+
+    fn set_mode_tx(&mut self) -> Result<(), &'static str> {
+        if self.state.mode != RADIO_MODE_TX {
+            self.command(CMD_SET_TX, &TX_SINGLE)?;
+            self.state.enter_mode(RADIO_MODE_TX);
+        }
+        Ok(())
+    }
+
+    // init the chip and restore the LoRa configuration
+    fn reconfigure(&mut self) -> Result<(), &'static str> {
+        let mode = self.state.mode;
+        let modem = self.modem;
+        let frequency = self.frequency;
+        let preamble_len = self.preamble_len;
+
+        self.state.enter_mode(RADIO_MODE_INITIALISING);
+        self.image_cal = None;
+        self.init()?;
+        self.set_frequency(frequency)?;
+        self.set_modem_config(&modem)?;
+        self.set_preamble_length(preamble_len)?;
+        if let Some(p) = self.tx_power {
+            self.set_tx_power(p)?;
+        }
+        // an interrupted transmission is not resumed
+        if mode == RADIO_MODE_RX {
+            self.set_mode_rx()?;
+        }
+
+        Ok(())
+    }
+
+    // RSSI and SNR of the last received packet. The SX1262
+    // doesn't give a frequency error estimation.
+    fn read_packet_info(&mut self) -> Result<PacketInfo, &'static str> {
+        let status = self.read_command(CMD_GET_PACKET_STATUS, &[], 3)?;
+        Ok(PacketInfo {
+            rssi: -(status[0] as i16) / 2,
+            snr: (status[1] as i8) as f32 / 4.0,
+            freq_error: 0.0,
+        })
+    }
+
+    fn enter_carrier_mode(&mut self) -> Result<(), &'static str> {
+        self.wait_packet_sent()?;
+        self.set_mode_idle()
+    }
+
+    // back to standby on the LoRa frequency
+    fn leave_carrier_mode(&mut self) -> Result<(), &'static str> {
+        self.command(CMD_SET_STANDBY, &[STDBY_RC])?;
+        self.state.enter_mode(RADIO_MODE_IDLE);
+        let freq = self.frequency;
+        self.set_frequency(freq)
+    }
+
+    // values the configuration registers should have
+    fn expected_registers(&self) -> Vec<(u16, u8)> {
+        vec![
+            (REG_LORA_SYNC_WORD_MSB, LORA_SYNC_WORD_PRIVATE[0]),
+            (REG_LORA_SYNC_WORD_LSB, LORA_SYNC_WORD_PRIVATE[1]),
+            (REG_OCP, OCP_SX1262),
+        ]
+    }
+
+    pub fn snapshot(&mut self) -> Result<RegisterSnapshot, &'static str> {
+        let mut registers = Vec::new();
+        for (reg, _) in self.expected_registers() {
+            registers.push((reg, self.read_register(reg, 1)?[0]));
+        }
+
+        Ok(RegisterSnapshot { registers })
+    }
+}
+
+impl<S: SpiBus, B: InputPin> Radio for SX1262<S, B> {
+    fn state(&self) -> &RadioState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut RadioState {
+        &mut self.state
+    }
+
+    // configure SPI bus, reset the chip and set it up in LoRa mode
+    fn init(&mut self) -> Result<(), &'static str> {
+        let speed = self.spi_speed;
+        self.spi.setup(speed).unwrap();
+
+        self.busy_pin.setup_input().map_err(|_| "Can't set up BUSY pin")?;
+
+        if let Some(ref reset) = self.reset_pin {
+            reset.export().map_err(|_| "Can't export reset pin")?;
+            reset.set_direction(Direction::Out).map_err(|_| "Can't set reset pin direction")?;
+            reset.set_value(0).map_err(|_| "Can't reset radio")?;
+            thread::sleep(time::Duration::from_millis(1));
+            reset.set_value(1).map_err(|_| "Can't reset radio")?;
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        // in case it's sleeping
+        self.wake_up();
+        self.wait_busy()?;
+
+        self.command(CMD_SET_STANDBY, &[STDBY_RC])?;
+        let chip_mode = self.get_status()? & CHIP_MODE_MASK;
+        if chip_mode != CHIP_MODE_STBY_RC && chip_mode != CHIP_MODE_STBY_XOSC {
+            return Err("Lora not configured");
+        }
+        self.state.enter_mode(RADIO_MODE_IDLE);
+
+        if self.tcxo_voltage > 0.0 {
+            let code = Self::tcxo_code(self.tcxo_voltage).ok_or("Invalid TCXO voltage")?;
+            self.command(
+                CMD_SET_DIO3_AS_TCXO_CTRL,
+                &[code, (TCXO_DELAY >> 16) as u8, (TCXO_DELAY >> 8) as u8, TCXO_DELAY as u8],
+            )?;
+            // the oscillator failed to start while the TCXO was off
+            self.command(CMD_CLEAR_DEVICE_ERRORS, &[0, 0])?;
+        }
+        self.command(CMD_CALIBRATE, &[CALIBRATE_ALL])?;
+        self.wait_busy().map_err(|_| "Radio calibration failed")?;
+        if self.dio2_rf_switch {
+            self.command(CMD_SET_DIO2_AS_RF_SWITCH_CTRL, &[0x01])?;
+        }
+
+        self.command(CMD_SET_REGULATOR_MODE, &[REGULATOR_DC_DC])?;
+        self.command(CMD_SET_PACKET_TYPE, &[PACKET_TYPE_LORA])?;
+        self.command(CMD_SET_BUFFER_BASE_ADDRESS, &[0, 0])?;
+        self.write_register(REG_LORA_SYNC_WORD_MSB, &LORA_SYNC_WORD_PRIVATE)?;
+        self.command(CMD_SET_PA_CONFIG, &PA_CONFIG_SX1262)?;
+
+        // all the IRQs in the status, none on the DIO pins, we poll
+        self.command(
+            CMD_SET_DIO_IRQ_PARAMS,
+            &[(IRQ_ALL >> 8) as u8, (IRQ_ALL & 0xff) as u8, 0, 0, 0, 0, 0, 0],
+        )?;
+        self.clear_irq(IRQ_ALL)?;
+
+        self.set_modem_config(&ModemPreset::Bw125Cr45Sf128.config())?;
+        self.set_preamble_length(8)?;
+
+        Ok(())
+    }
+
+    // SPI clock in Hz, applied in init()
+    fn set_spi_speed(&mut self, hz: u32) {
+        self.spi_speed = hz.clamp(1, MAX_SPI_SPEED);
+    }
+
+    fn set_frequency(&mut self, freq: f32) -> Result<(), &'static str> {
+        self.frequency = freq;
+        self.calibrate_image(freq)?;
+        self.set_rf_frequency(freq as f64 * 1000000.0)
+    }
+
+    fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    // 0 to 22dBm with the SX1262 PA
+    fn set_tx_power(&mut self, p: u8) -> Result<(), &'static str> {
+        self.tx_power = Some(p);
+        self.command(CMD_SET_TX_PARAMS, &[p.min(MAX_POWER), RAMP_200U])
+    }
+
+    fn set_modem_config(&mut self, config: &ModemConfig) -> Result<(), &'static str> {
+        config.validate()?;
+
+        self.modem = *config;
+        self.command(
+            CMD_SET_MODULATION_PARAMS,
+            &[
+                config.spreading_factor.value(),
+                Self::bandwidth_code(config.bandwidth),
+                config.coding_rate.value() - 4,
+                config.low_data_rate_optimize() as u8,
+            ],
+        )?;
+        self.set_packet_params(MAX_MESSAGE_LEN as u8)?;
+
+        // errata 15.1, modulation quality with 500kHz bandwidth
+        let tx_modulation = self.read_register(REG_TX_MODULATION, 1)?[0];
+        let tx_modulation = if config.bandwidth == Bandwidth::Bw500 {
+            tx_modulation & !0x04
+        } else {
+            tx_modulation | 0x04
+        };
+        self.write_register(REG_TX_MODULATION, &[tx_modulation])
+    }
+
+    fn modem_config(&self) -> ModemConfig {
+        self.modem
+    }
+
+    // change frequency, modem and power, waiting for the packet being sent
+    // and writing only what changes
+    fn set_profile(&mut self, profile: &RadioProfile) -> Result<(), &'static str> {
+        if profile.hopping {
+            return Err("Frequency hopping not supported by the SX1262");
+        }
+
+        self.wait_packet_sent()?;
+
+        let listening = self.state.mode == RADIO_MODE_RX;
+        self.set_mode_idle()?;
+        if profile.frequency != self.frequency {
+            self.set_frequency(profile.frequency)?;
+        }
+        if profile.modem != self.modem {
+            self.set_modem_config(&profile.modem)?;
+        }
+        if Some(profile.power) != self.tx_power {
+            self.set_tx_power(profile.power)?;
+        }
+        if listening {
+            self.set_mode_rx()?;
+        }
+
+        Ok(())
+    }
+
+    // time a packet of len bytes occupies the channel with the
+    // current modem configuration
+    fn time_on_air(&self, len: usize) -> time::Duration {
+        self.modem.time_on_air(self.preamble_len, len)
+    }
+
+    fn set_mode_idle(&mut self) -> Result<(), &'static str> {
+        if self.state.mode != RADIO_MODE_IDLE {
+            self.command(CMD_SET_STANDBY, &[STDBY_RC])?;
+            self.state.enter_mode(RADIO_MODE_IDLE);
+        }
+        Ok(())
+    }
+
+    // warm start, the configuration is kept. The next command wakes it up.
+    fn set_mode_sleep(&mut self) -> Result<(), &'static str> {
+        if self.state.mode != RADIO_MODE_SLEEP {
+            self.command(CMD_SET_SLEEP, &[SLEEP_WARM_START])?;
+            self.state.enter_mode(RADIO_MODE_SLEEP);
+        }
+        Ok(())
+    }
+
+    // Send data
+    fn send(&mut self, data: &[u8]) -> Result<bool, &'static str> {
+        if data.len() + self.header_len() > MAX_MESSAGE_LEN {
+            return Ok(false);
+        }
+
+        self.wait_packet_sent()?;
+
+        if let Some(lbt) = self.state.lbt {
+            self.listen_before_talk(&lbt)?;
+        }
+
+        self.set_mode_idle()?;
+
+        // header and data
+        let packet = self.state.packet(data);
+
+        // write data at the beginning of the buffer
+        let start = time::Instant::now();
+        let mut params = vec![0_u8];
+        params.extend_from_slice(&packet);
+        self.command(CMD_WRITE_BUFFER, &params)?;
+        self.state.stats.fifo_time += start.elapsed();
+        self.set_packet_params(packet.len() as u8)?;
+        self.clear_irq(IRQ_ALL)?;
+        self.tx_len = packet.len();
+        self.tx_deadline = Some(
+            time::Instant::now()
+                + self.time_on_air(packet.len()) * TX_TIMEOUT_FACTOR
+                + time::Duration::from_millis(TX_TIMEOUT_MARGIN),
+        );
+
+        self.set_mode_tx()?;
+
+        Ok(true)
+    }
+
+    fn wait_packet_sent(&mut self) -> Result<bool, &'static str> {
+        // If we are not currently in transmit mode,
+        // there is no packet to wait for
+        if self.state.mode != RADIO_MODE_TX {
+            return Ok(false);
+        }
+
+        let deadline = self.tx_deadline.unwrap_or_else(|| {
+            time::Instant::now() + time::Duration::from_millis(TX_TIMEOUT_MARGIN)
+        });
+        while self.irq_status()? & IRQ_TX_DONE == 0 {
+            if time::Instant::now() >= deadline {
+                self.state.stats.tx_timeouts += 1;
+                self.tx_deadline = None;
+                self.recover()?;
+                return Err("TX timeout");
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        self.tx_deadline = None;
+
+        self.state.stats.tx_packets += 1;
+        self.state.stats.tx_bytes += self.tx_len as u64;
+        self.state.stats.tx_airtime += self.time_on_air(self.tx_len);
+        self.clear_irq(IRQ_ALL)?;
+
+        // the chip goes back to standby by itself
+        self.state.enter_mode(RADIO_MODE_IDLE);
+        if self.state.sleep_after_tx {
+            self.set_mode_sleep()?;
+        }
+
+        Ok(true)
+    }
+
+    fn available(&mut self) -> Result<bool, &'static str> {
+        if self.state.mode == RADIO_MODE_TX {
+            return Err("Radio in TX mode");
+        }
+
+        if self.state.mode == RADIO_MODE_RX {
+            let irq = self.irq_status()?;
+            if irq & IRQ_RX_DONE != 0 && irq & (IRQ_CRC_ERR | IRQ_HEADER_ERR) != 0 {
+                // corrupted packet, discard it
+                self.state.stats.rx_crc_errors += 1;
+                self.state.last_packet = Some(self.read_packet_info()?);
+                self.state.stats.last_packet = self.state.last_packet;
+            } else if irq & IRQ_RX_DONE != 0 {
+                // Have received a packet
+                let status = self.read_command(CMD_GET_RX_BUFFER_STATUS, &[], 2)?;
+                let (length, ptr) = (status[0], status[1]);
+
+                let start = time::Instant::now();
+                let data = self.read_command(CMD_READ_BUFFER, &[ptr], length as usize)?;
+                self.state.stats.fifo_time += start.elapsed();
+                self.state.buf[..length as usize].copy_from_slice(&data);
+                self.state.buflen = length;
+
+                // Remember the RSSI and SNR of this packet
+                self.state.last_packet = Some(self.read_packet_info()?);
+                self.state.stats.last_packet = self.state.last_packet;
+
+                // We have received a message.
+                self.state.stats.rx_bytes += length as u64;
+                self.state.validate_rx_buf();
+                if self.state.rx_buf_valid {
+                    self.state.stats.rx_packets += 1;
+                } else {
+                    self.state.stats.rx_dropped += 1;
+                }
+            }
+            if irq != 0 {
+                self.clear_irq(irq)?;
+            }
+        }
+
+        // keeps listening in continuous mode
+        self.set_mode_rx()?;
+        Ok(self.state.rx_buf_valid)
+    }
+
+    fn channel_activity(&mut self) -> Result<bool, &'static str> {
+        if self.state.mode == RADIO_MODE_TX {
+            return Err("Radio in TX mode");
+        }
+
+        let previous = self.state.mode;
+        self.set_mode_idle()?;
+        let peak = self.modem.spreading_factor.value() + CAD_DET_PEAK_OFFSET;
+        self.command(CMD_SET_CAD_PARAMS, &[CAD_ON_4_SYMB, peak, CAD_DET_MIN, CAD_EXIT_STDBY, 0, 0, 0])?;
+        self.clear_irq(IRQ_ALL)?;
+        self.command(CMD_SET_CAD, &[])?;
+        self.state.enter_mode(RADIO_MODE_CAD);
+
+        let timeout = time::Duration::from_micros(
+            (self.modem.symbol_time() * CAD_TIMEOUT_SYMBOLS * 1e6) as u64,
+        ) + time::Duration::from_millis(TX_TIMEOUT_MARGIN);
+        let deadline = time::Instant::now() + timeout;
+        let irq = loop {
+            let irq = self.irq_status()?;
+            if irq & IRQ_CAD_DONE != 0 {
+                break irq;
+            }
+            if time::Instant::now() >= deadline {
+                self.recover()?;
+                return Err("CAD timeout");
+            }
+            thread::sleep(time::Duration::from_millis(1));
+        };
+
+        self.clear_irq(IRQ_ALL)?;
+        // back to standby by itself
+        self.state.enter_mode(RADIO_MODE_IDLE);
+        if previous == RADIO_MODE_RX {
+            self.set_mode_rx()?;
+        }
+
+        Ok(irq & IRQ_CAD_DETECTED != 0)
+    }
+
+    // the SX126x have no hopping support
+    fn set_hop_table(&mut self, _table: FrequencyHopping) -> Result<(), &'static str> {
+        Err("Frequency hopping not supported by the SX1262")
+    }
+
+    fn hopping(&self) -> bool {
+        false
+    }
+
+    fn send_tones(
+        &mut self,
+        frequency: f32,
+        offsets: &[f64],
+        lead: Option<(usize, time::Duration)>,
+        symbols: &[usize],
+        symbol_time: time::Duration,
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str> {
+        self.enter_carrier_mode()?;
+        let mut ticker = Ticker::new(tick);
+
+        let carrier = frequency as f64 * 1000000.0;
+        let (lead_tone, lead_time) = lead.unwrap_or((0, time::Duration::from_secs(0)));
+        self.set_rf_frequency(carrier + offsets[lead_tone])?;
+        self.command(CMD_SET_TX_CONTINUOUS_WAVE, &[])?;
+        self.state.enter_mode(RADIO_MODE_TX);
+        let start = time::Instant::now();
+        sleep_until(start + lead_time);
+
+        // symbol times from the start, so errors don't accumulate
+        for (n, s) in symbols.iter().enumerate() {
+            self.set_rf_frequency(carrier + offsets[*s])?;
+            self.command(CMD_SET_TX_CONTINUOUS_WAVE, &[])?;
+            // during the symbol, the sleep absorbs its time
+            ticker.poll();
+            sleep_until(start + lead_time + symbol_time * (n as u32 + 1));
+        }
+
+        self.state.stats.tx_airtime += start.elapsed();
+
+        self.leave_carrier_mode()
+    }
+
+    // send Morse code elements (key down, units) as an unmodulated
+    // carrier. Blocks until it's sent.
+    fn send_cw(
+        &mut self,
+        cw: &CwConfig,
+        elements: &[(bool, u32)],
+        tick: &mut dyn FnMut(),
+    ) -> Result<(), &'static str> {
+        cw.validate()?;
+        self.enter_carrier_mode()?;
+        let mut ticker = Ticker::new(tick);
+
+        self.set_rf_frequency(cw.frequency as f64 * 1000000.0)?;
+        // key up with the synthesizer running, so key down is fast
+        self.command(CMD_SET_FS, &[])?;
+        self.state.enter_mode(RADIO_MODE_TX);
+
+        let start = time::Instant::now();
+        let unit = cw.unit();
+        let mut units = 0;
+        let mut on_air = time::Duration::from_secs(0);
+        for &(key_down, n) in elements {
+            let t = time::Instant::now();
+            if key_down {
+                self.command(CMD_SET_TX_CONTINUOUS_WAVE, &[])?;
+            } else {
+                self.command(CMD_SET_FS, &[])?;
+            }
+            units += n;
+            ticker.poll();
+            sleep_until(start + unit * units);
+            if key_down {
+                on_air += t.elapsed();
+            }
+        }
+
+        self.state.stats.tx_airtime += on_air;
+
+        self.leave_carrier_mode()
+    }
+
+    // check the chip version, its errors and that the
+    // configuration registers still have the values we wrote
+    fn diagnose(&mut self) -> Result<Diagnostics, &'static str> {
+        if self.state.mode == RADIO_MODE_TX {
+            return Err("Radio in TX mode");
+        }
+
+        let version = self.read_register(REG_VERSION_STRING, VERSION_STRING_LEN)?;
+        let version = String::from_utf8_lossy(&version).trim_end_matches('\0').trim().to_string();
+        if !version.starts_with("SX126") {
+            return Err("Unknown radio version");
+        }
+
+        // reported, the register check below still runs
+        let errors = self.read_command(CMD_GET_DEVICE_ERRORS, &[], 2)?;
+        let device_errors = ((errors[0] as u16) << 8) | errors[1] as u16;
+        if device_errors != 0 {
+            self.command(CMD_CLEAR_DEVICE_ERRORS, &[0, 0])?;
+        }
+
+        let snapshot = self.snapshot()?;
+        let drift = self
+            .expected_registers()
+            .into_iter()
+            .zip(snapshot.registers.iter())
+            .filter(|&((_, expected), &(_, actual))| expected != actual)
+            .map(|((register, expected), &(_, actual))| RegisterDrift {
+                register,
+                expected,
+                actual,
+            })
+            .collect();
+
+        Ok(Diagnostics {
+            version,
+            temperature: None,
+            device_errors,
+            snapshot,
+            drift,
+        })
+    }
+
+    // reset the chip after a failure, restoring
+    // its frequency, modem configuration, power and mode
+    fn recover(&mut self) -> Result<(), &'static str> {
+        self.state.stats.resets += 1;
+        self.resetting = true;
+        let r = self.reconfigure();
+        self.resetting = false;
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // polls the chip stays busy after each command
+    const BUSY_POLLS: u32 = 3;
+
+    // the chip side of the SPI bus and the BUSY line
+    #[derive(Default)]
+    struct Chip {
+        // polls of BUSY left before it goes low
+        busy: u32,
+        polls: u32,
+        transfers: Vec<Vec<u8>>,
+        sent_while_busy: bool,
+        device_errors: u16,
+    }
+
+    struct MockSpi(Rc<RefCell<Chip>>);
+
+    struct MockBusy(Rc<RefCell<Chip>>);
+
+    impl SpiBus for MockSpi {
+        fn setup(&mut self, _speed_hz: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+            let mut rx = vec![0; data.len()];
+            self.transfer_bytes(data, &mut rx)
+        }
+
+        fn transfer_bytes(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+            let mut chip = self.0.borrow_mut();
+            if chip.busy > 0 {
+                chip.sent_while_busy = true;
+            }
+            match tx[0] {
+                CMD_READ_REGISTER if ((tx[1] as u16) << 8 | tx[2] as u16) == REG_VERSION_STRING => {
+                    let version = b"SX1261 V2D 2D02\0";
+                    let len = rx.len() - 4;
+                    rx[4..].copy_from_slice(&version[..len]);
+                }
+                CMD_GET_DEVICE_ERRORS => {
+                    rx[2] = (chip.device_errors >> 8) as u8;
+                    rx[3] = chip.device_errors as u8;
+                }
+                CMD_CLEAR_DEVICE_ERRORS => chip.device_errors = 0,
+                _ => {}
+            }
+            chip.transfers.push(tx.to_vec());
+            chip.busy = chip.busy.max(BUSY_POLLS);
+            Ok(())
+        }
+    }
+
+    impl InputPin for MockBusy {
+        fn setup_input(&self) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn is_high(&self) -> bool {
+            let mut chip = self.0.borrow_mut();
+            chip.polls += 1;
+            if chip.busy > 0 {
+                chip.busy -= 1;
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    fn radio() -> (SX1262<MockSpi, MockBusy>, Rc<RefCell<Chip>>) {
+        let chip = Rc::new(RefCell::new(Chip::default()));
+        let sx1262 = SX1262::with_spi(
+            MockSpi(chip.clone()),
+            MockBusy(chip.clone()),
+            0,
+            0,
+            0.0,
+            false,
+        );
+        (sx1262, chip)
+    }
+
+    #[test]
+    fn command_waits_for_busy() {
+        let (mut sx1262, chip) = radio();
+        chip.borrow_mut().busy = 5;
+        sx1262.command(CMD_SET_STANDBY, &[STDBY_RC]).unwrap();
+        sx1262.command(CMD_SET_PACKET_TYPE, &[PACKET_TYPE_LORA]).unwrap();
+
+        let chip = chip.borrow();
+        assert!(!chip.sent_while_busy);
+        assert_eq!(chip.polls, 5 + 1 + BUSY_POLLS + 1);
+        assert_eq!(
+            chip.transfers,
+            vec![vec![CMD_SET_STANDBY, STDBY_RC], vec![CMD_SET_PACKET_TYPE, PACKET_TYPE_LORA]]
+        );
+    }
+
+    #[test]
+    fn stuck_busy_resets() {
+        let (mut sx1262, chip) = radio();
+        chip.borrow_mut().busy = u32::MAX;
+        assert_eq!(sx1262.command(CMD_SET_PACKET_TYPE, &[PACKET_TYPE_LORA]), Err("Radio busy"));
+
+        // the command is never sent, and the reset gives up too
+        assert!(!chip.borrow().transfers.iter().any(|t| t[0] == CMD_SET_PACKET_TYPE));
+        assert_eq!(sx1262.stats().resets, 1);
+    }
+
+    #[test]
+    fn diagnose_reports_device_errors() {
+        let (mut sx1262, chip) = radio();
+        chip.borrow_mut().device_errors = 0x0040;
+        let diag = sx1262.diagnose().unwrap();
+
+        assert_eq!(diag.version, "SX1261 V2D 2D02");
+        assert_eq!(diag.device_errors, 0x0040);
+        assert_eq!(chip.borrow().device_errors, 0);
+        // the registers are still checked
+        assert_eq!(diag.snapshot.registers.len(), 3);
+        assert!(diag.drift.iter().any(|d| d.register == REG_LORA_SYNC_WORD_MSB));
+
+        assert_eq!(sx1262.diagnose().unwrap().device_errors, 0);
+    }
+}
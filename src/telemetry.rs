@@ -20,6 +20,9 @@
 extern crate chrono;
 use chrono::prelude::*;
 
+use horus::HorusPacket;
+use radio::LinkStats;
+
 pub struct Telemetry {
     id: String,
     msg: String,
@@ -41,6 +44,8 @@ pub struct Telemetry {
     sep: String,
     date_time: DateTime<Utc>,
     hpwr: u8,
+    pub count: u32,
+    pub duty_cycle: Option<f32>,
 }
 
 impl Telemetry {
@@ -76,6 +81,8 @@ impl Telemetry {
                 Utc::now().second()
             ),
             hpwr: 0,
+            count: 0,
+            duty_cycle: None,
         }
     }
 
@@ -112,6 +119,7 @@ impl Telemetry {
         self.tin = tin;
         self.tout = tout;
         self.hpwr = hpwr;
+        self.count = self.count.wrapping_add(1);
 
         // save old datetime
         let old_date_time = self.date_time;
@@ -141,6 +149,10 @@ impl Telemetry {
         }
     }
 
+    pub fn ascent_rate(&self) -> f32 {
+        self.arate
+    }
+
     fn dec_lat(&self) -> f32 {
         let degrees = (self.lat / 100.0).trunc();
         let fraction = (self.lat - (degrees * 100.0)) / 60.0;
@@ -195,6 +207,10 @@ impl Telemetry {
         aprs.push_str(&self.sep);
         aprs.push_str(&format!("AR={:.1}", self.arate));
         aprs.push_str(&self.sep);
+        if let Some(dc) = self.duty_cycle {
+            aprs.push_str(&format!("DC={:.2}", dc));
+            aprs.push_str(&self.sep);
+        }
         aprs.push_str(&self.msg.replace("\n", " - "));
         aprs.push_str(&format!(" - {}", match self.hpwr {
             0 => "L",
@@ -214,6 +230,68 @@ impl Telemetry {
         aprs
     }
 
+    // Horus Binary v2 packet. Custom data: ascent rate (i16, cm/s),
+    // external temperature (i16, 0.1ºC) and pressure (u16, 0.1mbar)
+    pub fn horus_packet(&self, payload_id: u16) -> HorusPacket {
+        let lat = if self.ns == 'S' { -self.dec_lat() } else { self.dec_lat() };
+        let lon = if self.ew == 'W' { -self.dec_lon() } else { self.dec_lon() };
+
+        let mut custom = [0_u8; 9];
+        custom[0..2].copy_from_slice(&((self.arate * 100.0) as i16).to_le_bytes());
+        custom[2..4].copy_from_slice(&((self.tout * 10.0) as i16).to_le_bytes());
+        custom[4..6].copy_from_slice(&((self.baro * 10.0) as u16).to_le_bytes());
+
+        HorusPacket {
+            payload_id,
+            counter: self.count as u16,
+            hours: self.date_time.hour() as u8,
+            minutes: self.date_time.minute() as u8,
+            seconds: self.date_time.second() as u8,
+            latitude: lat,
+            longitude: lon,
+            altitude: self.alt.clamp(0.0, 65535.0) as u16,
+            // knots to km/h
+            speed: (self.spd * 1.852).min(255.0) as u8,
+            sats: self.sats,
+            temperature: self.tin.clamp(-128.0, 127.0) as i8,
+            batt_voltage: (self.vbat / 5.0 * 255.0).clamp(0.0, 255.0) as u8,
+            custom,
+        }
+    }
+
+    // low rate radio link status packet, with the watchdog resets
+    pub fn status_string(&self, link: &LinkStats, watchdog_resets: u32) -> String {
+        let mut status = String::from("$$");
+        status.push_str(&self.id);
+        status.push_str(&self.sep);
+        status.push_str("STATUS");
+        status.push_str(&self.sep);
+        status.push_str(&format!("TX={}", link.tx_packets));
+        status.push_str(&self.sep);
+        status.push_str(&format!("TXB={}", link.tx_bytes));
+        status.push_str(&self.sep);
+        status.push_str(&format!("AIR={}", link.tx_airtime.as_secs()));
+        status.push_str(&self.sep);
+        status.push_str(&format!("RX={}", link.rx_packets));
+        status.push_str(&self.sep);
+        status.push_str(&format!("RXB={}", link.rx_bytes));
+        status.push_str(&self.sep);
+        status.push_str(&format!("CRC={}", link.rx_crc_errors));
+        status.push_str(&self.sep);
+        status.push_str(&format!("RST={}", link.resets));
+        status.push_str(&self.sep);
+        status.push_str(&format!("WDR={}", watchdog_resets));
+        if let Some(p) = link.last_packet {
+            status.push_str(&self.sep);
+            status.push_str(&format!("RSSI={}", p.rssi));
+            status.push_str(&self.sep);
+            status.push_str(&format!("SNR={:.1}", p.snr));
+        }
+        status.push('\n');
+
+        status
+    }
+
     pub fn csv_string(&mut self) -> String {
         let mut csv = String::from("");
         csv.push_str(&format!("{},", self.date));
@@ -230,7 +308,8 @@ impl Telemetry {
         csv.push_str(&format!("{:.1},", self.hdg));
         csv.push_str(&format!("{:.1},", self.spd));
         csv.push_str(&format!("{},", self.sats));
-        csv.push_str(&format!("{:.1}", self.arate));
+        csv.push_str(&format!("{:.1},", self.arate));
+        csv.push_str(&format!("{}", self.count));
 
         // return string
         csv
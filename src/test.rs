@@ -35,6 +35,9 @@ use gps::*;
 mod picture;
 use picture::*;
 
+mod radio;
+use radio::*;
+
 mod rf95;
 use rf95::*;
 
@@ -59,6 +62,8 @@ use ms5607::*;
 mod telemetry;
 use telemetry::*;
 
+mod jpeg;
+mod rs8;
 mod ssdv;
 use ssdv::*;
 
@@ -147,6 +152,9 @@ fn main() {
 			config.id.clone(),
 			0
 			);
+    ssdv.quality = config.ssdv_quality;
+    ssdv.fec = config.ssdv_fec;
+    ssdv.packet_len = config.ssdv_packet_len as usize;
     match ssdv.encode() {
         Ok(()) => println!("Encodeado SSDV {}, paquetes: {}", 
                                 ssdv.binaryname, ssdv.packets),
@@ -154,13 +162,20 @@ fn main() {
     };
 
     // get last ssdv packet
-    let i = ssdv.packets - 1;
-    match ssdv.get_packet(i) {
-        Ok(p) => { 
-            print!("Packet first 8 bytes: ");
-            println!("{:x} {:x} {:x} {:x} {:x} {} {} {}", 
-                                        p[0], p[1], p[2], p[3],
-                                        p[4], p[5], p[6], p[7]);
+    if ssdv.packets > 0 {
+        match ssdv.get_packet(ssdv.packets - 1) {
+            Ok(p) => println!("Last packet: {} image {} packet {}, {}x{} MCUs, EOI {}",
+                                        p.callsign_string(), p.image_id, p.packet_id,
+                                        p.width, p.height, p.eoi()),
+            Err(e) => println!("Error: {:?}", e),
+        }
+    }
+
+    // check all the packets of the file
+    match ssdv.iter() {
+        Ok(packets) => {
+            let errors = packets.filter(|p| p.is_err()).count();
+            println!("SSDV file checked, {} bad packets", errors);
         },
         Err(e) => println!("Error: {:?}", e),
     }
@@ -227,8 +242,19 @@ fn main() {
     lora.set_tx_power(5);
 
     println!("Sending...");
-    lora.send(telem.aprs_string().as_bytes());
-    lora.wait_packet_sent();
+    lora.send(telem.aprs_string().as_bytes()).unwrap();
+    lora.wait_packet_sent().unwrap();
+
+    println!("Sending RTTY...");
+    let rtty = RttyConfig {
+        frequency: 868.5,
+        baud: 50,
+        shift: 425,
+        bits: 7,
+        stop_bits: 2,
+        preamble: 500,
+    };
+    lora.send_rtty(&rtty, telem.aprs_string().as_bytes(), &mut || {}).unwrap();
 
     thread::sleep(Duration::from_millis(1000));
 
@@ -236,8 +262,8 @@ fn main() {
     print!("Packet: ");
     // test sending a SSDV image
     for i in 0..ssdv.packets {
-	    lora.send(&ssdv.get_packet(i).unwrap());
-	    lora.wait_packet_sent();
+	    lora.send(&ssdv.get_packet(i).unwrap()).unwrap();
+	    lora.wait_packet_sent().unwrap();
         thread::sleep(Duration::from_millis(10));
 	    print!("{}, ", &i);
 	    io::stdout().flush().ok().expect("Could not flush stdout");
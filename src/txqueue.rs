@@ -0,0 +1,303 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Radio transmit queue with priority classes. Command acks and telemetry
+// always go first, SSDV and bulk data share the remaining airtime.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// max frames waiting in each class
+const QUEUE_LEN: usize = 16;
+
+// in priority order
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TxClass {
+    Ack,
+    Telemetry,
+    Ssdv,
+    Bulk,
+}
+
+const CLASSES: [TxClass; 4] = [TxClass::Ack, TxClass::Telemetry, TxClass::Ssdv, TxClass::Bulk];
+
+impl TxClass {
+    fn index(self) -> usize {
+        match self {
+            TxClass::Ack => 0,
+            TxClass::Telemetry => 1,
+            TxClass::Ssdv => 2,
+            TxClass::Bulk => 3,
+        }
+    }
+}
+
+impl fmt::Display for TxClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            TxClass::Ack => "ack",
+            TxClass::Telemetry => "telemetry",
+            TxClass::Ssdv => "ssdv",
+            TxClass::Bulk => "bulk",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct TxFrame {
+    pub class: TxClass,
+    pub data: Vec<u8>,
+    pub queued: Instant,
+}
+
+pub struct TxQueue {
+    queues: Vec<VecDeque<TxFrame>>,
+    // airtime used by each class, for the SSDV/bulk sharing
+    airtime: Vec<Duration>,
+    // airtime weights of the shared classes
+    shares: Vec<u32>,
+    telemetry_max_gap: Duration,
+    last_telemetry: Option<Instant>,
+}
+
+impl TxQueue {
+    pub fn new(telemetry_max_gap: u32, ssdv_share: u32, bulk_share: u32) -> Self {
+        Self {
+            queues: (0..CLASSES.len()).map(|_| VecDeque::new()).collect(),
+            airtime: vec![Duration::from_secs(0); CLASSES.len()],
+            shares: vec![0, 0, ssdv_share.max(1), bulk_share.max(1)],
+            telemetry_max_gap: Duration::from_secs(telemetry_max_gap as u64),
+            last_telemetry: None,
+        }
+    }
+
+    // queue a frame, false if the class queue is full.
+    // Only the newest telemetry frame is kept, old positions are useless.
+    pub fn push(&mut self, class: TxClass, data: Vec<u8>) -> bool {
+        let q = &mut self.queues[class.index()];
+        if class == TxClass::Telemetry {
+            q.clear();
+        }
+        if q.len() >= QUEUE_LEN {
+            return false;
+        }
+        q.push_back(TxFrame {
+            class,
+            data,
+            queued: Instant::now(),
+        });
+        true
+    }
+
+    // put back a frame that couldn't be sent yet, it will be the next of its class
+    pub fn push_front(&mut self, frame: TxFrame) {
+        self.queues[frame.class.index()].push_front(frame);
+    }
+
+    // next frame to transmit
+    pub fn pop(&mut self) -> Option<TxFrame> {
+        // strict priority for acks and telemetry
+        for c in &[TxClass::Ack, TxClass::Telemetry] {
+            if let Some(f) = self.queues[c.index()].pop_front() {
+                return Some(f);
+            }
+        }
+
+        // SSDV and bulk share the airtime by weight,
+        // choose the one that has used less of its share
+        let mut best: Option<TxClass> = None;
+        for c in &[TxClass::Ssdv, TxClass::Bulk] {
+            if self.queues[c.index()].is_empty() {
+                continue;
+            }
+            best = match best {
+                Some(b) if self.usage(b) <= self.usage(*c) => Some(b),
+                _ => Some(*c),
+            };
+        }
+
+        match best {
+            Some(c) => self.queues[c.index()].pop_front(),
+            None => {
+                // idle, forget the past so no class gets a debt
+                for a in self.airtime.iter_mut() {
+                    *a = Duration::from_secs(0);
+                }
+                None
+            }
+        }
+    }
+
+    // account a transmitted frame
+    pub fn sent(&mut self, class: TxClass, airtime: Duration) {
+        self.airtime[class.index()] += airtime;
+        if class == TxClass::Telemetry {
+            self.last_telemetry = Some(Instant::now());
+        }
+    }
+
+    // true if the telemetry cadence is about to be broken and
+    // there isn't a telemetry frame waiting
+    pub fn telemetry_due(&self, now: Instant) -> bool {
+        if self.telemetry_max_gap.as_secs() == 0
+            || !self.queues[TxClass::Telemetry.index()].is_empty()
+        {
+            return false;
+        }
+        match self.last_telemetry {
+            Some(last) => now.duration_since(last) >= self.telemetry_max_gap,
+            None => false,
+        }
+    }
+
+    pub fn len(&self, class: TxClass) -> usize {
+        self.queues[class.index()].len()
+    }
+
+    pub fn is_full(&self, class: TxClass) -> bool {
+        self.len(class) >= QUEUE_LEN
+    }
+
+    // drop everything, returns the number of frames dropped
+    pub fn clear(&mut self) -> usize {
+        let mut n = 0;
+        for q in self.queues.iter_mut() {
+            n += q.len();
+            q.clear();
+        }
+        n
+    }
+
+    // drop the frames of a class, returns the number of frames dropped
+    pub fn purge(&mut self, class: TxClass) -> usize {
+        let q = &mut self.queues[class.index()];
+        let n = q.len();
+        q.clear();
+        n
+    }
+
+    fn usage(&self, class: TxClass) -> f64 {
+        let i = class.index();
+        let t = self.airtime[i];
+        (t.as_secs() as f64 + t.subsec_nanos() as f64 / 1e9) / self.shares[i] as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn priority_order() {
+        let mut q = TxQueue::new(0, 1, 1);
+        q.push(TxClass::Bulk, vec![3]);
+        q.push(TxClass::Ssdv, vec![2]);
+        q.push(TxClass::Telemetry, vec![1]);
+        q.push(TxClass::Ack, vec![0]);
+
+        assert_eq!(q.pop().unwrap().class, TxClass::Ack);
+        assert_eq!(q.pop().unwrap().class, TxClass::Telemetry);
+        // SSDV wins the tie
+        assert_eq!(q.pop().unwrap().class, TxClass::Ssdv);
+        assert_eq!(q.pop().unwrap().class, TxClass::Bulk);
+        assert!(q.pop().is_none());
+    }
+
+    #[test]
+    fn newest_telemetry() {
+        let mut q = TxQueue::new(0, 1, 1);
+        for i in 0..3 {
+            assert!(q.push(TxClass::Telemetry, vec![i]));
+        }
+        assert_eq!(q.len(TxClass::Telemetry), 1);
+        assert_eq!(q.pop().unwrap().data, vec![2]);
+
+        // and a requeued one is still the next
+        q.push(TxClass::Ssdv, vec![9]);
+        let f = q.pop().unwrap();
+        q.push_front(f);
+        assert_eq!(q.pop().unwrap().data, vec![9]);
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut q = TxQueue::new(0, 1, 1);
+        for i in 0..QUEUE_LEN {
+            assert!(q.push(TxClass::Ssdv, vec![i as u8]));
+        }
+        assert!(q.is_full(TxClass::Ssdv));
+        assert!(!q.push(TxClass::Ssdv, vec![0]));
+        assert_eq!(q.clear(), QUEUE_LEN);
+    }
+
+    #[test]
+    fn weighted_sharing() {
+        // SSDV gets 3 times the airtime of bulk
+        let mut q = TxQueue::new(0, 3, 1);
+        for _ in 0..QUEUE_LEN {
+            q.push(TxClass::Ssdv, vec![0]);
+            q.push(TxClass::Bulk, vec![0]);
+        }
+        let mut order = Vec::new();
+        for _ in 0..8 {
+            let f = q.pop().unwrap();
+            q.sent(f.class, ms(100));
+            order.push(f.class);
+        }
+        let ssdv = order.iter().filter(|c| **c == TxClass::Ssdv).count();
+        assert_eq!(ssdv, 6);
+        // interleaved, bulk is never starved for long
+        assert!(order[..4].contains(&TxClass::Bulk));
+    }
+
+    #[test]
+    fn idle_forgets_airtime() {
+        let mut q = TxQueue::new(0, 1, 1);
+        q.sent(TxClass::Ssdv, ms(10000));
+        assert!(q.pop().is_none());
+        q.push(TxClass::Bulk, vec![1]);
+        q.push(TxClass::Ssdv, vec![0]);
+        // no debt from before the idle time
+        assert_eq!(q.pop().unwrap().class, TxClass::Ssdv);
+    }
+
+    #[test]
+    fn telemetry_due() {
+        let mut q = TxQueue::new(10, 1, 1);
+        let now = Instant::now();
+        // nothing sent yet
+        assert!(!q.telemetry_due(now + Duration::from_secs(60)));
+
+        q.sent(TxClass::Telemetry, ms(100));
+        let now = Instant::now();
+        assert!(!q.telemetry_due(now + Duration::from_secs(9)));
+        assert!(q.telemetry_due(now + Duration::from_secs(10)));
+        // a frame waiting is enough
+        q.push(TxClass::Telemetry, vec![0]);
+        assert!(!q.telemetry_due(now + Duration::from_secs(10)));
+
+        // disabled
+        let mut q = TxQueue::new(0, 1, 1);
+        q.sent(TxClass::Telemetry, ms(100));
+        assert!(!q.telemetry_due(Instant::now() + Duration::from_secs(3600)));
+    }
+}
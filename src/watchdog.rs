@@ -0,0 +1,196 @@
+// (C) 2018 David Pello Gonzalez for ASHAB
+//
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+// Linux hardware watchdog (/dev/watchdog) and systemd watchdog (sd_notify)
+// support. Both are optional, the mission pets them after real progress.
+
+extern crate libc;
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+
+// linux/watchdog.h ioctls
+const WDIOC_GETBOOTSTATUS: libc::c_ulong = 0x80045702;
+const WDIOC_SETTIMEOUT: libc::c_ulong = 0xc0045706;
+const WDIOF_CARDRESET: libc::c_int = 0x0020;
+
+// changes on every boot, stored with the counter to count each reset once
+const BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+
+#[allow(dead_code)]
+pub struct Watchdog {
+    pub device: String,
+    pub timeout: u32,
+    pub systemd: bool,
+    pub resets: u32,
+    counter_file: String,
+    dev: Option<File>,
+    notify_socket: Option<String>,
+}
+
+impl Watchdog {
+    pub fn new(dev: &str, t: u32, sd: bool, counter: &str) -> Self {
+        Self {
+            device: String::from(dev),
+            timeout: t,
+            systemd: sd,
+            resets: 0,
+            counter_file: String::from(counter),
+            dev: None,
+            notify_socket: None,
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), io::Error> {
+        // number of watchdog resets until now, and the boot of the last one
+        let (resets, counted_boot) = match File::open(&self.counter_file) {
+            Ok(mut f) => {
+                let mut s = String::new();
+                f.read_to_string(&mut s)?;
+                Self::parse_counter(&s)
+            }
+            Err(_e) => (0, None),
+        };
+        self.resets = resets;
+
+        // hardware watchdog, starts counting as soon as it's opened
+        if !self.device.is_empty() {
+            let f = OpenOptions::new().write(true).open(&self.device)?;
+
+            let mut status: libc::c_int = 0;
+            if unsafe { libc::ioctl(f.as_raw_fd(), WDIOC_GETBOOTSTATUS as _, &mut status) } == 0
+                && status & WDIOF_CARDRESET != 0
+            {
+                // last boot was caused by the watchdog, remember it. The flag
+                // stays set until the next boot, so restarts of the mission
+                // in the same one are not counted again
+                let boot = Self::boot_id();
+                if boot.is_none() || boot != counted_boot {
+                    self.resets += 1;
+                    let mut c = File::create(&self.counter_file)?;
+                    c.write_all(format!("{}\n", self.resets).as_bytes())?;
+                    if let Some(b) = boot {
+                        c.write_all(format!("{}\n", b).as_bytes())?;
+                    }
+                    c.sync_all()?;
+                }
+            }
+
+            if self.timeout > 0 {
+                let mut t: libc::c_int = self.timeout as libc::c_int;
+                if unsafe { libc::ioctl(f.as_raw_fd(), WDIOC_SETTIMEOUT as _, &mut t) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                self.timeout = t as u32;
+            }
+
+            self.dev = Some(f);
+        }
+
+        // systemd watchdog, only if we run as a notify service
+        if self.systemd {
+            self.notify_socket = env::var("NOTIFY_SOCKET").ok();
+        }
+
+        Ok(())
+    }
+
+    // counter file: the number of resets and the boot ID of the last one
+    fn parse_counter(s: &str) -> (u32, Option<String>) {
+        let mut lines = s.lines();
+        let resets = lines.next().and_then(|l| l.trim().parse().ok()).unwrap_or(0);
+        let boot = lines.next().map(|l| l.trim().to_string()).filter(|b| !b.is_empty());
+        (resets, boot)
+    }
+
+    fn boot_id() -> Option<String> {
+        let mut s = String::new();
+        File::open(BOOT_ID).and_then(|mut f| f.read_to_string(&mut s)).ok()?;
+        let id = s.trim();
+        if id.is_empty() {
+            None
+        } else {
+            Some(id.to_string())
+        }
+    }
+
+    // tell systemd we are up and running
+    pub fn ready(&mut self) -> Result<(), io::Error> {
+        let msg = format!("READY=1\nSTATUS=Running, {} watchdog resets", self.resets);
+        self.notify(&msg)
+    }
+
+    // reset the watchdog timers, call only after real progress
+    pub fn pet(&mut self) -> Result<(), io::Error> {
+        if let Some(ref mut f) = self.dev {
+            f.write_all(b"\0")?;
+            f.flush()?;
+        }
+        self.notify("WATCHDOG=1")
+    }
+
+    // clean stop, disarm the hardware watchdog with the magic close
+    pub fn stop(&mut self) -> Result<(), io::Error> {
+        if let Some(mut f) = self.dev.take() {
+            f.write_all(b"V")?;
+            f.flush()?;
+        }
+        self.notify("STOPPING=1")
+    }
+
+    fn notify(&mut self, msg: &str) -> Result<(), io::Error> {
+        let path = match self.notify_socket {
+            Some(ref p) => p.clone(),
+            None => return Ok(()),
+        };
+
+        // abstract namespace sockets are not supported
+        if path.starts_with('@') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "abstract NOTIFY_SOCKET not supported",
+            ));
+        }
+
+        let sock = UnixDatagram::unbound()?;
+        sock.send_to(msg.as_bytes(), &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_with_boot_id() {
+        assert_eq!(
+            Watchdog::parse_counter("3\n5b6c1a2e-0d1f-4c0e-9a55-2f1d3e4c5b6a\n"),
+            (3, Some("5b6c1a2e-0d1f-4c0e-9a55-2f1d3e4c5b6a".to_string()))
+        );
+    }
+
+    #[test]
+    fn counter_without_boot_id() {
+        assert_eq!(Watchdog::parse_counter("7\n"), (7, None));
+        assert_eq!(Watchdog::parse_counter(""), (0, None));
+        assert_eq!(Watchdog::parse_counter("garbage\n"), (0, None));
+    }
+}